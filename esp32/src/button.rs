@@ -0,0 +1,130 @@
+// Physical button for when the web UI can't be reached, by default the BOOT
+// button (GPIO0) on the WiFi dev board. A timer samples the pin and
+// debounces it in software.
+//   short press  toggle led_gpio, if set, and log the bridge's state; wake
+//                the AP instead if ap_idle_min put it to sleep
+//   long press   factory reset: erase the ecco namespace and reboot
+// The reset fires once the button has been held for LONG_PRESS, without
+// waiting for the release, so it is clear when to let go.
+
+use anyhow::Result;
+use esp_idf_hal::gpio::{AnyIOPin, PinDriver, Pull};
+use esp_idf_svc::timer::{EspTaskTimerService, EspTimer};
+use log::*;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::shutdown::{self, Reason};
+use crate::uart::Bridge;
+use crate::{wifi, ws};
+
+const POLL: Duration = Duration::from_millis(10);
+// Samples in a row a new level has to hold before it counts
+const DEBOUNCE_SAMPLES: u8 = 3;
+const LONG_PRESS: Duration = Duration::from_secs(5);
+const LONG_PRESS_POLLS: u32 = (LONG_PRESS.as_millis() / POLL.as_millis()) as u32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Press {
+    Short,
+    Long,
+}
+
+#[derive(Default)]
+struct Debounce {
+    pressed: bool,
+    // Samples that disagreed with `pressed`
+    changing: u8,
+    // Samples since the press was accepted
+    held: u32,
+    long_fired: bool,
+}
+
+impl Debounce {
+    fn sample(&mut self, low: bool) -> Option<Press> {
+        if low != self.pressed {
+            self.changing += 1;
+            if self.changing < DEBOUNCE_SAMPLES {
+                return None;
+            }
+            self.changing = 0;
+            self.pressed = low;
+            if low {
+                self.held = 0;
+                self.long_fired = false;
+                return None;
+            }
+            // Released; a long press has already been handled
+            return (!self.long_fired).then_some(Press::Short);
+        }
+
+        self.changing = 0;
+        if self.pressed && !self.long_fired {
+            self.held += 1;
+            if self.held >= LONG_PRESS_POLLS {
+                self.long_fired = true;
+                return Some(Press::Long);
+            }
+        }
+        None
+    }
+}
+
+// The timer has to be kept alive for the button to keep working
+pub fn start(config: &Config, bridge: Arc<Bridge>) -> Result<Option<EspTimer<'static>>> {
+    let Some(pin) = config.button_gpio else {
+        return Ok(None);
+    };
+
+    // Pin numbers come from NVS, which config checks against the UART pins
+    let mut button = PinDriver::input(unsafe { AnyIOPin::new(pin as i32) })?;
+    button.set_pull(Pull::Up)?;
+    let mut led = match config.led_gpio {
+        Some(pin) => {
+            let mut led = PinDriver::output(unsafe { AnyIOPin::new(pin as i32) })?;
+            led.set_high()?;
+            Some(led)
+        }
+        None => None,
+    };
+
+    let ap_ip = config.ap_ip;
+    let mut debounce = Debounce::default();
+    let timer = EspTaskTimerService::new()?.timer(move || match debounce.sample(button.is_low()) {
+        Some(Press::Short) if wifi::wake_ap() => info!("Button pressed, waking the AP"),
+        Some(Press::Short) => {
+            if let Some(led) = led.as_mut() {
+                if let Err(e) = led.toggle() {
+                    warn!("Toggling the LED failed: {}", e);
+                }
+            }
+            log_state(ap_ip, &bridge);
+        }
+        Some(Press::Long) => factory_reset(),
+        None => {}
+    })?;
+    timer.every(POLL)?;
+
+    info!("Button on GPIO{}: short press for info, hold {:?} to factory reset", pin, LONG_PRESS);
+    Ok(Some(timer))
+}
+
+fn log_state(ap_ip: Ipv4Addr, bridge: &Bridge) {
+    let channel = wifi::ap_channel().map_or("?".into(), |channel| channel.to_string());
+    info!(
+        "WiFi {} (AP {} at {}) on channel {}, {} WebSocket client(s), UART {}",
+        wifi::state().name(),
+        wifi::WIFI_SSID,
+        ap_ip,
+        channel,
+        ws::client_count(),
+        bridge.mode().name()
+    );
+}
+
+fn factory_reset() {
+    warn!("Button held, erasing settings and rebooting");
+    shutdown::request(Reason::FactoryReset);
+}
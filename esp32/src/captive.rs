@@ -0,0 +1,116 @@
+// Captive portal, off unless the captive NVS key is set. A tiny DNS server
+// answers every A query with the AP address, and the OS connectivity probes
+// (registered in http.rs) are redirected to the landing page, so phones and
+// laptops pop up the webapp as soon as they join.
+
+use anyhow::Result;
+use log::*;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::thread;
+
+const DNS_PORT: u16 = 53;
+const DNS_STACK: usize = 4096;
+// Answers are short-lived so clients recover quickly once the portal is off
+const DNS_TTL: u32 = 60;
+const MAX_QUERY: usize = 512;
+
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+// Paths operating systems fetch to detect a captive portal
+pub const PROBE_PATHS: &[&str] = &[
+    // Android, ChromeOS
+    "/generate_204",
+    "/gen_204",
+    // Apple
+    "/hotspot-detect.html",
+    "/library/test/success.html",
+    // Windows
+    "/connecttest.txt",
+    "/ncsi.txt",
+    "/redirect",
+    // Firefox
+    "/canonical.html",
+    "/success.txt",
+];
+
+pub fn start_dns(ip: Ipv4Addr) -> Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DNS_PORT))?;
+
+    thread::Builder::new()
+        .name("captive-dns".into())
+        .stack_size(DNS_STACK)
+        .spawn(move || {
+            let mut buf = [0u8; MAX_QUERY];
+            loop {
+                let (n, peer) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(e) => {
+                        warn!("Captive DNS receive failed: {}", e);
+                        continue;
+                    }
+                };
+                let Some(answer) = answer(&buf[..n], ip) else {
+                    continue;
+                };
+                if let Err(e) = socket.send_to(&answer, peer) {
+                    debug!("Captive DNS reply to {} failed: {}", peer, e);
+                }
+            }
+        })?;
+
+    info!("Captive portal DNS answering with {}", ip);
+    Ok(())
+}
+
+// Reply to a standard query: its first question, answered with `ip` if it
+// asks for an A record and with no records otherwise. None for anything that
+// isn't a well-formed query.
+fn answer(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    let header = query.get(..12)?;
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    // QR set means a response; a non-zero opcode isn't a standard query
+    if flags & 0xf800 != 0 || questions == 0 {
+        return None;
+    }
+
+    // Question: QNAME labels up to the root, then QTYPE and QCLASS
+    let mut end = 12;
+    loop {
+        let len = *query.get(end)? as usize;
+        end += 1;
+        if len == 0 {
+            break;
+        }
+        // Compression pointers can't appear in the first question
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        end += len;
+    }
+    let qtype = u16::from_be_bytes([*query.get(end)?, *query.get(end + 1)?]);
+    let qclass = u16::from_be_bytes([*query.get(end + 2)?, *query.get(end + 3)?]);
+    end += 4;
+    let is_a = qtype == TYPE_A && qclass == CLASS_IN;
+
+    let mut reply = Vec::with_capacity(end + 16);
+    reply.extend_from_slice(&header[..2]);
+    // Response, authoritative, recursion desired copied, recursion available
+    reply.extend_from_slice(&(0x8480 | (flags & 0x0100)).to_be_bytes());
+    reply.extend_from_slice(&1u16.to_be_bytes());
+    reply.extend_from_slice(&(is_a as u16).to_be_bytes());
+    reply.extend_from_slice(&[0, 0, 0, 0]);
+    reply.extend_from_slice(&query[12..end]);
+
+    if is_a {
+        // Name as a pointer to the question at offset 12
+        reply.extend_from_slice(&[0xc0, 0x0c]);
+        reply.extend_from_slice(&TYPE_A.to_be_bytes());
+        reply.extend_from_slice(&CLASS_IN.to_be_bytes());
+        reply.extend_from_slice(&DNS_TTL.to_be_bytes());
+        reply.extend_from_slice(&4u16.to_be_bytes());
+        reply.extend_from_slice(&ip.octets());
+    }
+    Some(reply)
+}
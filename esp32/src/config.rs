@@ -0,0 +1,1308 @@
+// Runtime settings stored in NVS under the "ecco" namespace.
+// Everything has a default, so a blank NVS partition boots the stock AP.
+// Keys are listed in esp32/README.md.
+
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_sys as sys;
+use log::*;
+use std::ffi::CString;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use crate::logger;
+use crate::wifi;
+
+pub const NAMESPACE: &str = "ecco";
+
+// The IDF DHCP server refuses pools larger than this (DHCPS_MAX_LEASE)
+const MAX_DHCP_POOL: u32 = 100;
+// Leases handed out when only ap_ip/ap_mask are set
+const DEFAULT_POOL: u32 = 20;
+// DHCP lease bounds, in minutes. Under a minute the IDF server can't express,
+// and a week is long enough for any stable setup.
+const MIN_DHCP_LEASE_MIN: u16 = 1;
+const MAX_DHCP_LEASE_MIN: u16 = 7 * 24 * 60;
+// ap_idle_min cap, a day
+const MAX_AP_IDLE_MIN: u16 = 24 * 60;
+// Highest 2.4GHz channel allowed outside North America and Japan
+const MAX_CHANNEL: u8 = 13;
+// Shortest cmd_timeout_max we accept, so a typo can't make every call time out
+const MIN_CMD_TIMEOUT: Duration = Duration::from_secs(1);
+// A longer pause would hold the UART long enough to look like a hang.
+// Also the cap on batch_settle_ms.
+pub const MAX_CMD_DELAY: Duration = Duration::from_secs(5);
+// cmd_rx_max bounds. A full response frame has to fit, and the top end
+// already takes over a minute to arrive at 115200 baud.
+const MIN_CMD_RX: usize = crate::protocol::MAX_FRAME;
+const MAX_CMD_RX: usize = 1024 * 1024;
+// rx_idle_ms bounds. Under one FreeRTOS tick (10ms) the reader would spin,
+// and past a second interactive output feels stuck.
+const MIN_RX_IDLE: Duration = Duration::from_millis(10);
+const MAX_RX_IDLE: Duration = Duration::from_secs(1);
+// tx_retries / tx_retry_ms bounds. Retries hold up everything queued behind
+// the failed write, so both stay small.
+const MAX_TX_RETRIES: u8 = 10;
+const MIN_TX_RETRY: Duration = Duration::from_millis(1);
+const MAX_TX_RETRY: Duration = Duration::from_secs(1);
+// tx_gap_us cap. At 10ms a byte even a short command takes seconds to send.
+const MAX_TX_GAP: Duration = Duration::from_millis(10);
+// uart_reinit_max_s cap; past an hour a reboot is the quicker fix
+const MAX_UART_REINIT: u16 = 3600;
+// httpd task stack bounds; below the minimum the handlers overflow
+const MIN_HTTP_STACK: usize = 4096;
+const MAX_HTTP_STACK: usize = 32768;
+// httpd keeps 3 of the LWIP_MAX_SOCKETS (10) for itself
+const MAX_HTTP_SOCKETS: usize = 7;
+const MAX_TOKEN_LEN: usize = 64;
+// ws_max_message bounds. One Ecco frame has to fit, and the buffer for each
+// message is allocated whole, so the top end is what the heap can spare.
+const MIN_WS_MESSAGE: usize = crate::protocol::MAX_FRAME;
+const MAX_WS_MESSAGE: usize = 32768;
+// ws_send_timeout_ms bounds. While a send waits, httpd serves nobody else,
+// so the top end is httpd's own 5s.
+const MIN_WS_SEND_TIMEOUT: Duration = Duration::from_millis(100);
+const MAX_WS_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+// max_body bounds. JSON bodies are held whole while parsed, so the top end is
+// what the heap can spare; a batch of 16 full frames needs about 33KB.
+const MIN_BODY: usize = 1024;
+const MAX_BODY: usize = 65536;
+// Uploads stream to the Flipper, so max_upload and max_bundle only need a floor
+const MIN_UPLOAD: u32 = 1024;
+// gzip_min bounds; below a few hundred bytes the header and bit padding eat
+// the savings
+const MIN_GZIP: usize = 256;
+const MAX_GZIP: usize = 65536;
+// Largest raw_history; it is sent to new ecco-raw clients as one message
+const MAX_RAW_HISTORY: usize = 4096;
+// TLS handshakes run on the httpd task and need more stack than plain HTTP
+const MIN_TLS_STACK: usize = 10240;
+// Socket budget: CONFIG_LWIP_MAX_SOCKETS, of which the main server takes two
+// (listener and control) before any clients. With TLS the plain-HTTP
+// redirect server takes three more and the captive DNS server one.
+const LWIP_SOCKETS: usize = 10;
+const HTTPD_OWN_SOCKETS: usize = 2;
+const REDIRECT_SOCKETS: usize = 3;
+const DNS_SOCKETS: usize = 1;
+const MAX_CAPTIVE_TARGET: usize = 64;
+const MAX_BASE_PATH: usize = 64;
+// mdns_host is one DNS label; RFC 6763 caps instance names at 63 bytes
+const MAX_MDNS_HOST: usize = 32;
+const MAX_MDNS_NAME: usize = 63;
+// mdns_txt as stored, and how many items it may hold
+const MAX_MDNS_TXT_LEN: usize = 255;
+const MAX_MDNS_TXT_ITEMS: usize = 8;
+// Webapp branding. A logo can be an inline data: URI, so it gets the most
+// an NVS string can hold.
+const MAX_BRAND_TITLE: usize = 32;
+const MAX_BRAND_LOGO: usize = 4000;
+
+// on_connect as stored, and how many commands it may hold
+const MAX_ON_CONNECT_LEN: usize = 1024;
+const MAX_ON_CONNECT: usize = 8;
+const PRESENCE_S: std::ops::RangeInclusive<u16> = 1..=300;
+// boot_wait_s cap; a Flipper that takes longer isn't coming
+const MAX_BOOT_WAIT_S: u16 = 600;
+
+// NVS blobs are limited to what fits in a page set; real certs are ~1-2KB
+const MAX_PEM_LEN: usize = 4096;
+// ESP32-S2 GPIOs run 0-46, with no 22-25 and 26-32 wired to flash and
+// PSRAM. 46 is input only.
+const MAX_GPIO: u8 = 46;
+const UNUSABLE_GPIOS: std::ops::RangeInclusive<u8> = 22..=32;
+const INPUT_ONLY_GPIO: u8 = 46;
+// Taken by the Flipper UART, TX then RX
+pub const UART_GPIOS: [u8; 2] = [17, 18];
+// button_gpio value that turns the button off
+const GPIO_NONE: u8 = 0xff;
+// WiFi limits for a station SSID and WPA2 passphrase
+const MAX_SSID_LEN: usize = 32;
+const MAX_PASS_LEN: usize = 64;
+const MAX_STA_RETRIES: u8 = 50;
+// Station retry pauses, seconds: while connecting, and while on the fallback AP
+const STA_RETRY_S: std::ops::RangeInclusive<u16> = 1..=300;
+const STA_FALLBACK_S: std::ops::RangeInclusive<u16> = 30..=3600;
+// Room for 16 comma-separated MACs
+const MAX_BLOCKLIST_LEN: usize = 16 * 18;
+// ws_origins as stored, and how many origins it may hold
+const MAX_WS_ORIGINS_LEN: usize = 512;
+const MAX_WS_ORIGINS: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // AP gateway address and subnet prefix length
+    pub ap_ip: Ipv4Addr,
+    pub ap_prefix: u8,
+    // 1-13, or 0 to pick the least crowded of 1, 6 and 11 at boot
+    pub ap_channel: u8,
+    // AP-only mode: stop the AP once no station has been associated for this
+    // long, until the button wakes it; None keeps it up
+    pub ap_idle: Option<Duration>,
+    // Inclusive DHCP pool handed to AP clients
+    pub dhcp_start: Ipv4Addr,
+    pub dhcp_end: Ipv4Addr,
+    pub dhcp_lease: Duration,
+    // DNS server DHCP advertises, or None for the IDF default
+    pub dhcp_dns: Option<Ipv4Addr>,
+    // Upper bound for a per-call ?timeout_ms on the command API
+    pub cmd_timeout_max: Duration,
+    // Pause after each request before its response is read
+    pub cmd_delay: Duration,
+    // Pause between the steps of a batch or on_connect, after each response
+    pub batch_settle: Duration,
+    // Bytes a command may read while waiting for its response
+    pub cmd_rx_max: usize,
+    // RX quiet time after which the reader passes on what it has
+    pub rx_idle: Duration,
+    // Retries of a UART write that failed transiently, and the pause before each
+    pub tx_retries: u8,
+    pub tx_retry: Duration,
+    // Pause between bytes written to the Flipper, for receivers that drop
+    // bytes sent back to back; zero writes at full speed
+    pub tx_gap: Duration,
+    // Longest backoff between attempts to reinitialize a failed UART driver;
+    // zero leaves it failed
+    pub uart_reinit_max: Duration,
+    // httpd task stack, shared by every HTTP and WebSocket handler
+    pub http_stack: usize,
+    // Concurrent client sockets, WebSockets included
+    pub http_sockets: usize,
+    // Secret /ws clients must pass as ?token=, or None to allow anyone
+    pub ws_token: Option<String>,
+    // Browser origins /ws accepts besides the bridge's own, lowercase and
+    // without a trailing slash; None skips the Origin check
+    pub ws_origins: Option<Vec<String>>,
+    // Largest WebSocket message accepted from a client
+    pub ws_max_message: usize,
+    // Longest httpd may spend sending to one WebSocket client
+    pub ws_send_timeout: Duration,
+    // ecco-raw chunks dropped in a row before a slow client is closed; 0 never
+    pub ws_slow_drops: u8,
+    // Log every WebSocket frame sent; set at runtime through /api/debug/ws-frames
+    pub ws_frame_debug: bool,
+    // Open /ws sessions before new ones are turned away
+    pub ws_max_clients: usize,
+    // Largest JSON request body on /api/*
+    pub max_body: usize,
+    // Largest multipart body on the file upload route
+    pub max_upload: u32,
+    // Largest upload-bundle body, all files together
+    pub max_bundle: u32,
+    // Smallest /api/* response worth gzipping, or 0 for never
+    pub gzip_min: usize,
+    // Stations deauthenticated as soon as they associate
+    pub blocked_macs: Vec<[u8; 6]>,
+    // Set at runtime through /api/debug/loglevel
+    pub log_level: LevelFilter,
+    // PING the Flipper this often while WebSocket clients sit idle
+    pub keepalive: Option<Duration>,
+    // Commands run each time the Flipper starts answering, and how often
+    // the presence watcher PINGs it; unused while on_connect is empty
+    pub on_connect: Vec<(u8, Vec<u8>)>,
+    pub presence: Duration,
+    // How long after boot /api/health/ready waits for the Flipper's first
+    // answer before it checks as usual; None doesn't wait
+    pub boot_wait: Option<Duration>,
+    // Log method, path, status, client and duration of every API request
+    pub http_log: bool,
+    // Log every Ecco frame, decoded; set at runtime through /api/debug/frames
+    pub frame_trace: bool,
+    // Never write to the Flipper, only read; set at runtime through
+    // /api/debug/listen-only
+    pub listen_only: bool,
+    // Keep the newest /api/events entries across a planned restart
+    pub event_persist: bool,
+    // Bytes of recent Flipper output replayed to new ecco-raw clients
+    pub raw_history: usize,
+    // Server certificate and key; when set the API is served over HTTPS
+    pub tls: Option<Tls>,
+    // Answer all DNS with the AP address and send OS portal probes to
+    // captive_target
+    pub captive: bool,
+    // Path on this device the portal lands on
+    pub captive_target: String,
+    // Prefix a reverse proxy serves the bridge under, with the trailing
+    // slash; "/" when clients reach it directly
+    pub base_path: String,
+    // mDNS host name, answered as <mdns_host>.local
+    pub mdns_host: String,
+    // DNS-SD instance name of the _http._tcp service
+    pub mdns_name: String,
+    // TXT items added to the service after the built-in ones
+    pub mdns_txt: Vec<(String, String)>,
+    // Webapp name, shown in the header and the tab title
+    pub brand_title: String,
+    // Webapp accent colour, #rrggbb
+    pub brand_accent: String,
+    // Header logo: an http(s) URL, a path on this device or a data:image/ URI
+    pub brand_logo: Option<String>,
+    // Upstream network to join, or None to only run the AP
+    pub station: Option<Station>,
+    // Station modem sleep, or None for the IDF default (min)
+    pub power_save: Option<PowerSave>,
+    // Active-low button for info (short press) and factory reset (long press)
+    pub button_gpio: Option<u8>,
+    // Active-low LED the button toggles
+    pub led_gpio: Option<u8>,
+    // NVS keys whose stored value is in effect; every other setting is its
+    // default, either because the key is unset or because it was rejected
+    pub from_nvs: Vec<&'static str>,
+}
+
+#[derive(Clone)]
+pub struct Station {
+    pub ssid: String,
+    // Empty for an open network
+    pub password: String,
+    // Failed connects in a row before the fallback AP comes up
+    pub retries: u8,
+    pub retry_interval: Duration,
+    // How often the upstream is tried again while the fallback AP is up
+    pub fallback_retry: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerSave {
+    // Radio always on
+    None,
+    // Wake for every DTIM beacon
+    Min,
+    // Wake every listen interval (3 beacons), lowest power
+    Max,
+}
+
+impl std::str::FromStr for PowerSave {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "none" => Ok(PowerSave::None),
+            "min" => Ok(PowerSave::Min),
+            "max" => Ok(PowerSave::Max),
+            _ => Err(()),
+        }
+    }
+}
+
+// Keeps the passphrase out of any logged config
+impl std::fmt::Debug for Station {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Station")
+            .field("ssid", &self.ssid)
+            .field("retries", &self.retries)
+            .field("retry_interval", &self.retry_interval)
+            .field("fallback_retry", &self.fallback_retry)
+            .finish()
+    }
+}
+
+// PEM, NUL-terminated. Loaded once at boot and kept for the server's lifetime.
+#[derive(Clone, Copy)]
+pub struct Tls {
+    pub cert: &'static [u8],
+    pub key: &'static [u8],
+}
+
+// Keeps the key out of any logged config
+impl std::fmt::Debug for Tls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Tls {{ cert: {} bytes, key: {} bytes }}", self.cert.len(), self.key.len())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let ap_ip = Ipv4Addr::new(192, 168, 4, 1);
+        let ap_prefix = 24;
+        let (dhcp_start, dhcp_end) = default_pool(ap_ip, ap_prefix);
+
+        Self {
+            ap_ip,
+            ap_prefix,
+            ap_channel: 6,
+            ap_idle: None,
+            dhcp_start,
+            dhcp_end,
+            // The IDF DHCP server's own default
+            dhcp_lease: Duration::from_secs(120 * 60),
+            dhcp_dns: None,
+            cmd_timeout_max: Duration::from_secs(60),
+            cmd_delay: Duration::ZERO,
+            batch_settle: Duration::ZERO,
+            cmd_rx_max: 16384,
+            rx_idle: Duration::from_millis(20),
+            tx_retries: 3,
+            tx_retry: Duration::from_millis(20),
+            tx_gap: Duration::ZERO,
+            uart_reinit_max: Duration::from_secs(60),
+            http_stack: 8192,
+            http_sockets: MAX_HTTP_SOCKETS,
+            ws_token: None,
+            ws_origins: None,
+            ws_max_message: 4096,
+            ws_send_timeout: Duration::from_secs(2),
+            ws_slow_drops: 0,
+            ws_frame_debug: false,
+            ws_max_clients: 4,
+            max_body: 4096,
+            max_upload: 1024 * 1024,
+            max_bundle: 8 * 1024 * 1024,
+            gzip_min: 1024,
+            blocked_macs: Vec::new(),
+            log_level: logger::DEFAULT_LEVEL,
+            keepalive: None,
+            on_connect: Vec::new(),
+            presence: Duration::from_secs(5),
+            boot_wait: None,
+            http_log: false,
+            frame_trace: false,
+            listen_only: false,
+            event_persist: false,
+            raw_history: 1024,
+            tls: None,
+            captive: false,
+            captive_target: "/".into(),
+            base_path: "/".into(),
+            mdns_host: "ecco".into(),
+            mdns_name: "Ecco Flipper bridge".into(),
+            mdns_txt: Vec::new(),
+            brand_title: "Ecco".into(),
+            brand_accent: "#ff6b35".into(),
+            brand_logo: None,
+            station: None,
+            power_save: None,
+            // BOOT on the WiFi dev board
+            button_gpio: Some(0),
+            led_gpio: None,
+            from_nvs: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(partition: EspDefaultNvsPartition) -> Result<Self> {
+        // Opened read-write so the namespace gets created on first boot
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+        let mut config = Self::default();
+
+        let ap_ip = get_ip(&nvs, "ap_ip");
+        let ap_prefix = nvs.get_u8("ap_mask").unwrap_or(None);
+        let dhcp_start = get_ip(&nvs, "dhcp_start");
+        let dhcp_end = get_ip(&nvs, "dhcp_end");
+
+        if ap_ip.is_some() || ap_prefix.is_some() || dhcp_start.is_some() || dhcp_end.is_some() {
+            let ip = ap_ip.unwrap_or(config.ap_ip);
+            let prefix = ap_prefix.unwrap_or(config.ap_prefix);
+            let (start, end) = default_pool(ip, prefix);
+            let candidate = Self {
+                ap_ip: ip,
+                ap_prefix: prefix,
+                dhcp_start: dhcp_start.unwrap_or(start),
+                dhcp_end: dhcp_end.unwrap_or(end),
+                ..config.clone()
+            };
+
+            // A bad subnet would leave the board unreachable, so keep the
+            // defaults rather than applying it
+            match candidate.validate_ap() {
+                Ok(()) => {
+                    config = candidate;
+                    let keys = [
+                        ("ap_ip", ap_ip.is_some()),
+                        ("ap_mask", ap_prefix.is_some()),
+                        ("dhcp_start", dhcp_start.is_some()),
+                        ("dhcp_end", dhcp_end.is_some()),
+                    ];
+                    config.from_nvs.extend(keys.iter().filter(|(_, stored)| *stored).map(|(key, _)| *key));
+                }
+                Err(e) => warn!("Ignoring AP network config ({}), using {}/{}", e, config.ap_ip, config.ap_prefix),
+            }
+        }
+
+        if let Some(minutes) = nvs.get_u16("dhcp_lease_min").unwrap_or(None) {
+            if (MIN_DHCP_LEASE_MIN..=MAX_DHCP_LEASE_MIN).contains(&minutes) {
+                config.dhcp_lease = Duration::from_secs(minutes as u64 * 60);
+                config.from_nvs.push("dhcp_lease_min");
+            } else {
+                warn!(
+                    "Ignoring dhcp_lease_min {}, must be {}-{}",
+                    minutes, MIN_DHCP_LEASE_MIN, MAX_DHCP_LEASE_MIN
+                );
+            }
+        }
+
+        if let Some(channel) = nvs.get_u8("ap_channel").unwrap_or(None) {
+            if channel <= MAX_CHANNEL {
+                config.ap_channel = channel;
+                config.from_nvs.push("ap_channel");
+            } else {
+                warn!("Ignoring ap_channel {}, must be 1-{} or 0 for auto", channel, MAX_CHANNEL);
+            }
+        }
+
+        // 0 or unset keeps the AP up
+        if let Some(minutes) = nvs.get_u16("ap_idle_min").unwrap_or(None) {
+            if minutes <= MAX_AP_IDLE_MIN {
+                config.ap_idle = (minutes > 0).then(|| Duration::from_secs(minutes as u64 * 60));
+                config.from_nvs.push("ap_idle_min");
+            } else {
+                warn!("Ignoring ap_idle_min {}, max is {}", minutes, MAX_AP_IDLE_MIN);
+            }
+        }
+
+        if let Some(ms) = nvs.get_u32("cmd_timeout_max").unwrap_or(None) {
+            let max = Duration::from_millis(ms as u64);
+            if max >= MIN_CMD_TIMEOUT {
+                config.cmd_timeout_max = max;
+                config.from_nvs.push("cmd_timeout_max");
+            } else {
+                warn!("Ignoring cmd_timeout_max {}ms, minimum is {:?}", ms, MIN_CMD_TIMEOUT);
+            }
+        }
+
+        if let Some(ms) = nvs.get_u16("cmd_delay_ms").unwrap_or(None) {
+            let delay = Duration::from_millis(ms as u64);
+            if delay <= MAX_CMD_DELAY {
+                config.cmd_delay = delay;
+                config.from_nvs.push("cmd_delay_ms");
+            } else {
+                warn!("Ignoring cmd_delay_ms {}, max is {:?}", ms, MAX_CMD_DELAY);
+            }
+        }
+
+        if let Some(ms) = nvs.get_u16("batch_settle_ms").unwrap_or(None) {
+            let settle = Duration::from_millis(ms as u64);
+            if settle <= MAX_CMD_DELAY {
+                config.batch_settle = settle;
+                config.from_nvs.push("batch_settle_ms");
+            } else {
+                warn!("Ignoring batch_settle_ms {}, max is {:?}", ms, MAX_CMD_DELAY);
+            }
+        }
+
+        if let Some(max) = nvs.get_u32("cmd_rx_max").unwrap_or(None) {
+            let max = max as usize;
+            if (MIN_CMD_RX..=MAX_CMD_RX).contains(&max) {
+                config.cmd_rx_max = max;
+                config.from_nvs.push("cmd_rx_max");
+            } else {
+                warn!("Ignoring cmd_rx_max {}, must be {}-{}", max, MIN_CMD_RX, MAX_CMD_RX);
+            }
+        }
+
+        if let Some(ms) = nvs.get_u16("rx_idle_ms").unwrap_or(None) {
+            let idle = Duration::from_millis(ms as u64);
+            if (MIN_RX_IDLE..=MAX_RX_IDLE).contains(&idle) {
+                config.rx_idle = idle;
+                config.from_nvs.push("rx_idle_ms");
+            } else {
+                warn!("Ignoring rx_idle_ms {}, must be {:?} to {:?}", ms, MIN_RX_IDLE, MAX_RX_IDLE);
+            }
+        }
+
+        if let Some(retries) = nvs.get_u8("tx_retries").unwrap_or(None) {
+            if retries <= MAX_TX_RETRIES {
+                config.tx_retries = retries;
+                config.from_nvs.push("tx_retries");
+            } else {
+                warn!("Ignoring tx_retries {}, max is {}", retries, MAX_TX_RETRIES);
+            }
+        }
+
+        if let Some(ms) = nvs.get_u16("tx_retry_ms").unwrap_or(None) {
+            let pause = Duration::from_millis(ms as u64);
+            if (MIN_TX_RETRY..=MAX_TX_RETRY).contains(&pause) {
+                config.tx_retry = pause;
+                config.from_nvs.push("tx_retry_ms");
+            } else {
+                warn!("Ignoring tx_retry_ms {}, must be {:?} to {:?}", ms, MIN_TX_RETRY, MAX_TX_RETRY);
+            }
+        }
+
+        if let Some(us) = nvs.get_u16("tx_gap_us").unwrap_or(None) {
+            let gap = Duration::from_micros(us as u64);
+            if gap <= MAX_TX_GAP {
+                config.tx_gap = gap;
+                config.from_nvs.push("tx_gap_us");
+            } else {
+                warn!("Ignoring tx_gap_us {}, max is {:?}", us, MAX_TX_GAP);
+            }
+        }
+
+        if let Some(secs) = nvs.get_u16("uart_reinit_max_s").unwrap_or(None) {
+            if secs <= MAX_UART_REINIT {
+                config.uart_reinit_max = Duration::from_secs(secs as u64);
+                config.from_nvs.push("uart_reinit_max_s");
+            } else {
+                warn!("Ignoring uart_reinit_max_s {}, max is {}", secs, MAX_UART_REINIT);
+            }
+        }
+
+        if let Some(stack) = nvs.get_u32("http_stack").unwrap_or(None) {
+            let stack = stack as usize;
+            if (MIN_HTTP_STACK..=MAX_HTTP_STACK).contains(&stack) {
+                config.http_stack = stack;
+                config.from_nvs.push("http_stack");
+            } else {
+                warn!("Ignoring http_stack {}, must be {}-{}", stack, MIN_HTTP_STACK, MAX_HTTP_STACK);
+            }
+        }
+
+        if let Some(sockets) = nvs.get_u8("http_sockets").unwrap_or(None) {
+            let sockets = sockets as usize;
+            if (1..=MAX_HTTP_SOCKETS).contains(&sockets) {
+                config.http_sockets = sockets;
+                config.from_nvs.push("http_sockets");
+            } else {
+                warn!("Ignoring http_sockets {}, must be 1-{}", sockets, MAX_HTTP_SOCKETS);
+            }
+        }
+
+        config.ws_token = get_token(&nvs, "ws_token");
+        if config.ws_token.is_some() {
+            config.from_nvs.push("ws_token");
+        }
+
+        // ws_origins only widens the check, so it means nothing while it's off
+        if nvs.get_u8("ws_origin_check").unwrap_or(None).is_some_and(|on| on != 0) {
+            config.from_nvs.push("ws_origin_check");
+            let origins = get_origins(&nvs, "ws_origins");
+            if !origins.is_empty() {
+                config.from_nvs.push("ws_origins");
+            }
+            config.ws_origins = Some(origins);
+        }
+
+        if let Some(max) = nvs.get_u32("ws_max_message").unwrap_or(None) {
+            let max = max as usize;
+            if (MIN_WS_MESSAGE..=MAX_WS_MESSAGE).contains(&max) {
+                config.ws_max_message = max;
+                config.from_nvs.push("ws_max_message");
+            } else {
+                warn!("Ignoring ws_max_message {}, must be {}-{}", max, MIN_WS_MESSAGE, MAX_WS_MESSAGE);
+            }
+        }
+
+        if let Some(ms) = nvs.get_u16("ws_send_timeout_ms").unwrap_or(None) {
+            let timeout = Duration::from_millis(ms as u64);
+            if (MIN_WS_SEND_TIMEOUT..=MAX_WS_SEND_TIMEOUT).contains(&timeout) {
+                config.ws_send_timeout = timeout;
+                config.from_nvs.push("ws_send_timeout_ms");
+            } else {
+                warn!(
+                    "Ignoring ws_send_timeout_ms {}, must be {:?} to {:?}",
+                    ms, MIN_WS_SEND_TIMEOUT, MAX_WS_SEND_TIMEOUT
+                );
+            }
+        }
+        if let Some(drops) = nvs.get_u8("ws_slow_drops").unwrap_or(None) {
+            config.ws_slow_drops = drops;
+            config.from_nvs.push("ws_slow_drops");
+        }
+        if let Some(max) = nvs.get_u8("ws_max_clients").unwrap_or(None) {
+            let max = max as usize;
+            if (1..=MAX_HTTP_SOCKETS).contains(&max) {
+                config.ws_max_clients = max;
+                config.from_nvs.push("ws_max_clients");
+            } else {
+                warn!("Ignoring ws_max_clients {}, must be 1-{}", max, MAX_HTTP_SOCKETS);
+            }
+        }
+
+        if let Some(max) = nvs.get_u32("max_body").unwrap_or(None) {
+            let max = max as usize;
+            if (MIN_BODY..=MAX_BODY).contains(&max) {
+                config.max_body = max;
+                config.from_nvs.push("max_body");
+            } else {
+                warn!("Ignoring max_body {}, must be {}-{}", max, MIN_BODY, MAX_BODY);
+            }
+        }
+
+        if let Some(max) = nvs.get_u32("max_upload").unwrap_or(None) {
+            if max >= MIN_UPLOAD {
+                config.max_upload = max;
+                config.from_nvs.push("max_upload");
+            } else {
+                warn!("Ignoring max_upload {}, minimum is {}", max, MIN_UPLOAD);
+            }
+        }
+        if let Some(max) = nvs.get_u32("max_bundle").unwrap_or(None) {
+            if max >= MIN_UPLOAD {
+                config.max_bundle = max;
+                config.from_nvs.push("max_bundle");
+            } else {
+                warn!("Ignoring max_bundle {}, minimum is {}", max, MIN_UPLOAD);
+            }
+        }
+
+        if let Some(min) = nvs.get_u32("gzip_min").unwrap_or(None) {
+            let min = min as usize;
+            if min == 0 || (MIN_GZIP..=MAX_GZIP).contains(&min) {
+                config.gzip_min = min;
+                config.from_nvs.push("gzip_min");
+            } else {
+                warn!("Ignoring gzip_min {}, must be 0 or {}-{}", min, MIN_GZIP, MAX_GZIP);
+            }
+        }
+        config.blocked_macs = get_macs(&nvs, "mac_block");
+        if !config.blocked_macs.is_empty() {
+            config.from_nvs.push("mac_block");
+        }
+
+        if let Some(len) = nvs.get_u16("raw_history").unwrap_or(None) {
+            let len = len as usize;
+            if len <= MAX_RAW_HISTORY {
+                config.raw_history = len;
+                config.from_nvs.push("raw_history");
+            } else {
+                warn!("Ignoring raw_history {}, max is {}", len, MAX_RAW_HISTORY);
+            }
+        }
+
+        if let Some(on) = nvs.get_u8("http_log").unwrap_or(None) {
+            config.http_log = on != 0;
+            config.from_nvs.push("http_log");
+        }
+        if let Some(on) = nvs.get_u8("frame_trace").unwrap_or(None) {
+            config.frame_trace = on != 0;
+            config.from_nvs.push("frame_trace");
+        }
+        if let Some(on) = nvs.get_u8("ws_frame_debug").unwrap_or(None) {
+            config.ws_frame_debug = on != 0;
+            config.from_nvs.push("ws_frame_debug");
+        }
+        if let Some(on) = nvs.get_u8("listen_only").unwrap_or(None) {
+            config.listen_only = on != 0;
+            config.from_nvs.push("listen_only");
+        }
+        if let Some(on) = nvs.get_u8("event_persist").unwrap_or(None) {
+            config.event_persist = on != 0;
+            config.from_nvs.push("event_persist");
+        }
+
+        // 0 or unset leaves it off
+        if let Some(secs) = nvs.get_u16("keepalive_s").unwrap_or(None) {
+            config.keepalive = (secs > 0).then(|| Duration::from_secs(secs as u64));
+            config.from_nvs.push("keepalive_s");
+        }
+
+        config.on_connect = get_commands(&nvs, "on_connect");
+        if !config.on_connect.is_empty() {
+            config.from_nvs.push("on_connect");
+        }
+        if let Some(secs) = nvs.get_u16("presence_s").unwrap_or(None) {
+            if PRESENCE_S.contains(&secs) {
+                config.presence = Duration::from_secs(secs as u64);
+                config.from_nvs.push("presence_s");
+            } else {
+                warn!("Ignoring presence_s {}, must be {}-{}", secs, PRESENCE_S.start(), PRESENCE_S.end());
+            }
+        }
+
+        // 0 or unset doesn't wait
+        if let Some(secs) = nvs.get_u16("boot_wait_s").unwrap_or(None) {
+            if secs <= MAX_BOOT_WAIT_S {
+                config.boot_wait = (secs > 0).then(|| Duration::from_secs(secs as u64));
+                config.from_nvs.push("boot_wait_s");
+            } else {
+                warn!("Ignoring boot_wait_s {}, max is {}", secs, MAX_BOOT_WAIT_S);
+            }
+        }
+
+        config.tls = match (get_pem(&nvs, "tls_cert"), get_pem(&nvs, "tls_key")) {
+            (Some(cert), Some(key)) => {
+                config.from_nvs.extend(["tls_cert", "tls_key"]);
+                Some(Tls { cert, key })
+            }
+            (None, None) => None,
+            _ => {
+                warn!("Only one of tls_cert and tls_key is set, serving plain HTTP");
+                None
+            }
+        };
+        if config.tls.is_some() && config.http_stack < MIN_TLS_STACK {
+            info!("Raising http_stack to {} for TLS", MIN_TLS_STACK);
+            config.http_stack = MIN_TLS_STACK;
+        }
+
+        if let Some(on) = nvs.get_u8("captive").unwrap_or(None) {
+            config.captive = on != 0;
+            config.from_nvs.push("captive");
+        }
+        if let Some(target) = get_target(&nvs, "captive_target") {
+            config.captive_target = target;
+            config.from_nvs.push("captive_target");
+        }
+
+        // The captive portal only works if clients ask its DNS server
+        config.dhcp_dns = match get_ip(&nvs, "dns") {
+            Some(dns) if dns.is_unspecified() || dns.is_broadcast() || dns.is_multicast() => {
+                warn!("Ignoring dns {}, not a unicast address", dns);
+                config.captive.then_some(config.ap_ip)
+            }
+            Some(dns) => {
+                if config.captive && dns != config.ap_ip {
+                    warn!("dns {} is not the AP, so the captive portal won't see lookups", dns);
+                }
+                config.from_nvs.push("dns");
+                Some(dns)
+            }
+            None => config.captive.then_some(config.ap_ip),
+        };
+
+        if let Some(path) = get_base_path(&nvs, "base_path") {
+            config.base_path = path;
+            config.from_nvs.push("base_path");
+        }
+
+        if let Some(host) = get_mdns_host(&nvs, "mdns_host") {
+            config.mdns_host = host;
+            config.from_nvs.push("mdns_host");
+        }
+        let mut buf = [0u8; MAX_MDNS_NAME + 1];
+        match nvs.get_str("mdns_name", &mut buf) {
+            Ok(Some(name)) if !name.trim().is_empty() => {
+                config.mdns_name = name.trim().to_string();
+                config.from_nvs.push("mdns_name");
+            }
+            Ok(_) => {}
+            Err(e) => warn!("NVS mdns_name unreadable ({}), ignoring", e),
+        }
+        config.mdns_txt = get_txt(&nvs, "mdns_txt");
+        if !config.mdns_txt.is_empty() {
+            config.from_nvs.push("mdns_txt");
+        }
+
+        let mut buf = [0u8; MAX_BRAND_TITLE + 1];
+        match nvs.get_str("brand_title", &mut buf) {
+            Ok(Some(title)) if !title.trim().is_empty() => {
+                config.brand_title = title.trim().to_string();
+                config.from_nvs.push("brand_title");
+            }
+            Ok(_) => {}
+            Err(e) => warn!("NVS brand_title unreadable ({}), ignoring", e),
+        }
+        let mut buf = [0u8; 8];
+        match nvs.get_str("brand_accent", &mut buf) {
+            Ok(Some(color)) if is_color(color) => {
+                config.brand_accent = color.to_ascii_lowercase();
+                config.from_nvs.push("brand_accent");
+            }
+            Ok(Some(color)) => warn!("Ignoring NVS brand_accent {:?}, expected #rrggbb", color),
+            Ok(None) => {}
+            Err(e) => warn!("NVS brand_accent unreadable ({}), ignoring", e),
+        }
+        config.brand_logo = get_logo(&nvs, "brand_logo");
+        if config.brand_logo.is_some() {
+            config.from_nvs.push("brand_logo");
+        }
+
+        config.station = get_station(&nvs, &mut config.from_nvs);
+
+        let mut buf = [0u8; 8];
+        match nvs.get_str("wifi_ps", &mut buf).unwrap_or(None).map(str::parse) {
+            Some(Ok(mode)) => {
+                config.power_save = Some(mode);
+                config.from_nvs.push("wifi_ps");
+            }
+            Some(Err(_)) => warn!("Ignoring NVS wifi_ps, expected none/min/max"),
+            None => {}
+        }
+
+        match nvs.get_u8("button_gpio").unwrap_or(None) {
+            Some(GPIO_NONE) => {
+                config.button_gpio = None;
+                config.from_nvs.push("button_gpio");
+            }
+            Some(pin) if usable_gpio(pin) => {
+                config.button_gpio = Some(pin);
+                config.from_nvs.push("button_gpio");
+            }
+            Some(pin) => warn!("Ignoring button_gpio {}, must be 0-21 or 33-{} and not a UART pin", pin, MAX_GPIO),
+            None => {}
+        }
+        match nvs.get_u8("led_gpio").unwrap_or(None) {
+            Some(pin) if usable_gpio(pin) && pin != INPUT_ONLY_GPIO && Some(pin) != config.button_gpio => {
+                config.led_gpio = Some(pin);
+                config.from_nvs.push("led_gpio");
+            }
+            Some(pin) => warn!("Ignoring led_gpio {}, must be an output pin other than the button and UART", pin),
+            None => {}
+        }
+
+        let mut free = LWIP_SOCKETS - HTTPD_OWN_SOCKETS;
+        if config.tls.is_some() {
+            free -= REDIRECT_SOCKETS;
+        }
+        if config.captive {
+            free -= DNS_SOCKETS;
+        }
+        let max_sockets = free.min(MAX_HTTP_SOCKETS);
+        if config.http_sockets > max_sockets {
+            info!("Lowering http_sockets to {} to leave room for the redirect and DNS servers", max_sockets);
+            config.http_sockets = max_sockets;
+        }
+
+        let mut buf = [0u8; 8];
+        match nvs.get_str("log_level", &mut buf).unwrap_or(None).map(str::parse) {
+            Some(Ok(level)) => {
+                config.log_level = level;
+                config.from_nvs.push("log_level");
+            }
+            Some(Err(_)) => warn!("Ignoring NVS log_level, expected off/error/warn/info/debug/trace"),
+            None => {}
+        }
+
+        Ok(config)
+    }
+
+    // Persist a setting for the next boot; applying it now is up to the caller
+    pub fn store_str(partition: &EspDefaultNvsPartition, key: &str, value: &str) -> Result<()> {
+        let mut nvs = EspNvs::new(partition.clone(), NAMESPACE, true)?;
+        nvs.set_str(key, value)?;
+        Ok(())
+    }
+
+    pub fn store_u8(partition: &EspDefaultNvsPartition, key: &str, value: u8) -> Result<()> {
+        let nvs = EspNvs::new(partition.clone(), NAMESPACE, true)?;
+        nvs.set_u8(key, value)?;
+        Ok(())
+    }
+
+    // Factory reset: every key in the namespace, stored settings and recent
+    // commands alike. Takes effect on the next boot.
+    // EspNvs doesn't expose its handle, so this opens its own
+    pub fn erase_all() -> Result<()> {
+        let namespace = CString::new(NAMESPACE)?;
+        let mut handle: sys::nvs_handle_t = 0;
+        sys::esp!(unsafe { sys::nvs_open(namespace.as_ptr(), sys::nvs_open_mode_t_NVS_READWRITE, &mut handle) })?;
+
+        let result = sys::esp!(unsafe { sys::nvs_erase_all(handle) }).and_then(|()| sys::esp!(unsafe { sys::nvs_commit(handle) }));
+        unsafe { sys::nvs_close(handle) };
+        Ok(result?)
+    }
+
+    pub fn validate_ap(&self) -> Result<(), String> {
+        if !(8..=30).contains(&self.ap_prefix) {
+            return Err(format!("ap_mask /{} must be between /8 and /30", self.ap_prefix));
+        }
+        if !self.ap_ip.is_private() {
+            return Err(format!("ap_ip {} is not a private address", self.ap_ip));
+        }
+
+        let mask = prefix_mask(self.ap_prefix);
+        let network = u32::from(self.ap_ip) & mask;
+        let broadcast = network | !mask;
+        let host = |ip: Ipv4Addr| {
+            let ip = u32::from(ip);
+            ip & mask == network && ip != network && ip != broadcast
+        };
+
+        if !host(self.ap_ip) {
+            return Err(format!("ap_ip {} is not a host address in /{}", self.ap_ip, self.ap_prefix));
+        }
+        if !host(self.dhcp_start) || !host(self.dhcp_end) {
+            return Err(format!(
+                "DHCP range {}-{} is outside {}/{}",
+                self.dhcp_start, self.dhcp_end, self.ap_ip, self.ap_prefix
+            ));
+        }
+
+        let start = u32::from(self.dhcp_start);
+        let end = u32::from(self.dhcp_end);
+        if start > end {
+            return Err(format!("dhcp_start {} is after dhcp_end {}", self.dhcp_start, self.dhcp_end));
+        }
+        if end - start >= MAX_DHCP_POOL {
+            return Err(format!("DHCP range holds more than {} addresses", MAX_DHCP_POOL));
+        }
+        if (start..=end).contains(&u32::from(self.ap_ip)) {
+            return Err(format!("DHCP range includes ap_ip {}", self.ap_ip));
+        }
+
+        Ok(())
+    }
+}
+
+fn usable_gpio(pin: u8) -> bool {
+    pin <= MAX_GPIO && !UNUSABLE_GPIOS.contains(&pin) && !UART_GPIOS.contains(&pin)
+}
+
+// Addresses are stored as dotted-quad strings so they can be set with
+// nvs_partition_gen or any NVS editor
+fn get_ip(nvs: &EspNvs<NvsDefault>, key: &str) -> Option<Ipv4Addr> {
+    let mut buf = [0u8; 16];
+    let value = match nvs.get_str(key, &mut buf) {
+        Ok(value) => value,
+        Err(e) => {
+            warn!("NVS {} unreadable ({}), ignoring", key, e);
+            None
+        }
+    };
+
+    match value.map(str::parse) {
+        Some(Ok(ip)) => Some(ip),
+        Some(Err(_)) => {
+            warn!("NVS {} is not an IPv4 address, ignoring", key);
+            None
+        }
+        None => None,
+    }
+}
+
+// Compared against the raw query string, so only URL-safe characters are
+// allowed; anything else would never match and lock every client out
+fn get_token(nvs: &EspNvs<NvsDefault>, key: &str) -> Option<String> {
+    let mut buf = [0u8; MAX_TOKEN_LEN + 1];
+    let token = match nvs.get_str(key, &mut buf) {
+        Ok(token) => token?,
+        Err(e) => {
+            warn!("NVS {} unreadable ({}), WebSocket auth disabled", key, e);
+            return None;
+        }
+    };
+
+    let url_safe = |c: char| c.is_ascii_alphanumeric() || "-._~".contains(c);
+    if token.is_empty() {
+        None
+    } else if token.chars().all(url_safe) {
+        Some(token.to_string())
+    } else {
+        warn!("NVS {} has characters outside A-Z a-z 0-9 - . _ ~, WebSocket auth disabled", key);
+        None
+    }
+}
+
+// Comma-separated list; entries that don't parse are skipped with a warning
+fn get_macs(nvs: &EspNvs<NvsDefault>, key: &str) -> Vec<[u8; 6]> {
+    let mut buf = [0u8; MAX_BLOCKLIST_LEN + 1];
+    let list = match nvs.get_str(key, &mut buf) {
+        Ok(Some(list)) => list,
+        Ok(None) => return Vec::new(),
+        Err(e) => {
+            warn!("NVS {} unreadable ({}), ignoring", key, e);
+            return Vec::new();
+        }
+    };
+
+    list.split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .filter_map(|entry| {
+            let mac = wifi::parse_mac(entry);
+            if mac.is_none() {
+                warn!("NVS {} entry {:?} is not a MAC address, ignoring", key, entry);
+            }
+            mac
+        })
+        .collect()
+}
+
+// Comma-separated http(s) origins, as browsers send them in Origin
+fn get_origins(nvs: &EspNvs<NvsDefault>, key: &str) -> Vec<String> {
+    let mut buf = [0u8; MAX_WS_ORIGINS_LEN + 1];
+    let list = match nvs.get_str(key, &mut buf) {
+        Ok(Some(list)) => list,
+        Ok(None) => return Vec::new(),
+        Err(e) => {
+            warn!("NVS {} unreadable ({}), ignoring", key, e);
+            return Vec::new();
+        }
+    };
+
+    let origins: Vec<String> = list
+        .split(',')
+        .map(|entry| entry.trim().trim_end_matches('/').to_ascii_lowercase())
+        .filter(|entry| !entry.is_empty())
+        .filter(|entry| {
+            let valid = entry.starts_with("http://") || entry.starts_with("https://");
+            if !valid {
+                warn!("NVS {} entry {:?} is not an http(s) origin, ignoring", key, entry);
+            }
+            valid
+        })
+        .collect();
+    if origins.len() > MAX_WS_ORIGINS {
+        warn!("NVS {} holds {} origins, only the first {} are used", key, origins.len(), MAX_WS_ORIGINS);
+    }
+    origins.into_iter().take(MAX_WS_ORIGINS).collect()
+}
+
+// sta_ssid turns station mode on; the other sta_* keys tune it. The keys
+// that were applied are added to `from_nvs`.
+fn get_station(nvs: &EspNvs<NvsDefault>, from_nvs: &mut Vec<&'static str>) -> Option<Station> {
+    let mut buf = [0u8; MAX_PASS_LEN + 1];
+    let ssid = match nvs.get_str("sta_ssid", &mut buf) {
+        Ok(Some(ssid)) if !ssid.is_empty() && ssid.len() <= MAX_SSID_LEN => ssid.to_string(),
+        Ok(Some(ssid)) => {
+            warn!("Ignoring sta_ssid {:?}, must be 1-{} bytes", ssid, MAX_SSID_LEN);
+            return None;
+        }
+        Ok(None) => return None,
+        Err(e) => {
+            warn!("NVS sta_ssid unreadable ({}), station mode off", e);
+            return None;
+        }
+    };
+
+    let password = match nvs.get_str("sta_pass", &mut buf) {
+        Ok(password) => password.unwrap_or("").to_string(),
+        Err(e) => {
+            warn!("NVS sta_pass unreadable ({}), station mode off", e);
+            return None;
+        }
+    };
+    if !password.is_empty() && password.len() < 8 {
+        warn!("Ignoring sta_pass, a WPA2 passphrase is 8-{} characters; station mode off", MAX_PASS_LEN);
+        return None;
+    }
+
+    from_nvs.push("sta_ssid");
+    if !password.is_empty() {
+        from_nvs.push("sta_pass");
+    }
+    let mut station = Station {
+        ssid,
+        password,
+        retries: 5,
+        retry_interval: Duration::from_secs(5),
+        fallback_retry: Duration::from_secs(300),
+    };
+
+    if let Some(retries) = nvs.get_u8("sta_retries").unwrap_or(None) {
+        if (1..=MAX_STA_RETRIES).contains(&retries) {
+            station.retries = retries;
+            from_nvs.push("sta_retries");
+        } else {
+            warn!("Ignoring sta_retries {}, must be 1-{}", retries, MAX_STA_RETRIES);
+        }
+    }
+    if let Some(secs) = nvs.get_u16("sta_retry_s").unwrap_or(None) {
+        if STA_RETRY_S.contains(&secs) {
+            station.retry_interval = Duration::from_secs(secs as u64);
+            from_nvs.push("sta_retry_s");
+        } else {
+            warn!("Ignoring sta_retry_s {}, must be {}-{}", secs, STA_RETRY_S.start(), STA_RETRY_S.end());
+        }
+    }
+    if let Some(secs) = nvs.get_u16("sta_fallback_s").unwrap_or(None) {
+        if STA_FALLBACK_S.contains(&secs) {
+            station.fallback_retry = Duration::from_secs(secs as u64);
+            from_nvs.push("sta_fallback_s");
+        } else {
+            warn!("Ignoring sta_fallback_s {}, must be {}-{}", secs, STA_FALLBACK_S.start(), STA_FALLBACK_S.end());
+        }
+    }
+
+    Some(station)
+}
+
+// A path on this device, e.g. /setup
+fn get_target(nvs: &EspNvs<NvsDefault>, key: &str) -> Option<String> {
+    let mut buf = [0u8; MAX_CAPTIVE_TARGET + 1];
+    match nvs.get_str(key, &mut buf) {
+        Ok(Some(path)) if path.starts_with('/') && !path.contains(char::is_whitespace) => Some(path.to_string()),
+        Ok(Some(path)) => {
+            warn!("NVS {} {:?} is not a path starting with /, ignoring", key, path);
+            None
+        }
+        Ok(None) => None,
+        Err(e) => {
+            warn!("NVS {} unreadable ({}), ignoring", key, e);
+            None
+        }
+    }
+}
+
+// A URL path such as /ecco or /lab/ecco/, stored with one trailing slash.
+// It ends up in an HTML attribute, so only URL-safe characters are taken.
+fn get_base_path(nvs: &EspNvs<NvsDefault>, key: &str) -> Option<String> {
+    let mut buf = [0u8; MAX_BASE_PATH + 1];
+    let path = match nvs.get_str(key, &mut buf) {
+        Ok(path) => path?.trim(),
+        Err(e) => {
+            warn!("NVS {} unreadable ({}), ignoring", key, e);
+            return None;
+        }
+    };
+
+    let url_safe = |c: char| c.is_ascii_alphanumeric() || "-._~/".contains(c);
+    if path.starts_with('/') && !path.contains("//") && path.chars().all(url_safe) {
+        Some(format!("{}/", path.trim_end_matches('/')))
+    } else {
+        warn!("Ignoring NVS {} {:?}, must be a path of A-Z a-z 0-9 - . _ ~ starting with /", key, path);
+        None
+    }
+}
+
+// One DNS label: lowercase letters, digits and inner hyphens
+fn get_mdns_host(nvs: &EspNvs<NvsDefault>, key: &str) -> Option<String> {
+    let mut buf = [0u8; MAX_MDNS_HOST + 1];
+    let host = match nvs.get_str(key, &mut buf) {
+        Ok(host) => host?,
+        Err(e) => {
+            warn!("NVS {} unreadable ({}), ignoring", key, e);
+            return None;
+        }
+    };
+
+    let label = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-';
+    if !host.is_empty() && host.chars().all(label) && !host.starts_with('-') && !host.ends_with('-') {
+        Some(host.to_string())
+    } else {
+        warn!("Ignoring NVS {} {:?}, must be a-z 0-9 and inner hyphens", key, host);
+        None
+    }
+}
+
+// Comma-separated key=value items, e.g. role=lab,site=bench. Items without
+// a key are skipped with a warning.
+fn get_txt(nvs: &EspNvs<NvsDefault>, key: &str) -> Vec<(String, String)> {
+    let mut buf = [0u8; MAX_MDNS_TXT_LEN + 1];
+    let list = match nvs.get_str(key, &mut buf) {
+        Ok(Some(list)) => list,
+        Ok(None) => return Vec::new(),
+        Err(e) => {
+            warn!("NVS {} unreadable ({}), ignoring", key, e);
+            return Vec::new();
+        }
+    };
+
+    let items: Vec<(String, String)> = list
+        .split(',')
+        .filter(|item| !item.trim().is_empty())
+        .filter_map(|item| {
+            let (name, value) = item.split_once('=').unwrap_or((item, ""));
+            let name = name.trim();
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_graphic()) {
+                warn!("NVS {} item {:?} has no usable key, ignoring", key, item);
+                return None;
+            }
+            Some((name.to_string(), value.trim().to_string()))
+        })
+        .collect();
+
+    if items.len() > MAX_MDNS_TXT_ITEMS {
+        warn!("NVS {} has {} items, only the first {} are used", key, items.len(), MAX_MDNS_TXT_ITEMS);
+    }
+    items.into_iter().take(MAX_MDNS_TXT_ITEMS).collect()
+}
+
+// Semicolon-separated CMD[:PAYLOAD] items, CMD a spec name or hex ID and
+// PAYLOAD hex, e.g. DEVICE_INFO;0x03:0000ff. A bad item drops the whole
+// list, since later commands may depend on earlier ones.
+fn get_commands(nvs: &EspNvs<NvsDefault>, key: &str) -> Vec<(u8, Vec<u8>)> {
+    let mut buf = vec![0u8; MAX_ON_CONNECT_LEN + 1];
+    let list = match nvs.get_str(key, &mut buf) {
+        Ok(Some(list)) => list,
+        Ok(None) => return Vec::new(),
+        Err(e) => {
+            warn!("NVS {} unreadable ({}), ignoring", key, e);
+            return Vec::new();
+        }
+    };
+
+    let parse = |item: &str| -> Option<(u8, Vec<u8>)> {
+        let (name, payload) = item.split_once(':').unwrap_or((item, ""));
+        let name = name.trim();
+        let command = match name.strip_prefix("0x").or_else(|| name.strip_prefix("0X")) {
+            Some(hex) if (1..=2).contains(&hex.len()) => {
+                hex.bytes().try_fold(0u8, |command, b| Some(command << 4 | crate::protocol::hex_digit(b)?))?
+            }
+            Some(_) => return None,
+            None => crate::protocol::cmd_by_name(name)?,
+        };
+        let payload = crate::protocol::from_hex(payload.trim())?;
+        if payload.len() > crate::protocol::MAX_PAYLOAD {
+            return None;
+        }
+        Some((command, payload))
+    };
+
+    let mut commands = Vec::new();
+    for item in list.split(';').filter(|item| !item.trim().is_empty()) {
+        match parse(item) {
+            Some(command) => commands.push(command),
+            None => {
+                warn!("NVS {} item {:?} is not CMD[:hex payload], ignoring {}", key, item.trim(), key);
+                return Vec::new();
+            }
+        }
+    }
+    if commands.len() > MAX_ON_CONNECT {
+        warn!("NVS {} has {} commands, max {}, ignoring it", key, commands.len(), MAX_ON_CONNECT);
+        return Vec::new();
+    }
+    commands
+}
+
+fn is_color(s: &str) -> bool {
+    s.len() == 7 && s.starts_with('#') && s[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Goes into an <img src>, so only schemes that can't run script are taken
+fn get_logo(nvs: &EspNvs<NvsDefault>, key: &str) -> Option<String> {
+    let mut buf = vec![0u8; MAX_BRAND_LOGO + 1];
+    let logo = match nvs.get_str(key, &mut buf) {
+        Ok(logo) => logo?.trim(),
+        Err(e) => {
+            warn!("NVS {} unreadable ({}), ignoring", key, e);
+            return None;
+        }
+    };
+
+    let allowed = ["https://", "http://", "data:image/"];
+    if allowed.iter().any(|prefix| logo.starts_with(prefix)) || (logo.starts_with('/') && !logo.starts_with("//")) {
+        Some(logo.to_string())
+    } else {
+        warn!("Ignoring NVS {}, must be an http(s) URL, a /path or a data:image/ URI", key);
+        None
+    }
+}
+
+// PEM blob, NUL-terminated for mbedTLS and leaked so the server can keep
+// pointing at it
+fn get_pem(nvs: &EspNvs<NvsDefault>, key: &str) -> Option<&'static [u8]> {
+    let mut buf = match nvs.blob_len(key) {
+        Ok(Some(len)) if len <= MAX_PEM_LEN => vec![0u8; len],
+        Ok(Some(len)) => {
+            warn!("NVS {} is {} bytes, max {}, ignoring", key, len, MAX_PEM_LEN);
+            return None;
+        }
+        Ok(None) => return None,
+        Err(e) => {
+            warn!("NVS {} unreadable ({}), ignoring", key, e);
+            return None;
+        }
+    };
+
+    let mut pem = match nvs.get_blob(key, &mut buf) {
+        Ok(Some(pem)) => pem.to_vec(),
+        _ => return None,
+    };
+    if !pem.starts_with(b"-----BEGIN ") {
+        warn!("NVS {} is not PEM, ignoring", key);
+        return None;
+    }
+    if pem.last() != Some(&0) {
+        pem.push(0);
+    }
+    Some(Box::leak(pem.into_boxed_slice()))
+}
+
+// Callers keep prefix within 8..=30
+fn prefix_mask(prefix: u8) -> u32 {
+    u32::MAX << (32 - prefix as u32)
+}
+
+// The hosts right after the gateway, or from the bottom of the subnet if the
+// gateway is the last host
+fn default_pool(ip: Ipv4Addr, prefix: u8) -> (Ipv4Addr, Ipv4Addr) {
+    let mask = prefix_mask(prefix.clamp(8, 30));
+    let network = u32::from(ip) & mask;
+    let last_host = (network | !mask) - 1;
+    let start = if u32::from(ip) < last_host { u32::from(ip) + 1 } else { network + 1 };
+    let end = (start + DEFAULT_POOL - 1).min(last_host);
+
+    (Ipv4Addr::from(start), Ipv4Addr::from(end))
+}
@@ -0,0 +1,156 @@
+// What /api/config/effective reports: every setting as the firmware is
+// using it right now, with where the value came from. Unlike
+// /api/debug/nvs, values are after the boot checks, so a rejected key
+// shows up here as its default. Secrets are never shown.
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::config::{Config, PowerSave, UART_GPIOS};
+use crate::http::level_name;
+use crate::logger;
+use crate::nvs_debug;
+use crate::protocol;
+use crate::uart::{Bridge, UART_BAUD};
+use crate::wifi;
+use crate::ws;
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Default,
+    Nvs,
+    // Changed through the API since boot
+    Runtime,
+}
+
+#[derive(Serialize)]
+pub struct Setting {
+    pub key: &'static str,
+    // In the units of the NVS key; null for secrets and unset options
+    pub value: Value,
+    pub source: Source,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub redacted: bool,
+}
+
+// Settings in the order of the Configuration table in esp32/README.md
+pub fn settings(config: &Config, bridge: &Bridge) -> Vec<Setting> {
+    let station = config.station.as_ref();
+    let ms = |d: std::time::Duration| d.as_millis() as u64;
+    let secs = |d: std::time::Duration| d.as_secs();
+
+    let settings: Vec<(&'static str, Value)> = vec![
+        ("ap_ip", json!(config.ap_ip.to_string())),
+        ("ap_mask", json!(config.ap_prefix)),
+        ("ap_channel", json!(config.ap_channel)),
+        ("ap_idle_min", json!(config.ap_idle.map_or(0, secs) / 60)),
+        ("dhcp_start", json!(config.dhcp_start.to_string())),
+        ("dhcp_end", json!(config.dhcp_end.to_string())),
+        ("dhcp_lease_min", json!(secs(config.dhcp_lease) / 60)),
+        ("dns", json!(config.dhcp_dns.map(|dns| dns.to_string()))),
+        ("base_path", json!(config.base_path)),
+        ("batch_settle_ms", json!(ms(config.batch_settle))),
+        ("boot_wait_s", json!(config.boot_wait.map_or(0, secs))),
+        ("brand_title", json!(config.brand_title)),
+        ("brand_accent", json!(config.brand_accent)),
+        ("brand_logo", json!(config.brand_logo)),
+        ("button_gpio", json!(config.button_gpio)),
+        ("captive", json!(config.captive)),
+        ("captive_target", json!(config.captive_target)),
+        ("cmd_delay_ms", json!(ms(config.cmd_delay))),
+        ("cmd_rx_max", json!(config.cmd_rx_max)),
+        ("rx_idle_ms", json!(ms(config.rx_idle))),
+        ("tx_retries", json!(config.tx_retries)),
+        ("tx_retry_ms", json!(ms(config.tx_retry))),
+        ("tx_gap_us", json!(config.tx_gap.as_micros() as u64)),
+        ("uart_reinit_max_s", json!(secs(config.uart_reinit_max))),
+        ("cmd_timeout_max", json!(ms(config.cmd_timeout_max))),
+        ("http_stack", json!(config.http_stack)),
+        ("http_sockets", json!(config.http_sockets)),
+        ("event_persist", json!(config.event_persist)),
+        ("frame_trace", json!(bridge.tracing())),
+        ("gzip_min", json!(config.gzip_min)),
+        ("http_log", json!(config.http_log)),
+        ("keepalive_s", json!(config.keepalive.map_or(0, secs))),
+        ("listen_only", json!(bridge.listen_only())),
+        ("led_gpio", json!(config.led_gpio)),
+        ("log_level", json!(level_name(logger::level()))),
+        ("mac_block", json!(config.blocked_macs.iter().map(wifi::format_mac).collect::<Vec<_>>())),
+        ("max_body", json!(config.max_body)),
+        ("max_bundle", json!(config.max_bundle)),
+        ("max_upload", json!(config.max_upload)),
+        ("mdns_host", json!(config.mdns_host)),
+        ("mdns_name", json!(config.mdns_name)),
+        ("mdns_txt", json!(config.mdns_txt.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>())),
+        ("on_connect", json!(config.on_connect.iter().map(command).collect::<Vec<_>>())),
+        ("presence_s", json!(secs(config.presence))),
+        ("raw_history", json!(config.raw_history)),
+        ("sta_ssid", json!(station.map(|s| &s.ssid))),
+        ("sta_pass", Value::Null),
+        ("sta_retries", json!(station.map(|s| s.retries))),
+        ("sta_retry_s", json!(station.map(|s| secs(s.retry_interval)))),
+        ("sta_fallback_s", json!(station.map(|s| secs(s.fallback_retry)))),
+        ("tls_cert", json!(config.tls.map(|tls| tls.cert.len()))),
+        ("tls_key", Value::Null),
+        ("wifi_ps", json!(config.power_save.map(power_save_name))),
+        ("ws_max_message", json!(config.ws_max_message)),
+        ("ws_send_timeout_ms", json!(ms(config.ws_send_timeout))),
+        ("ws_max_clients", json!(config.ws_max_clients)),
+        ("ws_slow_drops", json!(config.ws_slow_drops)),
+        ("ws_frame_debug", json!(ws::frame_debug())),
+        ("ws_token", Value::Null),
+        ("ws_origin_check", json!(config.ws_origins.is_some())),
+        ("ws_origins", json!(config.ws_origins.as_deref().unwrap_or_default())),
+    ];
+
+    settings
+        .into_iter()
+        .map(|(key, value)| {
+            let runtime = match key {
+                "frame_trace" => bridge.tracing() != config.frame_trace,
+                "listen_only" => bridge.listen_only() != config.listen_only,
+                "ws_frame_debug" => ws::frame_debug() != config.ws_frame_debug,
+                "log_level" => logger::level() != config.log_level,
+                _ => false,
+            };
+            let source = if runtime {
+                Source::Runtime
+            } else if config.from_nvs.contains(&key) {
+                Source::Nvs
+            } else {
+                Source::Default
+            };
+            Setting { key, value, source, redacted: nvs_debug::is_secret(key) && source != Source::Default }
+        })
+        .collect()
+}
+
+// The parts that no NVS key changes, and what the settings add up to
+pub fn summary(config: &Config) -> Value {
+    json!({
+        "uart": { "baud": UART_BAUD, "tx_gpio": UART_GPIOS[0], "rx_gpio": UART_GPIOS[1] },
+        "ap_ssid": wifi::WIFI_SSID,
+        "station": config.station.is_some(),
+        "tls": config.tls.is_some(),
+        "auth": config.ws_token.is_some(),
+    })
+}
+
+// Written back the way on_connect is stored
+fn command((cmd, payload): &(u8, Vec<u8>)) -> String {
+    let name = protocol::cmd_name(*cmd).map_or_else(|| format!("0x{:02x}", cmd), str::to_string);
+    if payload.is_empty() {
+        return name;
+    }
+    let hex: String = payload.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}:{}", name, hex)
+}
+
+fn power_save_name(mode: PowerSave) -> &'static str {
+    match mode {
+        PowerSave::None => "none",
+        PowerSave::Min => "min",
+        PowerSave::Max => "max",
+    }
+}
@@ -0,0 +1,139 @@
+// Errors returned by /api/* handlers.
+// Every variant maps to an HTTP status and a numeric code, and is sent to the
+// client as {"error": "...", "code": N}. Flipper status codes pass through
+// unchanged (see protocol/schema.md); bridge-side errors start at 100.
+
+use embedded_svc::http::server::Request;
+use embedded_svc::io::Write;
+use esp_idf_svc::http::server::EspHttpConnection;
+use esp_idf_svc::io::EspIOError;
+use esp_idf_sys::EspError;
+use std::fmt;
+
+use crate::protocol::{status, status_name};
+
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    NotFound(String),
+    Forbidden(String),
+    // Declared or actual body size over the route's limit
+    TooLarge(usize),
+    // Another client holds the UART lock; carries its owner
+    Locked(String),
+    // Known path, other method; carries the Allow header value
+    MethodNotAllowed(String),
+    Uart(EspError),
+    Timeout,
+    // cmd_rx_max bytes arrived without the response; carries the cap
+    Overrun(usize),
+    Busy,
+    // Listen-only mode is on, so nothing is written to the Flipper
+    ListenOnly,
+    Flipper(u8),
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn status(&self) -> u16 {
+        match self {
+            ApiError::BadRequest(_) => 400,
+            ApiError::NotFound(_) => 404,
+            ApiError::Forbidden(_) => 403,
+            ApiError::TooLarge(_) => 413,
+            ApiError::Locked(_) => 423,
+            ApiError::MethodNotAllowed(_) => 405,
+            ApiError::Uart(_) => 502,
+            ApiError::Timeout => 504,
+            ApiError::Overrun(_) => 502,
+            ApiError::Busy => 503,
+            ApiError::ListenOnly => 409,
+            ApiError::Flipper(code) => match *code {
+                status::ERR_INVALID => 400,
+                status::ERR_NOT_FOUND => 404,
+                status::ERR_NO_DATA => 422,
+                status::ERR_FULL => 507,
+                status::ERR_BUSY | status::ERR_NOT_READY => 503,
+                status::ERR_TIMEOUT => 504,
+                _ => 502,
+            },
+            ApiError::Internal(_) => 500,
+        }
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            ApiError::Flipper(code) => *code as u16,
+            ApiError::BadRequest(_) => 100,
+            ApiError::NotFound(_) => 101,
+            ApiError::Forbidden(_) => 102,
+            ApiError::TooLarge(_) => 103,
+            ApiError::Locked(_) => 104,
+            ApiError::MethodNotAllowed(_) => 105,
+            ApiError::Uart(_) => 200,
+            ApiError::Timeout => 201,
+            ApiError::Busy => 202,
+            ApiError::Overrun(_) => 203,
+            ApiError::ListenOnly => 204,
+            ApiError::Internal(_) => 255,
+        }
+    }
+
+    pub fn body(&self) -> String {
+        serde_json::json!({
+            "error": self.to_string(),
+            "code": self.code(),
+        })
+        .to_string()
+    }
+
+    pub fn respond(&self, req: Request<&mut EspHttpConnection>) -> anyhow::Result<()> {
+        let mut resp = req.into_response(self.status(), None, &[
+            ("Content-Type", "application/json"),
+        ])?;
+        resp.write_all(self.body().as_bytes())?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::BadRequest(msg) => write!(f, "bad request: {}", msg),
+            ApiError::NotFound(msg) => write!(f, "not found: {}", msg),
+            ApiError::Forbidden(msg) => write!(f, "forbidden: {}", msg),
+            ApiError::TooLarge(max) => write!(f, "body larger than {} bytes", max),
+            ApiError::Locked(owner) => write!(f, "UART locked by {}", owner),
+            ApiError::MethodNotAllowed(allow) => write!(f, "method not allowed, use {}", allow),
+            ApiError::Uart(e) => write!(f, "UART error: {}", e),
+            ApiError::Timeout => write!(f, "Flipper did not respond in time"),
+            ApiError::Overrun(max) => write!(f, "Flipper sent over {} bytes without a response", max),
+            ApiError::Busy => write!(f, "UART is busy"),
+            ApiError::ListenOnly => write!(f, "listen-only mode, nothing is sent to the Flipper"),
+            ApiError::Flipper(code) => {
+                write!(f, "Flipper error: {}", status_name(*code).unwrap_or("unrecognized status"))
+            }
+            ApiError::Internal(msg) => write!(f, "internal error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<EspError> for ApiError {
+    fn from(e: EspError) -> Self {
+        ApiError::Uart(e)
+    }
+}
+
+impl From<EspIOError> for ApiError {
+    fn from(e: EspIOError) -> Self {
+        ApiError::Internal(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> Self {
+        ApiError::BadRequest(e.to_string())
+    }
+}
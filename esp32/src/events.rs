@@ -0,0 +1,201 @@
+// Typed event history for /api/events: WebSocket clients coming and going,
+// commands, file transfers, failed API calls, boots and restarts. The last
+// MAX_EVENTS are kept in RAM. With event_persist set, the newest SAVED of
+// them are also written to NVS on a planned restart and read back at boot,
+// so a timeline survives /api/reboot. `boot` tells the boots apart, since
+// `at` restarts from zero with each.
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_sys as sys;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Mutex, OnceLock};
+
+use crate::config::NAMESPACE;
+use crate::error::ApiError;
+use crate::protocol::Frame;
+use crate::stats;
+
+const KEY: &str = "events";
+const MAX_EVENTS: usize = 128;
+// About 120 bytes each as JSON, so the blob stays under 4KB
+const SAVED: usize = 32;
+
+// Values of `type`, for ?type= and its error message
+pub const TYPES: &[&str] = &[
+    "boot",
+    "restart",
+    "client_connected",
+    "client_disconnected",
+    "command",
+    "transfer_started",
+    "transfer_finished",
+    "error",
+];
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Boot {
+        // esp_reset_reason(), e.g. "power_on" or "panic"
+        reset: String,
+    },
+    Restart {
+        // "restart" or "factory_reset"
+        reason: String,
+    },
+    ClientConnected {
+        fd: i32,
+        protocol: String,
+    },
+    ClientDisconnected {
+        fd: i32,
+    },
+    // One frame sent on behalf of a client: "api", "batch" or "rpc"
+    Command {
+        source: String,
+        cmd: u8,
+        // The Flipper's status, or None if no response came back
+        status: Option<u8>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    TransferStarted {
+        // "download", "upload" or "bundle"
+        kind: String,
+    },
+    TransferFinished {
+        kind: String,
+        // The file, or a bundle's target directory; None if it never got that far
+        path: Option<String>,
+        ok: bool,
+        bytes: Option<u64>,
+        ms: u64,
+    },
+    // A failed /api/* call
+    Error {
+        method: String,
+        path: String,
+        status: u16,
+        code: u16,
+        message: String,
+    },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Boot { .. } => "boot",
+            Event::Restart { .. } => "restart",
+            Event::ClientConnected { .. } => "client_connected",
+            Event::ClientDisconnected { .. } => "client_disconnected",
+            Event::Command { .. } => "command",
+            Event::TransferStarted { .. } => "transfer_started",
+            Event::TransferFinished { .. } => "transfer_finished",
+            Event::Error { .. } => "error",
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Entry {
+    // Counts up across boots, so ?since= can page through them
+    pub id: u32,
+    pub boot: u32,
+    // ms since that boot, as on log lines
+    pub at: u32,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+static EVENTS: Mutex<VecDeque<Entry>> = Mutex::new(VecDeque::new());
+// Only set with event_persist
+static NVS: OnceLock<EspDefaultNvsPartition> = OnceLock::new();
+
+// Picks up what the last planned restart saved, then records this boot
+pub fn start(nvs: EspDefaultNvsPartition, persist: bool) {
+    if persist {
+        match read(&nvs) {
+            Ok(saved) => EVENTS.lock().unwrap().extend(saved),
+            Err(e) => warn!("Saved events unreadable ({}), starting empty", e),
+        }
+        let _ = NVS.set(nvs);
+    }
+    record(Event::Boot { reset: reset_reason().into() });
+}
+
+pub fn record(event: Event) {
+    let mut events = EVENTS.lock().unwrap();
+    let id = events.back().map_or(0, |last| last.id.wrapping_add(1));
+    if events.len() == MAX_EVENTS {
+        events.pop_front();
+    }
+    let at = unsafe { sys::esp_log_timestamp() };
+    events.push_back(Entry { id, boot: stats::lifetime().boots, at, event });
+}
+
+// An exchange with the Flipper, however it ended
+pub fn command(source: &str, cmd: u8, result: &Result<Frame, ApiError>) {
+    let (status, error) = match result {
+        Ok(frame) => (Some(frame.status), None),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    record(Event::Command { source: source.into(), cmd, status, error });
+}
+
+// Oldest first, only those after `since` and of the given types if any
+pub fn list(types: &[&str], since: Option<u32>) -> Vec<Entry> {
+    EVENTS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|entry| since.map_or(true, |since| entry.id > since))
+        .filter(|entry| types.is_empty() || types.contains(&entry.event.name()))
+        .cloned()
+        .collect()
+}
+
+// Called on the way down for a planned restart
+pub fn save() {
+    let Some(nvs) = NVS.get() else {
+        return;
+    };
+    let events = EVENTS.lock().unwrap();
+    let newest: Vec<&Entry> = events.iter().skip(events.len().saturating_sub(SAVED)).collect();
+    let result = serde_json::to_vec(&newest).map_err(anyhow::Error::from).and_then(|blob| {
+        let mut nvs = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+        nvs.set_blob(KEY, &blob)?;
+        Ok(())
+    });
+    if let Err(e) = result {
+        warn!("Saving events failed: {}", e);
+    }
+}
+
+fn read(nvs: &EspDefaultNvsPartition) -> anyhow::Result<Vec<Entry>> {
+    let nvs = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+    let Some(len) = nvs.blob_len(KEY)? else {
+        return Ok(Vec::new());
+    };
+    let mut buf = vec![0u8; len];
+    match nvs.get_blob(KEY, &mut buf)? {
+        Some(blob) => Ok(serde_json::from_slice(blob)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn reset_reason() -> &'static str {
+    match unsafe { sys::esp_reset_reason() } {
+        sys::esp_reset_reason_t_ESP_RST_POWERON => "power_on",
+        sys::esp_reset_reason_t_ESP_RST_EXT => "external",
+        sys::esp_reset_reason_t_ESP_RST_SW => "software",
+        sys::esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        sys::esp_reset_reason_t_ESP_RST_INT_WDT
+        | sys::esp_reset_reason_t_ESP_RST_TASK_WDT
+        | sys::esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep_sleep",
+        sys::esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        _ => "unknown",
+    }
+}
@@ -0,0 +1,166 @@
+// gzip for /api/* responses. A full deflate implementation wants a few
+// hundred KB for its window and hash chains, which this board doesn't have,
+// so this is the small end of one: greedy LZ77 over the whole body with a
+// single-entry hash table, and the fixed Huffman code. On JSON, with its
+// repeated keys, that still takes most responses to well under half.
+
+// 2048 hash slots of one u32 position each, 8KB per response
+const HASH_BITS: u32 = 11;
+const WINDOW: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+// Base lengths and extra bits of length codes 257-285
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+// Base distances and extra bits of distance codes 0-29
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097,
+    6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    // Magic, deflate, no flags, no mtime, no extra flags, OS unknown
+    let header = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    let mut bits = Bits { out: header, acc: 0, len: 0 };
+    deflate(data, &mut bits);
+    let mut out = bits.finish();
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+// Accept-Encoding lists gzip without q=0
+pub fn accepted(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|item| {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or_default().trim();
+        let refused = params.any(|param| {
+            param.trim().strip_prefix("q=").and_then(|q| q.trim().parse::<f32>().ok()) == Some(0.0)
+        });
+        (coding.eq_ignore_ascii_case("gzip") || coding == "*") && !refused
+    })
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// One final block with the fixed code
+fn deflate(data: &[u8], bits: &mut Bits) {
+    bits.put(1, 1);
+    bits.put(1, 2);
+
+    // Position + 1 of the last 3-byte sequence with each hash, 0 for none
+    let mut head = vec![0u32; 1 << HASH_BITS];
+    let mut at = 0;
+    while at < data.len() {
+        match match_at(data, at, &mut head) {
+            Some((len, dist)) => {
+                put_length(bits, len);
+                put_distance(bits, dist);
+                for skipped in at + 1..at + len {
+                    insert(data, skipped, &mut head);
+                }
+                at += len;
+            }
+            None => {
+                put_literal(bits, data[at] as u16);
+                at += 1;
+            }
+        }
+    }
+    put_literal(bits, 256);
+}
+
+// The longest match at `at` with the one candidate the table holds, which
+// may be a hash collision, and records `at` in its place
+fn match_at(data: &[u8], at: usize, head: &mut [u32]) -> Option<(usize, usize)> {
+    let slot = hash(data, at)?;
+    let stored = head[slot] as usize;
+    head[slot] = at as u32 + 1;
+    let candidate = stored.checked_sub(1)?;
+    let dist = at - candidate;
+    if dist > WINDOW {
+        return None;
+    }
+
+    let max = (data.len() - at).min(MAX_MATCH);
+    let len = (0..max).take_while(|&i| data[candidate + i] == data[at + i]).count();
+    (len >= MIN_MATCH).then_some((len, dist))
+}
+
+fn insert(data: &[u8], at: usize, head: &mut [u32]) {
+    if let Some(slot) = hash(data, at) {
+        head[slot] = at as u32 + 1;
+    }
+}
+
+fn hash(data: &[u8], at: usize) -> Option<usize> {
+    let b = data.get(at..at + MIN_MATCH)?;
+    let v = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+    Some((v.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize)
+}
+
+fn put_literal(bits: &mut Bits, value: u16) {
+    let (code, len) = match value {
+        0..=143 => (0x30 + value, 8),
+        144..=255 => (0x190 + value - 144, 9),
+        256..=279 => (value - 256, 7),
+        _ => (0xc0 + value - 280, 8),
+    };
+    bits.put_code(code, len);
+}
+
+fn put_length(bits: &mut Bits, len: usize) {
+    let i = LENGTH_BASE.iter().rposition(|&base| base as usize <= len).unwrap_or(0);
+    put_literal(bits, 257 + i as u16);
+    bits.put((len - LENGTH_BASE[i] as usize) as u32, LENGTH_EXTRA[i]);
+}
+
+fn put_distance(bits: &mut Bits, dist: usize) {
+    let i = DIST_BASE.iter().rposition(|&base| base as usize <= dist).unwrap_or(0);
+    bits.put_code(i as u16, 5);
+    bits.put((dist - DIST_BASE[i] as usize) as u32, DIST_EXTRA[i]);
+}
+
+// Deflate packs values LSB first, but Huffman codes MSB first
+struct Bits {
+    out: Vec<u8>,
+    acc: u32,
+    len: u8,
+}
+
+impl Bits {
+    fn put(&mut self, value: u32, len: u8) {
+        self.acc |= value << self.len;
+        self.len += len;
+        while self.len >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    fn put_code(&mut self, code: u16, len: u8) {
+        let reversed = (code.reverse_bits() >> (16 - len)) as u32;
+        self.put(reversed, len);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.out.push(self.acc as u8);
+        }
+        self.out
+    }
+}
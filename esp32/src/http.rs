@@ -0,0 +1,1891 @@
+use anyhow::Result;
+use embedded_svc::http::server::Request;
+use embedded_svc::http::Headers;
+use embedded_svc::io::{ErrorType, Read, Write};
+use esp_idf_svc::http::server::{Configuration as HttpConfig, EspHttpConnection, EspHttpServer};
+use esp_idf_svc::handle::RawHandle;
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::EspIOError;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::tls::X509;
+use esp_idf_sys as sys;
+use log::*;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::ffi::{CStr, CString};
+use std::mem::ManuallyDrop;
+use std::net::{IpAddr, TcpStream};
+use std::os::fd::FromRawFd;
+use std::ptr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::captive;
+use crate::config::{Config, MAX_CMD_DELAY, NAMESPACE};
+use crate::effective;
+use crate::error::ApiError;
+use crate::events::{self, Event};
+use crate::gzip;
+use crate::ir::{self, Lookup};
+use crate::lock;
+use crate::logger;
+use crate::multipart::Multipart;
+use crate::nvs_debug::{self, Kind};
+use crate::presence;
+use crate::protocol::{self, cmd, status};
+use crate::recent;
+use crate::screenshot;
+use crate::shutdown::{self, Reason};
+use crate::stats;
+use crate::tar::{self, Tar};
+use crate::transfer;
+use crate::uart::{Bridge, UART_BAUD};
+use crate::version;
+use crate::webapp;
+use crate::wifi;
+use crate::ws;
+
+// Commands accepted by one /api/command/batch call
+const MAX_BATCH: usize = 16;
+// Random bytes sent with /api/flipper/ping
+const PING_BYTES: usize = 8;
+// Longest notification the Flipper app will play, ms
+const NOTIFY_MAX_MS: u16 = 5000;
+// NOTIFY flag bits
+const NOTIFY_VIBRATE: u8 = 0x01;
+const NOTIFY_SOUND: u8 = 0x02;
+// How long /api/health/ready waits for the Flipper's PING response
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Upload target when the form has no path field
+const UPLOAD_DIR: &str = "/ext";
+// Longest path or filename form field
+const MAX_FORM_FIELD: usize = 256;
+const MAX_UPLOAD_PATH: usize = 255;
+
+const MAX_URI_HANDLERS: usize = 48;
+// The port-80 redirect server only sends a header
+const REDIRECT_STACK: usize = 3072;
+// Anything but esp-idf-svc's 32768, which the main server already holds
+const REDIRECT_CTRL_PORT: u16 = 32769;
+
+// One log line per /api/* request, from the http_log NVS key
+static REQUEST_LOG: AtomicBool = AtomicBool::new(false);
+// Largest JSON body read_json() accepts, from max_body
+static MAX_BODY: AtomicUsize = AtomicUsize::new(4096);
+// Smallest /api/* response sent gzipped to clients that take it, from
+// gzip_min; 0 turns it off
+static GZIP_MIN: AtomicUsize = AtomicUsize::new(0);
+// ws_token, if set, for the routes needs_token() picks
+static TOKEN: OnceLock<Option<String>> = OnceLock::new();
+// Every registered path and method, for the Allow header of a 405
+static ROUTES: Mutex<Vec<(String, Method)>> = Mutex::new(Vec::new());
+
+pub fn setup_http_server(
+    bridge: Arc<Bridge>,
+    config: Arc<Config>,
+    nvs: EspDefaultNvsPartition,
+) -> Result<EspHttpServer<'static>> {
+    let server_config = HttpConfig {
+        http_port: 80,
+        https_port: 443,
+        stack_size: config.http_stack,
+        max_open_sockets: config.http_sockets,
+        // The default 32 is close to full once the captive portal probes are in
+        max_uri_handlers: MAX_URI_HANDLERS,
+        server_certificate: config.tls.map(|tls| X509::pem_until_nul(tls.cert)),
+        private_key: config.tls.map(|tls| X509::pem_until_nul(tls.key)),
+        ..Default::default()
+    };
+
+    let mut server = EspHttpServer::new(&server_config)?;
+    REQUEST_LOG.store(config.http_log, Ordering::Relaxed);
+    MAX_BODY.store(config.max_body, Ordering::Relaxed);
+    GZIP_MIN.store(config.gzip_min, Ordering::Relaxed);
+    let _ = TOKEN.set(config.ws_token.clone());
+    info!(
+        "HTTP{} server up, {} byte stack, {} sockets",
+        if config.tls.is_some() { "S" } else { "" },
+        server_config.stack_size,
+        server_config.max_open_sockets
+    );
+    if config.tls.is_some() {
+        start_redirect(&config)?;
+    }
+
+    // OS connectivity probes land on the portal page instead of succeeding
+    if config.captive {
+        let scheme = if config.tls.is_some() { "https" } else { "http" };
+        let target = format!("{}://{}{}", scheme, config.ap_ip, config.captive_target);
+        for path in captive::PROBE_PATHS {
+            let target = target.clone();
+            route(&mut server, path, Method::Get, move |req| -> Result<()> {
+                req.into_response(302, None, &[("Location", target.as_str())])?;
+                Ok(())
+            })?;
+        }
+        info!("Captive portal redirecting to {}", target);
+    }
+
+    // Serve webapp
+    let index = webapp::index_html(&config.base_path);
+    route(&mut server, "/", Method::Get, move |req| -> Result<()> {
+        req.into_ok_response()?
+            .write_all(index.as_bytes())?;
+        Ok(())
+    })?;
+
+    route(&mut server, "/app.js", Method::Get, |req| -> Result<()> {
+        let mut resp = req.into_response(200, None, &[
+            ("Content-Type", "application/javascript"),
+        ])?;
+        resp.write_all(webapp::APP_JS.as_bytes())?;
+        Ok(())
+    })?;
+
+    route(&mut server, "/style.css", Method::Get, |req| -> Result<()> {
+        let mut resp = req.into_response(200, None, &[
+            ("Content-Type", "text/css"),
+        ])?;
+        resp.write_all(webapp::STYLE_CSS.as_bytes())?;
+        Ok(())
+    })?;
+
+    // Served from the root so its scope covers the whole webapp. no-cache
+    // makes the browser check for a newer worker on every load.
+    route(&mut server, "/sw.js", Method::Get, |req| -> Result<()> {
+        let mut resp = req.into_response(200, None, &[
+            ("Content-Type", "application/javascript"),
+            ("Cache-Control", "no-cache"),
+        ])?;
+        resp.write_all(webapp::SW_JS.as_bytes())?;
+        Ok(())
+    })?;
+
+    // Send a single command frame and return the Flipper's response
+    let bridge_cmd = bridge.clone();
+    let config_cmd = config.clone();
+    api_handler(&mut server, "/api/command", Method::Post, move |req| {
+        bridge_cmd.lock().check(lock_key(req))?;
+        let timeout = request_timeout(req.uri(), &config_cmd)?;
+        let encoding = Encoding::from_query(req.uri())?;
+        let body: CommandRequest = read_json(req)?;
+        let payload = protocol::from_hex(&body.payload)
+            .ok_or_else(|| ApiError::BadRequest("payload must be a hex string".into()))?;
+
+        let timeout = timeout.unwrap_or_else(|| protocol::response_timeout(body.cmd));
+        recent::record(body.cmd, &payload);
+        let result = bridge_cmd.command(body.cmd, &payload, timeout);
+        events::command("api", body.cmd, &result);
+        let frame = result?;
+        if frame.status != status::OK {
+            return Err(ApiError::Flipper(frame.status));
+        }
+
+        Ok(json!({
+            "seq": frame.seq,
+            "cmd": frame.cmd,
+            "payload": encoding.encode(&frame.payload),
+            "encoding": encoding.name(),
+        }))
+    })?;
+
+    // Several command frames run back to back, with nothing else on the UART
+    // in between. Results come back in order.
+    let bridge_batch = bridge.clone();
+    let config_batch = config.clone();
+    api_handler(&mut server, "/api/command/batch", Method::Post, move |req| {
+        bridge_batch.lock().check(lock_key(req))?;
+        let timeout = request_timeout(req.uri(), &config_batch)?;
+        let encoding = Encoding::from_query(req.uri())?;
+        let body: BatchRequest = read_json(req)?;
+        if body.commands.is_empty() || body.commands.len() > MAX_BATCH {
+            return Err(ApiError::BadRequest(format!("commands must hold 1 to {} entries", MAX_BATCH)));
+        }
+
+        let settle = |ms: Option<u32>, field: &str| match ms {
+            Some(ms) if ms as u128 > MAX_CMD_DELAY.as_millis() => Err(ApiError::BadRequest(format!(
+                "{} is at most {}",
+                field,
+                MAX_CMD_DELAY.as_millis()
+            ))),
+            Some(ms) => Ok(Some(Duration::from_millis(ms as u64))),
+            None => Ok(None),
+        };
+        let batch_settle = settle(body.settle_ms, "settle_ms")?.unwrap_or(config_batch.batch_settle);
+
+        let requests = body
+            .commands
+            .iter()
+            .enumerate()
+            .map(|(i, command)| {
+                let payload = protocol::from_hex(&command.payload)
+                    .ok_or_else(|| ApiError::BadRequest(format!("commands[{}].payload must be a hex string", i)))?;
+                let timeout = timeout.unwrap_or_else(|| protocol::response_timeout(command.cmd));
+                let pause = settle(command.settle_ms, &format!("commands[{}].settle_ms", i))?.unwrap_or(batch_settle);
+                Ok((command.cmd, payload, timeout, pause))
+            })
+            .collect::<Result<Vec<_>, ApiError>>()?;
+
+        let ok = |result: &Result<protocol::Frame, ApiError>| matches!(result, Ok(frame) if frame.status == status::OK);
+        let results = bridge_batch.command_batch(&requests, |result| ok(result) || !body.stop_on_error);
+        recent::record_all(requests[..results.len()].iter().map(|(cmd, payload, _, _)| (*cmd, &payload[..])));
+        for ((cmd, _, _, _), result) in requests.iter().zip(&results) {
+            events::command("batch", *cmd, result);
+        }
+
+        let results: Vec<Value> = requests
+            .iter()
+            .zip(&results)
+            .map(|((cmd, _, _, _), result)| match result {
+                Ok(frame) => json!({
+                    "cmd": cmd,
+                    "ok": frame.status == status::OK,
+                    "status": frame.status,
+                    "payload": encoding.encode(&frame.payload),
+                }),
+                Err(e) => json!({
+                    "cmd": cmd,
+                    "ok": false,
+                    "error": e.to_string(),
+                    "code": e.code(),
+                }),
+            })
+            .collect();
+
+        Ok(json!({
+            "results": results,
+            "completed": results.len() == requests.len(),
+            "encoding": encoding.name(),
+        }))
+    })?;
+
+    // Frames sent through the two endpoints above, oldest first, kept across
+    // reboots so they can be replayed
+    api_handler(&mut server, "/api/command/recent", Method::Get, |_req| {
+        Ok(json!({ "commands": recent::list() }))
+    })?;
+
+    // Which side is using the UART: idle, raw passthrough or a framed exchange
+    let bridge_status = bridge.clone();
+    let ws_max_clients = config.ws_max_clients;
+    api_handler(&mut server, "/api/status", Method::Get, move |_req| {
+        Ok(status(&bridge_status, ws_max_clients))
+    })?;
+
+    // Liveness: answered by anything that can still run a handler
+    api_handler(&mut server, "/api/health/live", Method::Get, |_req| {
+        Ok(json!({ "alive": true }))
+    })?;
+
+    // Readiness: the board is on its network and the Flipper answers a PING
+    let bridge_ready = bridge.clone();
+    route(&mut server, "/api/health/ready", Method::Get, move |req| -> Result<()> {
+        let started = Instant::now();
+        let wifi = wifi::state();
+        let wifi_up = matches!(wifi, wifi::State::Ap | wifi::State::Connected);
+        // The boot wait is PINGing already, so no need for another
+        let starting = presence::starting();
+        // Another client's lock means the link is in use, not down, so
+        // the probe stays ready and just skips its PING
+        let locked = bridge_ready.lock().check(lock_key(&req)).is_err();
+        let flipper = if starting {
+            Err(ApiError::Internal("still waiting for the Flipper after boot".into()))
+        } else if locked {
+            Ok(None)
+        } else {
+            let start = Instant::now();
+            call(&bridge_ready, cmd::PING, &[], READY_TIMEOUT).map(|_| Some(start.elapsed()))
+        };
+
+        let ready = wifi_up && flipper.is_ok();
+        let mut body = json!({
+            "ready": ready,
+            "starting": starting,
+            "locked": locked,
+            "wifi": wifi.name(),
+            "flipper": flipper.is_ok(),
+        });
+        match flipper {
+            Ok(Some(rtt)) => body["rtt_ms"] = json!(rtt.as_micros() as f64 / 1000.0),
+            Ok(None) => {}
+            Err(e) => body["error"] = json!(e.to_string()),
+        }
+
+        respond_check(req, ready, &body, started)
+    })?;
+
+    // Scripted end-to-end check of the Flipper link, for CI or after a config
+    // change. Steps run in order and the first to fail ends the test.
+    let bridge_selftest = bridge.clone();
+    route(&mut server, "/api/selftest/bridge", Method::Get, move |req| -> Result<()> {
+        let started = Instant::now();
+        let mut steps = Vec::new();
+        let pass = selftest(&bridge_selftest, lock_key(&req), &mut steps);
+        let body = json!({
+            "pass": pass,
+            "steps": steps,
+            "total_ms": started.elapsed().as_micros() as f64 / 1000.0,
+        });
+        respond_check(req, pass, &body, started)
+    })?;
+
+    // Usage summed over every boot, and this boot's share of it
+    api_handler(&mut server, "/api/stats/lifetime", Method::Get, |_req| {
+        let mut body = json!(stats::lifetime());
+        body["this_boot"] = json!(stats::session());
+        Ok(body)
+    })?;
+
+    // Recorded events, oldest first. ?type=a,b keeps only those types,
+    // ?since=<id> only newer ones and ?limit=<N> the newest N.
+    api_handler(&mut server, "/api/events", Method::Get, |req| {
+        let types: Vec<&str> = query_param(req.uri(), "type")
+            .map(|types| types.split(',').filter(|name| !name.is_empty()).collect())
+            .unwrap_or_default();
+        if let Some(unknown) = types.iter().find(|name| !events::TYPES.contains(name)) {
+            return Err(ApiError::BadRequest(format!(
+                "unknown event type {:?}, expected one of {:?}",
+                unknown,
+                events::TYPES
+            )));
+        }
+        let since = number_param::<u32>(req.uri(), "since")?;
+        let limit = number_param::<usize>(req.uri(), "limit")?;
+
+        let mut list = events::list(&types, since);
+        if let Some(limit) = limit {
+            list.drain(..list.len().saturating_sub(limit));
+        }
+        Ok(json!({ "events": list }))
+    })?;
+
+    api_handler(&mut server, "/api/version", Method::Get, |_req| {
+        Ok(json!(version::current()))
+    })?;
+
+    // Read by the webapp at load time, so it can be white-labeled from NVS
+    let branding = json!({
+        "title": config.brand_title,
+        "accent": config.brand_accent,
+        "logo": config.brand_logo,
+    });
+    api_handler(&mut server, "/api/branding", Method::Get, move |_req| {
+        Ok(branding.clone())
+    })?;
+
+    // Orderly restart; the reply goes out before anything is stopped
+    api_handler(&mut server, "/api/reboot", Method::Post, move |req| {
+        shutdown::request(Reason::Restart);
+        Ok(json!({ "restarting": true }))
+    })?;
+
+    // Exclusive use of the UART for one client. The holder sends the key it
+    // gets back as X-Ecco-Lock (or ?lock= on /ws); other clients get 423.
+    let bridge_lock = bridge.clone();
+    api_handler(&mut server, "/api/lock", Method::Get, move |_req| {
+        Ok(match bridge_lock.lock().holder() {
+            Some(holder) => json!({
+                "locked": true,
+                "owner": holder.owner,
+                "expires_in_ms": holder.expires_in.as_millis() as u64,
+            }),
+            None => json!({ "locked": false }),
+        })
+    })?;
+
+    // Take the lock, or renew it by sending the current key
+    let bridge_lock = bridge.clone();
+    api_handler(&mut server, "/api/lock", Method::Post, move |req| {
+        let key = lock_key(req).map(str::to_string);
+        let body: LockRequest = read_json(req)?;
+        let owner = body.owner.trim();
+        if owner.is_empty() || owner.len() > lock::MAX_OWNER {
+            return Err(ApiError::BadRequest(format!("owner must be 1 to {} characters", lock::MAX_OWNER)));
+        }
+        let ttl = match body.ttl_ms {
+            Some(0) => return Err(ApiError::BadRequest("ttl_ms must be positive".into())),
+            Some(ms) => Duration::from_millis(ms as u64).min(lock::MAX_TTL),
+            None => lock::DEFAULT_TTL,
+        };
+
+        let key = bridge_lock.lock().acquire(owner, ttl, key.as_deref())?;
+        info!("UART locked by {} for {:?}", owner, ttl);
+        Ok(json!({ "lock": key, "owner": owner, "ttl_ms": ttl.as_millis() as u64 }))
+    })?;
+
+    let bridge_lock = bridge.clone();
+    api_handler(&mut server, "/api/lock", Method::Delete, move |req| {
+        bridge_lock.lock().release(lock_key(req))?;
+        info!("UART lock released");
+        Ok(json!({ "locked": false }))
+    })?;
+
+    // Round trip of a PING carrying random bytes, which the Flipper echoes.
+    // The RTT includes queueing behind any exchange already in flight.
+    let bridge_ping = bridge.clone();
+    api_handler(&mut server, "/api/flipper/ping", Method::Get, move |req| {
+        bridge_ping.lock().check(lock_key(req))?;
+        let mut payload = [0u8; PING_BYTES];
+        unsafe { sys::esp_fill_random(payload.as_mut_ptr() as *mut _, payload.len()) };
+
+        let start = Instant::now();
+        let frame = call(&bridge_ping, cmd::PING, &payload, protocol::response_timeout(cmd::PING))?;
+        let rtt = start.elapsed();
+
+        Ok(json!({
+            "rtt_ms": rtt.as_micros() as f64 / 1000.0,
+            "echo": frame.payload == payload,
+        }))
+    })?;
+
+    // Blink the LED, plus optional vibration and beep, to find the Flipper
+    let bridge_notify = bridge.clone();
+    api_handler(&mut server, "/api/flipper/notify", Method::Post, move |req| {
+        bridge_notify.lock().check(lock_key(req))?;
+        let body: NotifyRequest = read_json(req)?;
+        let color = match body.color.as_deref() {
+            None => [0, 0, 255],
+            Some(color) => parse_color(color)
+                .ok_or_else(|| ApiError::BadRequest("color must be #rrggbb".into()))?,
+        };
+        if body.duration_ms > NOTIFY_MAX_MS {
+            return Err(ApiError::BadRequest(format!("duration_ms is at most {}", NOTIFY_MAX_MS)));
+        }
+
+        let mut flags = 0;
+        if body.vibrate {
+            flags |= NOTIFY_VIBRATE;
+        }
+        if body.sound {
+            flags |= NOTIFY_SOUND;
+        }
+
+        let mut payload = color.to_vec();
+        payload.extend_from_slice(&body.duration_ms.to_le_bytes());
+        payload.push(flags);
+
+        call(&bridge_notify, cmd::NOTIFY, &payload, protocol::response_timeout(cmd::NOTIFY))?;
+        Ok(json!({ "notified": true }))
+    })?;
+
+    // Runtime log level, kept in NVS so it survives a reboot
+    api_handler(&mut server, "/api/debug/loglevel", Method::Get, |_req| {
+        Ok(json!({ "level": level_name(logger::level()) }))
+    })?;
+
+    let nvs_level = nvs.clone();
+    api_handler(&mut server, "/api/debug/loglevel", Method::Post, move |req| {
+        let body: LogLevelRequest = read_json(req)?;
+        let level: LevelFilter = body
+            .level
+            .parse()
+            .map_err(|_| ApiError::BadRequest("level must be off, error, warn, info, debug or trace".into()))?;
+
+        logger::set_level(level);
+        Config::store_str(&nvs_level, "log_level", level_name(level))
+            .map_err(|e| ApiError::Internal(format!("saving log_level: {}", e)))?;
+        info!("Log level set to {}", level_name(logger::level()));
+
+        Ok(json!({ "level": level_name(logger::level()) }))
+    })?;
+
+    // Decoded frame log, kept in NVS like the log level
+    let bridge_trace = bridge.clone();
+    api_handler(&mut server, "/api/debug/frames", Method::Get, move |_req| {
+        Ok(json!({ "enabled": bridge_trace.tracing() }))
+    })?;
+
+    let bridge_trace = bridge.clone();
+    let nvs_trace = nvs.clone();
+    api_handler(&mut server, "/api/debug/frames", Method::Post, move |req| {
+        let body: FrameTraceRequest = read_json(req)?;
+
+        bridge_trace.set_tracing(body.enabled);
+        Config::store_u8(&nvs_trace, "frame_trace", body.enabled as u8)
+            .map_err(|e| ApiError::Internal(format!("saving frame_trace: {}", e)))?;
+        info!("Frame trace {}", if body.enabled { "on" } else { "off" });
+
+        Ok(json!({ "enabled": bridge_trace.tracing() }))
+    })?;
+
+    // Metadata of every WebSocket frame sent, in the log
+    api_handler(&mut server, "/api/debug/ws-frames", Method::Get, |_req| {
+        Ok(json!({ "enabled": ws::frame_debug() }))
+    })?;
+
+    let nvs_ws_frames = nvs.clone();
+    api_handler(&mut server, "/api/debug/ws-frames", Method::Post, move |req| {
+        let body: FrameTraceRequest = read_json(req)?;
+
+        ws::set_frame_debug(body.enabled);
+        Config::store_u8(&nvs_ws_frames, "ws_frame_debug", body.enabled as u8)
+            .map_err(|e| ApiError::Internal(format!("saving ws_frame_debug: {}", e)))?;
+        info!("WebSocket frame debug {}", if body.enabled { "on" } else { "off" });
+
+        Ok(json!({ "enabled": ws::frame_debug() }))
+    })?;
+
+    // Listen-only mode: the reader and every tap keep going, but nothing is
+    // written to the Flipper until it is turned off again
+    let bridge_listen = bridge.clone();
+    api_handler(&mut server, "/api/debug/listen-only", Method::Get, move |_req| {
+        Ok(json!({ "enabled": bridge_listen.listen_only() }))
+    })?;
+
+    let bridge_listen = bridge.clone();
+    let nvs_listen = nvs.clone();
+    api_handler(&mut server, "/api/debug/listen-only", Method::Post, move |req| {
+        let body: ListenOnlyRequest = read_json(req)?;
+
+        bridge_listen.set_listen_only(body.enabled);
+        Config::store_u8(&nvs_listen, "listen_only", body.enabled as u8)
+            .map_err(|e| ApiError::Internal(format!("saving listen_only: {}", e)))?;
+        info!("Listen-only mode {}", if body.enabled { "on" } else { "off" });
+
+        Ok(json!({ "enabled": bridge_listen.listen_only() }))
+    })?;
+
+    // Settings as stored in NVS, secrets redacted
+    let nvs_list = nvs.clone();
+    api_handler(&mut server, "/api/debug/nvs", Method::Get, move |req| {
+        let entries = nvs_debug::list(&nvs_list).map_err(nvs_debug::nvs_error)?;
+        Ok(json!({ "namespace": NAMESPACE, "entries": entries }))
+    })?;
+
+    // Writing is only allowed with a ws_token set, so an open AP can't be
+    // reconfigured by whoever joins it
+    let config_nvs = config.clone();
+    let nvs_set = nvs.clone();
+    api_handler(&mut server, "/api/debug/nvs", Method::Post, move |req| {
+        // route() has checked the token already if there is one
+        if config_nvs.ws_token.is_none() {
+            return Err(ApiError::Forbidden("set ws_token to enable NVS writes".into()));
+        }
+        let body: NvsSetRequest = read_json(req)?;
+
+        let value = match (body.value, body.remove) {
+            (Some(value), false) => Some(value),
+            (None, true) => None,
+            _ => return Err(ApiError::BadRequest("give either value or remove".into())),
+        };
+
+        let kind = nvs_debug::set(&nvs_set, &body.key, body.kind, value.as_ref())?;
+        match &value {
+            Some(_) if nvs_debug::is_secret(&body.key) => warn!("NVS {} set through the API", body.key),
+            Some(value) => warn!("NVS {} set to {} through the API", body.key, value),
+            None => warn!("NVS {} removed through the API", body.key),
+        }
+
+        Ok(json!({ "key": body.key, "type": kind, "removed": value.is_none(), "restart_required": true }))
+    })?;
+
+    // Every setting as it is in use now, and whether it came from NVS or is
+    // the default
+    let config_effective = config.clone();
+    let bridge_effective = bridge.clone();
+    api_handler(&mut server, "/api/config/effective", Method::Get, move |req| {
+        let mut body = effective::summary(&config_effective);
+        body["settings"] = json!(effective::settings(&config_effective, &bridge_effective));
+        Ok(body)
+    })?;
+
+    // Kept log lines as a text attachment, optionally bounded with
+    // ?since=<ms since boot> and/or ?lines=<N>
+    route(&mut server, "/api/debug/log/download", Method::Get, |mut req| -> Result<()> {
+        let started = Instant::now();
+        let bounds = number_param::<u32>(req.uri(), "since")
+            .and_then(|since| Ok((since, number_param::<usize>(req.uri(), "lines")?)));
+        let (since, lines) = match bounds {
+            Ok(bounds) => bounds,
+            Err(e) => {
+                log_request(&mut req, Method::Get, e.status(), started);
+                return e.respond(req);
+            }
+        };
+
+        let mut body = logger::history(since.unwrap_or(0), lines.unwrap_or(usize::MAX)).join("\n");
+        body.push('\n');
+
+        log_request(&mut req, Method::Get, 200, started);
+        let mut resp = req.into_response(200, None, &[
+            ("Content-Type", "text/plain"),
+            ("Content-Disposition", "attachment; filename=\"ecco.log\""),
+        ])?;
+        resp.write_all(body.as_bytes())?;
+        Ok(())
+    })?;
+
+    // Names in a Flipper directory, on the SD card (/ext) or internal flash
+    // (/int), with a note on which of the two it is
+    let bridge_list = bridge.clone();
+    api_handler(&mut server, "/api/flipper/list", Method::Get, move |req| {
+        bridge_list.lock().check(lock_key(req))?;
+        let path = match query_param(req.uri(), "path").and_then(percent_decode) {
+            Some(path) if path.starts_with('/') => path,
+            _ => return Err(ApiError::BadRequest("path must be an absolute Flipper directory".into())),
+        };
+        let names = storage_list(&bridge_list, &path)?;
+        let (storage, note) = match storage_mount(&path) {
+            Some((storage, note)) => (Some(storage), Some(note)),
+            None => (None, None),
+        };
+        Ok(json!({ "path": path, "storage": storage, "note": note, "names": names }))
+    })?;
+
+    // File download from Flipper storage, /ext or /int. A single Range is
+    // honored, so an interrupted download can resume where it stopped.
+    let bridge_file = bridge.clone();
+    route(&mut server, "/api/flipper/file", Method::Get, move |mut req| -> Result<()> {
+        let started = Instant::now();
+        if let Err(e) = bridge_file.lock().check(lock_key(&req)) {
+            log_request(&mut req, Method::Get, e.status(), started);
+            return e.respond(req);
+        }
+        let mut transfer = transfer::begin("download");
+        let path = match query_param(req.uri(), "path").and_then(percent_decode) {
+            Some(path) if path.starts_with('/') => path,
+            _ => {
+                let e = ApiError::BadRequest("path must be an absolute Flipper path".into());
+                log_request(&mut req, Method::Get, e.status(), started);
+                return e.respond(req);
+            }
+        };
+        transfer.set_path(&path);
+        let range = req.header("Range").and_then(ByteRange::parse);
+
+        // Read the first chunk where the range most likely starts, which also
+        // gives the file size
+        let guess = match range {
+            Some(ByteRange::From(start) | ByteRange::Span(start, _)) => start,
+            _ => 0,
+        };
+        let (size, first) = match storage_read_at(&bridge_file, &path, guess) {
+            Ok(read) => read,
+            Err(e) => {
+                log_request(&mut req, Method::Get, e.status(), started);
+                return e.respond(req);
+            }
+        };
+
+        let name = path.rsplit('/').next().unwrap_or("download").replace('"', "");
+        let disposition = format!("attachment; filename=\"{}\"", name);
+        let mut headers = vec![
+            ("Content-Type", "application/octet-stream"),
+            ("Accept-Ranges", "bytes"),
+            ("Content-Disposition", disposition.as_str()),
+        ];
+
+        let (status, bounds) = match range.map(|range| range.resolve(size)) {
+            None => (200, (0, size)),
+            Some(Some(bounds)) => (206, bounds),
+            Some(None) => {
+                let content_range = format!("bytes */{}", size);
+                log_request(&mut req, Method::Get, 416, started);
+                req.into_response(416, None, &[("Content-Range", content_range.as_str())])?;
+                return Ok(());
+            }
+        };
+        let content_range = format!("bytes {}-{}/{}", bounds.0, bounds.1.saturating_sub(1), size);
+        if status == 206 {
+            headers.push(("Content-Range", content_range.as_str()));
+        }
+        let first = (bounds.0 == guess).then_some(first);
+
+        log_request(&mut req, Method::Get, status, started);
+        let mut resp = req.into_response(status, None, &headers)?;
+        if let Err(e) = stream_file(&mut resp, &bridge_file, &path, bounds, first) {
+            // Headers are out, so all that's left is to cut the transfer short
+            warn!("Download of {} failed: {}", path, e);
+            return Err(e);
+        }
+        transfer.done((bounds.1 - bounds.0) as u64);
+        Ok(())
+    })?;
+
+    // What the Flipper's display shows right now, as a PNG that can be saved
+    // or used straight in an <img>. ?scale=1-4 (default 4) enlarges it.
+    let bridge_screen = bridge.clone();
+    route(&mut server, "/api/flipper/screenshot.png", Method::Get, move |mut req| -> Result<()> {
+        let started = Instant::now();
+        let png = screenshot_scale(req.uri()).and_then(|scale| {
+            bridge_screen.lock().check(lock_key(&req))?;
+            let frame = call(&bridge_screen, cmd::GUI_SCREENSHOT, &[], protocol::response_timeout(cmd::GUI_SCREENSHOT))?;
+            if frame.payload.len() != screenshot::FRAMEBUFFER_LEN {
+                return Err(ApiError::Internal(format!("GUI_SCREENSHOT sent {} bytes", frame.payload.len())));
+            }
+            Ok(screenshot::to_png(&frame.payload, scale))
+        });
+
+        let png = match png {
+            Ok(png) => png,
+            Err(e) => {
+                log_request(&mut req, Method::Get, e.status(), started);
+                return e.respond(req);
+            }
+        };
+        log_request(&mut req, Method::Get, 200, started);
+        let mut resp = req.into_response(200, None, &[
+            ("Content-Type", "image/png"),
+            ("Cache-Control", "no-store"),
+            ("Content-Disposition", "inline; filename=\"flipper.png\""),
+        ])?;
+        resp.write_all(&png)?;
+        Ok(())
+    })?;
+
+    // File upload to Flipper storage from a multipart/form-data form, so a
+    // plain <input type=file> works. Optional `path` (target directory) and
+    // `filename` fields must come before the file part, which is written as it
+    // arrives.
+    let bridge_upload = bridge.clone();
+    let max_upload = config.max_upload;
+    api_handler(&mut server, "/api/flipper/file", Method::Post, move |req| {
+        bridge_upload.lock().check(lock_key(req))?;
+        check_body_len(req, max_upload as u64)?;
+        let mut transfer = transfer::begin("upload");
+        let content_type = req.header("Content-Type").unwrap_or_default().to_string();
+        let mut form = Multipart::new(&mut *req, &content_type)?;
+        let mut dir = UPLOAD_DIR.to_string();
+        let mut filename = None;
+
+        while let Some(part) = form.next_part()? {
+            match (part.name.as_str(), part.filename) {
+                ("path", None) => dir = form.read_text(MAX_FORM_FIELD)?,
+                ("filename", None) => filename = Some(form.read_text(MAX_FORM_FIELD)?),
+                (_, Some(sent)) => {
+                    let name = filename.take().unwrap_or(sent);
+                    let path = upload_path(&dir, &name)?;
+                    transfer.set_path(&path);
+                    let size = storage_write(&bridge_upload, &path, |buf| form.read(buf))?;
+                    transfer.done(size as u64);
+                    info!("Uploaded {} ({} bytes)", path, size);
+                    return Ok(json!({ "path": path, "size": size }));
+                }
+                // Anything else in the form is ignored
+                _ => {}
+            }
+        }
+        Err(ApiError::BadRequest("no file in the form".into()))
+    })?;
+
+    // Many files in one request, e.g. restoring a backup: a tar archive
+    // (application/x-tar, target directory in ?path=) or a multipart form
+    // with several file parts whose filenames may carry relative paths.
+    // Either is written as it arrives, directories are made as needed, and
+    // a file that fails doesn't stop the rest.
+    let bridge_bundle = bridge.clone();
+    let max_bundle = config.max_bundle;
+    api_handler(&mut server, "/api/flipper/upload-bundle", Method::Post, move |req| {
+        check_body_len(req, max_bundle as u64)?;
+        bridge_bundle.lock().check(lock_key(req))?;
+        let mut transfer = transfer::begin("bundle");
+        let content_type = req.header("Content-Type").unwrap_or_default().to_string();
+        let kind = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        let path = query_param(req.uri(), "path").map(str::to_string);
+        let mut bundle = Bundle::new(&bridge_bundle, max_upload);
+        // Chunked bodies have no Content-Length to check, so count them too
+        let mut body = Limited::new(&mut *req, max_bundle as u64);
+
+        let result = (|| -> Result<(), ApiError> {
+            match kind.as_str() {
+                "application/x-tar" | "application/tar" => {
+                    let dir = match path {
+                        Some(path) => {
+                            percent_decode(&path).ok_or_else(|| ApiError::BadRequest("path is not valid UTF-8".into()))?
+                        }
+                        None => UPLOAD_DIR.to_string(),
+                    };
+                    bundle.check_dir(&dir)?;
+                    transfer.set_path(&dir);
+                    let mut archive = Tar::new(&mut body);
+                    while let Some(entry) = archive.next_entry()? {
+                        match entry.kind {
+                            tar::Kind::File if entry.size > max_upload as u64 => {
+                                bundle.fail(&entry.path, ApiError::TooLarge(max_upload as usize))
+                            }
+                            tar::Kind::File => bundle.write(&dir, &entry.path, |buf| archive.read(buf)),
+                            tar::Kind::Dir => bundle.mkdir(&dir, &entry.path),
+                            tar::Kind::Other(kind) => {
+                                info!("Bundle: skipping {} (tar type {:?})", entry.path, kind as char)
+                            }
+                        }
+                    }
+                }
+                "multipart/form-data" => {
+                    let mut form = Multipart::new(&mut body, &content_type)?;
+                    let mut dir = UPLOAD_DIR.to_string();
+                    while let Some(part) = form.next_part()? {
+                        match (part.name.as_str(), part.filename) {
+                            ("path", None) => {
+                                dir = form.read_text(MAX_FORM_FIELD)?;
+                                bundle.check_dir(&dir)?;
+                                transfer.set_path(&dir);
+                            }
+                            (_, Some(name)) => bundle.write(&dir, &name, |buf| form.read(buf)),
+                            _ => {}
+                        }
+                    }
+                }
+                _ => return Err(ApiError::BadRequest("expected application/x-tar or multipart/form-data".into())),
+            }
+            Ok(())
+        })();
+        // Cut off at the limit, the parser sees a truncated archive; say why
+        if body.over {
+            return Err(ApiError::TooLarge(max_bundle as usize));
+        }
+        result?;
+        transfer.done(bundle.bytes);
+        Ok(bundle.report())
+    })?;
+
+    // Stations on the AP. The IDF DHCP server doesn't keep the hostname
+    // clients send (option 12), so the MAC is the only name available.
+    api_handler(&mut server, "/api/wifi/clients", Method::Get, |_req| {
+        let clients = wifi::ap_clients().map_err(|e| ApiError::Internal(format!("station list: {}", e)))?;
+        let clients: Vec<Value> = clients
+            .iter()
+            .map(|client| {
+                let mac = wifi::format_mac(&client.mac);
+                json!({
+                    "name": mac,
+                    "mac": mac,
+                    "ip": client.ip.map(|ip| ip.to_string()),
+                    "rssi": client.rssi,
+                })
+            })
+            .collect();
+        Ok(json!({ "clients": clients }))
+    })?;
+
+    // Deauthenticate a station; it may reconnect unless it's in mac_block
+    api_handler(&mut server, "/api/wifi/kick", Method::Post, move |req| {
+        let body: KickRequest = read_json(req)?;
+        let mac = wifi::parse_mac(&body.mac)
+            .ok_or_else(|| ApiError::BadRequest("mac must look like aa:bb:cc:dd:ee:ff".into()))?;
+
+        match wifi::kick(&mac) {
+            Ok(true) => Ok(json!({ "kicked": wifi::format_mac(&mac) })),
+            Ok(false) => Err(ApiError::NotFound(format!("no station {}", wifi::format_mac(&mac)))),
+            Err(e) => Err(ApiError::Internal(format!("deauth: {}", e))),
+        }
+    })?;
+
+    // Saved IR remotes, or the signals in one of them with ?file=
+    let bridge_ir = bridge.clone();
+    api_handler(&mut server, "/api/flipper/ir/list", Method::Get, move |req| {
+        bridge_ir.lock().check(lock_key(req))?;
+        if let Some(file) = query_param(req.uri(), "file") {
+            // Decoded before the '/' check, so an escaped %2F is refused too
+            let file = percent_decode(file).ok_or_else(|| ApiError::BadRequest("file is not valid UTF-8".into()))?;
+            let contents = read_ir_file(&bridge_ir, &file)?;
+            return Ok(json!({ "file": file, "signals": ir::signal_names(&contents) }));
+        }
+
+        let files: Vec<String> = storage_list(&bridge_ir, ir::IR_DIR)?
+            .into_iter()
+            .filter(|name| name.ends_with(ir::IR_EXT))
+            .collect();
+        Ok(json!({ "files": files }))
+    })?;
+
+    // Transmit a named signal from a saved remote, or raw timings
+    let bridge_ir = bridge.clone();
+    api_handler(&mut server, "/api/flipper/ir/send", Method::Post, move |req| {
+        bridge_ir.lock().check(lock_key(req))?;
+        let body: IrSendRequest = read_json(req)?;
+
+        let timings = match body {
+            IrSendRequest { raw: Some(raw), .. } => raw,
+            IrSendRequest { file: Some(file), signal: Some(signal), .. } => {
+                match ir::find_signal(&read_ir_file(&bridge_ir, &file)?, &signal) {
+                    Lookup::Raw(timings) => timings,
+                    Lookup::Unsupported(kind) => {
+                        return Err(ApiError::BadRequest(format!(
+                            "signal {:?} is type {:?}, only raw signals can be sent",
+                            signal, kind
+                        )))
+                    }
+                    Lookup::Missing => {
+                        return Err(ApiError::NotFound(format!("no signal {:?} in {}", signal, file)))
+                    }
+                }
+            }
+            _ => return Err(ApiError::BadRequest("expected raw, or file and signal".into())),
+        };
+
+        if timings.is_empty() || timings.len() > ir::MAX_TIMINGS {
+            return Err(ApiError::BadRequest(format!(
+                "signal has {} timings, expected 1 to {}",
+                timings.len(),
+                ir::MAX_TIMINGS
+            )));
+        }
+
+        let payload = ir::transmit_payload(&timings);
+        call(&bridge_ir, cmd::IR_TRANSMIT, &payload, protocol::response_timeout(cmd::IR_TRANSMIT))?;
+        Ok(json!({ "transmitted": true, "timings": timings.len() }))
+    })?;
+
+    ws::register(&mut server, bridge.clone(), &config)?;
+
+    // httpd's own 404 and 405 are plain text, and the 405 has no Allow
+    let handle = server.handle();
+    sys::esp!(unsafe {
+        sys::httpd_register_err_handler(handle, sys::httpd_err_code_t_HTTPD_404_NOT_FOUND, Some(no_route))
+    })?;
+    sys::esp!(unsafe {
+        sys::httpd_register_err_handler(handle, sys::httpd_err_code_t_HTTPD_405_METHOD_NOT_ALLOWED, Some(no_route))
+    })?;
+
+    Ok(server)
+}
+
+// Single range from a Range: bytes=... header
+#[derive(Clone, Copy)]
+enum ByteRange {
+    // bytes=500-
+    From(u32),
+    // bytes=500-999, inclusive
+    Span(u32, u32),
+    // bytes=-500, the last 500 bytes
+    Suffix(u32),
+}
+
+impl ByteRange {
+    // None for anything else, including multiple ranges; the whole file is
+    // sent instead, which RFC 9110 allows
+    fn parse(header: &str) -> Option<Self> {
+        let spec = header.trim().strip_prefix("bytes=")?;
+        let (start, end) = spec.split_once('-')?;
+        let (start, end) = (start.trim(), end.trim());
+
+        match (start.is_empty(), end.is_empty()) {
+            (false, true) => Some(ByteRange::From(start.parse().ok()?)),
+            (false, false) => Some(ByteRange::Span(start.parse().ok()?, end.parse().ok()?)),
+            (true, false) => Some(ByteRange::Suffix(end.parse().ok()?)),
+            (true, true) => None,
+        }
+    }
+
+    // Half-open byte bounds within a file of `size`, or None if unsatisfiable
+    fn resolve(self, size: u32) -> Option<(u32, u32)> {
+        let (start, end) = match self {
+            ByteRange::From(start) => (start, size),
+            ByteRange::Span(start, last) if start <= last => (start, last.saturating_add(1).min(size)),
+            ByteRange::Span(..) => return None,
+            ByteRange::Suffix(len) if len > 0 => (size - len.min(size), size),
+            ByteRange::Suffix(_) => return None,
+        };
+        (start < end).then_some((start, end))
+    }
+}
+
+#[derive(Deserialize)]
+struct CommandRequest {
+    cmd: u8,
+    #[serde(default)]
+    payload: String,
+}
+
+#[derive(Deserialize)]
+struct BatchRequest {
+    commands: Vec<BatchCommand>,
+    #[serde(default = "default_true")]
+    stop_on_error: bool,
+    // Pause after each step, in place of batch_settle_ms
+    settle_ms: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct BatchCommand {
+    cmd: u8,
+    #[serde(default)]
+    payload: String,
+    // Pause after this step, in place of the batch's
+    settle_ms: Option<u32>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct LogLevelRequest {
+    level: String,
+}
+
+#[derive(Deserialize)]
+struct NvsSetRequest {
+    key: String,
+    // Needed only for a key that isn't stored yet
+    #[serde(rename = "type", default)]
+    kind: Option<Kind>,
+    value: Option<Value>,
+    // Instead of a value, deletes the key
+    #[serde(default)]
+    remove: bool,
+}
+
+#[derive(Deserialize)]
+struct FrameTraceRequest {
+    enabled: bool,
+}
+
+#[derive(Deserialize)]
+struct ListenOnlyRequest {
+    enabled: bool,
+}
+
+#[derive(Deserialize)]
+struct NotifyRequest {
+    color: Option<String>,
+    // 0 lets the Flipper pick its default
+    #[serde(default)]
+    duration_ms: u16,
+    #[serde(default)]
+    vibrate: bool,
+    #[serde(default)]
+    sound: bool,
+}
+
+#[derive(Deserialize)]
+struct KickRequest {
+    mac: String,
+}
+
+#[derive(Deserialize)]
+struct LockRequest {
+    // Shown to clients that are refused, so they know who to wait for
+    owner: String,
+    ttl_ms: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct IrSendRequest {
+    file: Option<String>,
+    signal: Option<String>,
+    raw: Option<Vec<u32>>,
+}
+
+// With TLS on, the HTTPS server only listens on 443, so a second bare httpd
+// on port 80 sends every GET to the same URL over https://. It is started
+// through the C API because esp-idf-svc gives every server the same control
+// port, and runs for the life of the firmware.
+fn start_redirect(config: &Config) -> Result<()> {
+    let server_config = sys::httpd_config_t {
+        task_priority: 5,
+        stack_size: REDIRECT_STACK,
+        core_id: i32::MAX,
+        server_port: 80,
+        ctrl_port: REDIRECT_CTRL_PORT,
+        max_open_sockets: 1,
+        max_uri_handlers: 1,
+        max_resp_headers: 8,
+        backlog_conn: 5,
+        lru_purge_enable: true,
+        recv_wait_timeout: 5,
+        send_wait_timeout: 5,
+        uri_match_fn: Some(sys::httpd_uri_match_wildcard),
+        ..Default::default()
+    };
+
+    // Used when a client sends no Host header
+    let fallback: &'static CStr = Box::leak(CString::new(config.ap_ip.to_string())?.into_boxed_c_str());
+    let handler = sys::httpd_uri_t {
+        uri: c"/*".as_ptr(),
+        method: sys::http_method_HTTP_GET,
+        handler: Some(redirect),
+        user_ctx: fallback.as_ptr() as *mut _,
+        ..Default::default()
+    };
+
+    let mut handle: sys::httpd_handle_t = ptr::null_mut();
+    sys::esp!(unsafe { sys::httpd_start(&mut handle, &server_config) })?;
+    sys::esp!(unsafe { sys::httpd_register_uri_handler(handle, &handler) })?;
+    info!("Redirecting http:// to https://");
+    Ok(())
+}
+
+unsafe extern "C" fn redirect(req: *mut sys::httpd_req_t) -> sys::esp_err_t {
+    let mut host = [0u8; 64];
+    let host = if sys::httpd_req_get_hdr_value_str(req, c"Host".as_ptr(), host.as_mut_ptr() as *mut _, host.len())
+        == sys::ESP_OK
+    {
+        CStr::from_bytes_until_nul(&host).unwrap_or_default().to_string_lossy().into_owned()
+    } else {
+        CStr::from_ptr((*req).user_ctx as *const _).to_string_lossy().into_owned()
+    };
+    // Drop any :80, the HTTPS server is on the default port
+    let host = host.split(':').next().unwrap_or_default();
+    let uri = CStr::from_ptr((*req).uri.as_ptr()).to_string_lossy();
+
+    let Ok(location) = CString::new(format!("https://{}{}", host, uri)) else {
+        return sys::ESP_FAIL;
+    };
+    sys::httpd_resp_set_status(req, c"301 Moved Permanently".as_ptr());
+    sys::httpd_resp_set_hdr(req, c"Location".as_ptr(), location.as_ptr());
+    sys::httpd_resp_send(req, ptr::null(), 0)
+}
+
+// Requests no handler took: an unknown path, or a known one with another
+// method. Answered like any /api/* error.
+unsafe extern "C" fn no_route(req: *mut sys::httpd_req_t, err: sys::httpd_err_code_t) -> sys::esp_err_t {
+    let uri = CStr::from_ptr((*req).uri.as_ptr()).to_string_lossy();
+    let path = uri.split('?').next().unwrap_or_default();
+    let allow = allowed_methods(path);
+
+    let (error, status) = if err == sys::httpd_err_code_t_HTTPD_405_METHOD_NOT_ALLOWED && !allow.is_empty() {
+        (ApiError::MethodNotAllowed(allow), c"405 Method Not Allowed")
+    } else {
+        (ApiError::NotFound(path.into()), c"404 Not Found")
+    };
+    if REQUEST_LOG.load(Ordering::Relaxed) {
+        info!("{} -> {}", uri, error.status());
+    }
+
+    let allow = match &error {
+        ApiError::MethodNotAllowed(allow) => CString::new(allow.as_str()).ok(),
+        _ => None,
+    };
+    let body = error.body();
+    sys::httpd_resp_set_status(req, status.as_ptr());
+    sys::httpd_resp_set_type(req, c"application/json".as_ptr());
+    if let Some(allow) = &allow {
+        sys::httpd_resp_set_hdr(req, c"Allow".as_ptr(), allow.as_ptr());
+    }
+    // ESP_OK keeps the connection open for the client's next request
+    sys::httpd_resp_send(req, body.as_ptr() as *const _, body.len() as isize);
+    sys::ESP_OK
+}
+
+// "GET, POST" for a registered path; empty if there is none
+fn allowed_methods(path: &str) -> String {
+    let routes = ROUTES.lock().unwrap();
+    let methods: Vec<&str> =
+        routes.iter().filter(|(uri, _)| uri == path).map(|(_, method)| method_name(*method)).collect();
+    methods.join(", ")
+}
+
+// Handlers not registered through route() or api_handler(), i.e. ws.rs's
+pub fn add_route(uri: &str, method: Method) {
+    ROUTES.lock().unwrap().push((uri.to_string(), method));
+}
+
+// fn_handler that is also listed in ROUTES
+fn route<F>(server: &mut EspHttpServer<'static>, uri: &str, method: Method, f: F) -> Result<()>
+where
+    F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> Result<()> + Send + 'static,
+{
+    let gated = needs_token(uri);
+    server.fn_handler(uri, method, move |mut req| {
+        if gated {
+            if let Err(e) = check_token(&req, TOKEN.get().and_then(Option::as_deref)) {
+                record_failure(&req, method, &e);
+                log_request(&mut req, method, e.status(), Instant::now());
+                return e.respond(req);
+            }
+        }
+        f(req)
+    })?;
+    add_route(uri, method);
+    Ok(())
+}
+
+// With a ws_token set, every /api/* route needs it except these: read-only,
+// off the UART but for the readiness PING, and wanted by the page header
+// and by probes before anyone has typed a token
+const OPEN_ROUTES: &[&str] = &["/api/status", "/api/version", "/api/branding", "/api/health/live", "/api/health/ready"];
+
+fn needs_token(uri: &str) -> bool {
+    uri.starts_with("/api/") && !OPEN_ROUTES.contains(&uri)
+}
+
+// Body of /api/status, also sent for a "status" control message on /ws
+pub fn status(bridge: &Bridge, ws_max_clients: usize) -> Value {
+    json!({
+        "mode": bridge.mode().name(),
+        "listen_only": bridge.listen_only(),
+        "wifi": wifi::state().name(),
+        "channel": wifi::ap_channel().ok(),
+        "uart_errors": bridge.line_errors(),
+        "uart_queue": bridge.queue_stats(),
+        "rpc_queued": ws::rpc_queued(),
+        "flipper_present": presence::present(),
+        "starting": presence::starting(),
+        "ws_clients": ws::client_count(),
+        "ws_max_clients": ws_max_clients,
+        "version": version::current(),
+    })
+}
+
+// UART up, nothing keeping this client from the Flipper, PING sent and
+// answered with its own bytes
+fn selftest(bridge: &Bridge, lock: Option<&str>, steps: &mut Vec<Value>) -> bool {
+    let mut payload = [0u8; PING_BYTES];
+    unsafe { sys::esp_fill_random(payload.as_mut_ptr() as *mut _, payload.len()) };
+    let mut response = None;
+
+    selftest_step(steps, "uart", || {
+        if !bridge.uart_ok() {
+            return Err(ApiError::Internal("UART driver is down".into()));
+        }
+        Ok(json!({ "baud": UART_BAUD }))
+    }) && selftest_step(steps, "access", || {
+        bridge.lock().check(lock)?;
+        if bridge.listen_only() {
+            return Err(ApiError::ListenOnly);
+        }
+        Ok(Value::Null)
+    }) && selftest_step(steps, "ping", || {
+        response = Some(call(bridge, cmd::PING, &payload, protocol::response_timeout(cmd::PING))?);
+        Ok(Value::Null)
+    }) && selftest_step(steps, "response", || {
+        let frame = response.take().ok_or_else(|| ApiError::Internal("no PING response".into()))?;
+        if frame.payload != payload {
+            return Err(ApiError::Internal(format!("PING echoed {} different bytes", frame.payload.len())));
+        }
+        Ok(json!({ "bytes": frame.payload.len() }))
+    })
+}
+
+// Runs one step and records its outcome and time
+fn selftest_step(steps: &mut Vec<Value>, name: &str, f: impl FnOnce() -> Result<Value, ApiError>) -> bool {
+    let started = Instant::now();
+    let result = f();
+    let mut step = json!({
+        "step": name,
+        "pass": result.is_ok(),
+        "ms": started.elapsed().as_micros() as f64 / 1000.0,
+    });
+    match &result {
+        Ok(Value::Null) => {}
+        Ok(detail) => step["detail"] = detail.clone(),
+        Err(e) => {
+            step["error"] = json!(e.to_string());
+            step["code"] = json!(e.code());
+        }
+    }
+    steps.push(step);
+    result.is_ok()
+}
+
+// 200 or 503 with the same body, so a probe that only looks at the status
+// code still works
+fn respond_check(mut req: Request<&mut EspHttpConnection>, ok: bool, body: &Value, started: Instant) -> Result<()> {
+    let code = if ok { 200 } else { 503 };
+    log_request(&mut req, Method::Get, code, started);
+    let mut resp = req.into_response(code, None, &[
+        ("Content-Type", "application/json"),
+        ("Cache-Control", "no-store"),
+    ])?;
+    resp.write_all(body.to_string().as_bytes())?;
+    Ok(())
+}
+
+// Run one command, turning a non-OK Flipper status into an error
+fn call(bridge: &Bridge, cmd: u8, payload: &[u8], timeout: Duration) -> Result<protocol::Frame, ApiError> {
+    let frame = bridge.command(cmd, payload, timeout)?;
+    if frame.status != status::OK {
+        return Err(ApiError::Flipper(frame.status));
+    }
+    Ok(frame)
+}
+
+// STORAGE_LIST: COUNT(1) then COUNT NUL-terminated names
+fn storage_list(bridge: &Bridge, path: &str) -> Result<Vec<String>, ApiError> {
+    let frame = call(bridge, cmd::STORAGE_LIST, &c_path(path), protocol::response_timeout(cmd::STORAGE_LIST))?;
+    let count = *frame.payload.first().unwrap_or(&0) as usize;
+
+    Ok(frame
+        .payload
+        .get(1..)
+        .unwrap_or_default()
+        .split(|&b| b == 0)
+        .take(count)
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect())
+}
+
+// Which mount a path is on, and what sets it apart
+fn storage_mount(path: &str) -> Option<(&'static str, &'static str)> {
+    let mount = path.split('/').nth(1)?;
+    match mount {
+        "ext" => Some(("ext", "SD card; ERR_NOT_READY (code 8) while none is inserted")),
+        "int" => Some(("int", "internal flash; small, shared with the Flipper's settings, ERR_FULL (code 7) when full")),
+        _ => None,
+    }
+}
+
+// First frame of a file
+fn storage_read(bridge: &Bridge, path: &str) -> Result<Vec<u8>, ApiError> {
+    Ok(storage_read_at(bridge, path, 0)?.1)
+}
+
+// STORAGE_READ: whole-file SIZE(4), then up to one frame of data from `offset`
+fn storage_read_at(bridge: &Bridge, path: &str, offset: u32) -> Result<(u32, Vec<u8>), ApiError> {
+    let mut payload = c_path(path);
+    payload.extend_from_slice(&offset.to_le_bytes());
+
+    let frame = call(bridge, cmd::STORAGE_READ, &payload, protocol::response_timeout(cmd::STORAGE_READ))?;
+    let Some(size) = frame.payload.get(..4) else {
+        return Err(ApiError::Internal("short STORAGE_READ response".into()));
+    };
+    let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]);
+    Ok((size, frame.payload[4..].to_vec()))
+}
+
+// Send `start..end` of a file, fetching it from the Flipper a frame at a time.
+// `first` is the chunk at `start` if it was already read.
+fn stream_file(
+    resp: &mut impl Write<Error = EspIOError>,
+    bridge: &Bridge,
+    path: &str,
+    (start, end): (u32, u32),
+    mut first: Option<Vec<u8>>,
+) -> Result<()> {
+    let mut offset = start;
+    while offset < end {
+        let data = match first.take() {
+            Some(data) => data,
+            None => storage_read_at(bridge, path, offset)?.1,
+        };
+        // The file shrank since the size was read
+        if data.is_empty() {
+            break;
+        }
+
+        let n = data.len().min((end - offset) as usize);
+        resp.write_all(&data[..n])?;
+        offset += n as u32;
+    }
+    Ok(())
+}
+
+// STORAGE_WRITE the rest of the current form part, a frame at a time.
+// Returns the file size the Flipper reports after the last write.
+// `read` fills a buffer from the upload and returns 0 at its end
+fn storage_write<F>(bridge: &Bridge, path: &str, mut read: F) -> Result<u32, ApiError>
+where
+    F: FnMut(&mut [u8]) -> Result<usize, ApiError>,
+{
+    let header = c_path(path).len() + 4;
+    let mut chunk = vec![0u8; protocol::MAX_PAYLOAD - header];
+    let mut offset = 0u32;
+
+    loop {
+        // Whole frames, so the Flipper sees as few writes as possible
+        let mut len = 0;
+        while len < chunk.len() {
+            let n = read(&mut chunk[len..])?;
+            if n == 0 {
+                break;
+            }
+            len += n;
+        }
+        // Offset 0 always goes out, which creates an empty file too
+        if len == 0 && offset > 0 {
+            return Ok(offset);
+        }
+
+        let mut payload = c_path(path);
+        payload.extend_from_slice(&offset.to_le_bytes());
+        payload.extend_from_slice(&chunk[..len]);
+        let frame = call(bridge, cmd::STORAGE_WRITE, &payload, protocol::response_timeout(cmd::STORAGE_WRITE))?;
+
+        offset += len as u32;
+        let size = frame.payload.get(..4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        if size != Some(offset) {
+            return Err(ApiError::Internal(format!("{} is {:?} bytes after writing {}", path, size, offset)));
+        }
+        if len < chunk.len() {
+            return Ok(offset);
+        }
+    }
+}
+
+// Per-file results of an upload-bundle request, and the directories it
+// has already made so each costs one STORAGE_MKDIR at most
+struct Bundle<'a> {
+    bridge: &'a Bridge,
+    max_file: u32,
+    made: HashSet<String>,
+    files: Vec<Value>,
+    failed: usize,
+    // Written, over every file that made it
+    bytes: u64,
+}
+
+impl<'a> Bundle<'a> {
+    fn new(bridge: &'a Bridge, max_file: u32) -> Self {
+        Self { bridge, max_file, made: HashSet::new(), files: Vec::new(), failed: 0, bytes: 0 }
+    }
+
+    fn check_dir(&self, dir: &str) -> Result<(), ApiError> {
+        if !dir.starts_with('/') {
+            return Err(ApiError::BadRequest("path must be an absolute Flipper directory".into()));
+        }
+        Ok(())
+    }
+
+    fn write<F>(&mut self, dir: &str, name: &str, mut read: F)
+    where
+        F: FnMut(&mut [u8]) -> Result<usize, ApiError>,
+    {
+        let path = match bundle_path(dir, name) {
+            Ok(path) => path,
+            Err(e) => return self.fail(name, e),
+        };
+
+        // A multipart body declares no per-file size, so it's counted here
+        let max = self.max_file as u64;
+        let mut total = 0u64;
+        let limited = |buf: &mut [u8]| {
+            let n = read(buf)?;
+            total += n as u64;
+            if total > max {
+                return Err(ApiError::TooLarge(max as usize));
+            }
+            Ok(n)
+        };
+
+        match self.make_parents(dir, &path).and_then(|()| storage_write(self.bridge, &path, limited)) {
+            Ok(size) => {
+                info!("Bundle: wrote {} ({} bytes)", path, size);
+                self.bytes += size as u64;
+                self.files.push(json!({ "path": path, "size": size }));
+            }
+            Err(e) => self.fail(&path, e),
+        }
+    }
+
+    // A directory entry from a tar, so empty directories come across too
+    fn mkdir(&mut self, dir: &str, name: &str) {
+        let result = bundle_path(dir, name).and_then(|path| {
+            self.make_parents(dir, &path)?;
+            self.make_dir(&path)
+        });
+        if let Err(e) = result {
+            self.fail(name, e);
+        }
+    }
+
+    fn make_parents(&mut self, dir: &str, path: &str) -> Result<(), ApiError> {
+        let base = dir.trim_end_matches('/').len();
+        for (at, _) in path.match_indices('/').filter(|(at, _)| *at > base) {
+            self.make_dir(&path[..at])?;
+        }
+        Ok(())
+    }
+
+    fn make_dir(&mut self, path: &str) -> Result<(), ApiError> {
+        if self.made.contains(path) {
+            return Ok(());
+        }
+        call(self.bridge, cmd::STORAGE_MKDIR, &c_path(path), protocol::response_timeout(cmd::STORAGE_MKDIR))
+            .inspect_err(|e| warn!("Bundle: can't create {}: {}", path, e))?;
+        self.made.insert(path.to_string());
+        Ok(())
+    }
+
+    fn fail(&mut self, path: &str, e: ApiError) {
+        warn!("Bundle: {} failed: {}", path, e);
+        self.failed += 1;
+        self.files.push(json!({ "path": path, "error": e.to_string(), "code": e.code() }));
+    }
+
+    fn report(self) -> Value {
+        json!({
+            "written": self.files.len() - self.failed,
+            "failed": self.failed,
+            "files": self.files,
+        })
+    }
+}
+
+// A relative path from a bundle under `dir`. Leading slashes and "."
+// components are dropped; ".." is refused so nothing lands outside `dir`.
+fn bundle_path(dir: &str, name: &str) -> Result<String, ApiError> {
+    let mut path = dir.trim_end_matches('/').to_string();
+    let mut parts = 0;
+    for part in name.split(['/', '\\']).filter(|part| !part.is_empty() && *part != ".") {
+        if part == ".." || part.contains('\0') {
+            return Err(ApiError::BadRequest(format!("{:?} is not a path inside the target directory", name)));
+        }
+        path.push('/');
+        path.push_str(part);
+        parts += 1;
+    }
+    if parts == 0 {
+        return Err(ApiError::BadRequest("entry has no file name".into()));
+    }
+    if path.len() > MAX_UPLOAD_PATH {
+        return Err(ApiError::BadRequest(format!("target path longer than {} bytes", MAX_UPLOAD_PATH)));
+    }
+    Ok(path)
+}
+
+// Browsers may send a full client path as the filename; only its last
+// component is used
+fn upload_path(dir: &str, filename: &str) -> Result<String, ApiError> {
+    let dir = dir.trim_end_matches('/');
+    if !dir.starts_with('/') {
+        return Err(ApiError::BadRequest("path must be an absolute Flipper directory".into()));
+    }
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    if name.is_empty() || name == "." || name == ".." || name.contains('\0') {
+        return Err(ApiError::BadRequest("filename is not a usable file name".into()));
+    }
+
+    let path = format!("{}/{}", dir, name);
+    // Room for NUL, OFFSET and at least some data in each frame
+    if path.len() > MAX_UPLOAD_PATH {
+        return Err(ApiError::BadRequest(format!("target path longer than {} bytes", MAX_UPLOAD_PATH)));
+    }
+    Ok(path)
+}
+
+// Remote names are taken relative to the IR directory, with or without .ir
+fn read_ir_file(bridge: &Bridge, file: &str) -> Result<String, ApiError> {
+    if file.is_empty() || file.contains('/') {
+        return Err(ApiError::BadRequest("file must be a name inside /ext/infrared".into()));
+    }
+    let suffix = if file.ends_with(ir::IR_EXT) { "" } else { ir::IR_EXT };
+    let contents = storage_read(bridge, &format!("{}/{}{}", ir::IR_DIR, file, suffix))?;
+    Ok(String::from_utf8_lossy(&contents).into_owned())
+}
+
+fn c_path(path: &str) -> Vec<u8> {
+    let mut bytes = path.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+// Register an /api/* handler. The closure returns a JSON value on success;
+// any ApiError is turned into a JSON error body with the matching status.
+fn api_handler<F>(server: &mut EspHttpServer<'static>, uri: &str, method: Method, f: F) -> Result<()>
+where
+    F: Fn(&mut Request<&mut EspHttpConnection>) -> Result<Value, ApiError> + Send + 'static,
+{
+    route(server, uri, method, move |mut req| -> Result<()> {
+        let started = Instant::now();
+        match f(&mut req) {
+            Ok(body) => {
+                log_request(&mut req, method, 200, started);
+                let body = body.to_string().into_bytes();
+                let min = GZIP_MIN.load(Ordering::Relaxed);
+                let wants_gzip = req.header("Accept-Encoding").is_some_and(gzip::accepted);
+
+                // Sent plain if compressing didn't help, e.g. a base64 capture
+                let compressed = (min > 0 && body.len() >= min && wants_gzip)
+                    .then(|| gzip::gzip(&body))
+                    .filter(|compressed| compressed.len() < body.len());
+                match compressed {
+                    Some(compressed) => {
+                        let mut resp = req.into_response(200, None, &[
+                            ("Content-Type", "application/json"),
+                            ("Content-Encoding", "gzip"),
+                            ("Vary", "Accept-Encoding"),
+                        ])?;
+                        resp.write_all(&compressed)?;
+                    }
+                    None => {
+                        let mut resp = req.into_response(200, None, &[
+                            ("Content-Type", "application/json"),
+                            ("Vary", "Accept-Encoding"),
+                        ])?;
+                        resp.write_all(&body)?;
+                    }
+                }
+                Ok(())
+            }
+            Err(e) => {
+                record_failure(&req, method, &e);
+                log_request(&mut req, method, e.status(), started);
+                e.respond(req)
+            }
+        }
+    })?;
+    Ok(())
+}
+
+fn record_failure(req: &Request<&mut EspHttpConnection>, method: Method, e: &ApiError) {
+    warn!("{} {} failed: {}", method_name(method), req.uri(), e);
+    events::record(Event::Error {
+        method: method_name(method).into(),
+        path: req.uri().split('?').next().unwrap_or_default().into(),
+        status: e.status(),
+        code: e.code(),
+        message: e.to_string(),
+    });
+}
+
+// Key of the UART lock the client holds, if any
+fn lock_key<'a>(req: &'a Request<&mut EspHttpConnection>) -> Option<&'a str> {
+    req.header("X-Ecco-Lock").map(str::trim).filter(|key| !key.is_empty())
+}
+
+// `Authorization: Bearer <token>` when `token` is set
+fn check_token(req: &Request<&mut EspHttpConnection>, token: Option<&str>) -> Result<(), ApiError> {
+    let Some(token) = token else {
+        return Ok(());
+    };
+    let given = req.header("Authorization").and_then(|value| value.strip_prefix("Bearer ")).unwrap_or("");
+    if ws::token_matches(given.trim(), token) {
+        Ok(())
+    } else {
+        Err(ApiError::Forbidden("bad or missing token".into()))
+    }
+}
+
+// Logged before the response is written, so the time is the handler's own
+fn log_request(req: &mut Request<&mut EspHttpConnection>, method: Method, status: u16, started: Instant) {
+    if !REQUEST_LOG.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let peer = peer_ip(req).map(|ip| ip.to_string()).unwrap_or_else(|| "?".into());
+    info!(
+        "{} {} {} -> {} in {}ms",
+        peer,
+        method_name(method),
+        req.uri(),
+        status,
+        started.elapsed().as_millis()
+    );
+}
+
+fn peer_ip(req: &mut Request<&mut EspHttpConnection>) -> Option<IpAddr> {
+    let fd = unsafe { sys::httpd_req_to_sockfd(req.connection().handle()) };
+    // Borrow the socket to ask for its peer; httpd still owns and closes it
+    let socket = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(fd) });
+
+    // httpd listens on IPv6, so IPv4 clients show up as mapped addresses
+    match socket.peer_addr().ok()?.ip() {
+        IpAddr::V6(ip) => Some(ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4)),
+        ip => Some(ip),
+    }
+}
+
+fn read_json<T: DeserializeOwned>(req: &mut Request<&mut EspHttpConnection>) -> Result<T, ApiError> {
+    let max = MAX_BODY.load(Ordering::Relaxed);
+    check_body_len(req, max as u64)?;
+    let mut body = Vec::new();
+    let mut buf = [0u8; 256];
+
+    loop {
+        let n = req.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        if body.len() + n > max {
+            return Err(ApiError::TooLarge(max));
+        }
+        body.extend_from_slice(&buf[..n]);
+    }
+
+    Ok(serde_json::from_slice(&body)?)
+}
+
+// Refuse a body by its Content-Length, before reading any of it. httpd has
+// no chunked request bodies, so the length is always declared.
+fn check_body_len(req: &Request<&mut EspHttpConnection>, max: u64) -> Result<(), ApiError> {
+    match req.content_len() {
+        Some(len) if len > max => Err(ApiError::TooLarge(max as usize)),
+        _ => Ok(()),
+    }
+}
+
+// Ends a body early once it passes `max` bytes. `over` tells that apart
+// from the body really ending there.
+struct Limited<R> {
+    inner: R,
+    left: u64,
+    over: bool,
+}
+
+impl<R> Limited<R> {
+    fn new(inner: R, max: u64) -> Self {
+        Limited { inner, left: max, over: false }
+    }
+}
+
+impl<R: ErrorType> ErrorType for Limited<R> {
+    type Error = R::Error;
+}
+
+impl<R: Read> Read for Limited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if self.over || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.left == 0 {
+            // Anything past the limit, even one byte, is over it
+            let mut probe = [0u8; 1];
+            self.over = self.inner.read(&mut probe)? > 0;
+            return Ok(0);
+        }
+        let len = self.left.min(buf.len() as u64) as usize;
+        let n = self.inner.read(&mut buf[..len])?;
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+fn screenshot_scale(uri: &str) -> Result<usize, ApiError> {
+    match query_param(uri, "scale") {
+        None => Ok(screenshot::MAX_SCALE),
+        Some(value) => value
+            .parse()
+            .ok()
+            .filter(|scale| (1..=screenshot::MAX_SCALE).contains(scale))
+            .ok_or_else(|| ApiError::BadRequest(format!("scale must be 1 to {}", screenshot::MAX_SCALE))),
+    }
+}
+
+// ?timeout_ms=N overrides the per-command default, capped at cmd_timeout_max
+fn request_timeout(uri: &str, config: &Config) -> Result<Option<Duration>, ApiError> {
+    let Some(value) = query_param(uri, "timeout_ms") else {
+        return Ok(None);
+    };
+
+    let ms: u64 = value
+        .parse()
+        .ok()
+        .filter(|ms| *ms > 0)
+        .ok_or_else(|| ApiError::BadRequest("timeout_ms must be a positive integer".into()))?;
+
+    Ok(Some(Duration::from_millis(ms).min(config.cmd_timeout_max)))
+}
+
+// How response payloads from the command endpoints are written, picked
+// with ?encoding=. Hex is the default so existing clients keep working;
+// text suits commands that answer with names or paths.
+#[derive(Clone, Copy)]
+enum Encoding {
+    Hex,
+    Base64,
+    // UTF-8, with invalid sequences replaced by U+FFFD
+    Text,
+}
+
+impl Encoding {
+    fn from_query(uri: &str) -> Result<Self, ApiError> {
+        match query_param(uri, "encoding") {
+            None | Some("hex") => Ok(Encoding::Hex),
+            Some("base64") => Ok(Encoding::Base64),
+            Some("text") => Ok(Encoding::Text),
+            Some(_) => Err(ApiError::BadRequest("encoding must be hex, base64 or text".into())),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Hex => "hex",
+            Encoding::Base64 => "base64",
+            Encoding::Text => "text",
+        }
+    }
+
+    fn encode(self, data: &[u8]) -> String {
+        match self {
+            Encoding::Hex => to_hex(data),
+            Encoding::Base64 => to_base64(data),
+            Encoding::Text => String::from_utf8_lossy(data).into_owned(),
+        }
+    }
+}
+
+// Optional non-negative integer query parameter
+fn number_param<T: FromStr>(uri: &str, name: &str) -> Result<Option<T>, ApiError> {
+    query_param(uri, name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| ApiError::BadRequest(format!("{} must be a non-negative integer", name)))
+        })
+        .transpose()
+}
+
+// %XX escapes, and '+' as a space as in form-encoded queries
+fn percent_decode(value: &str) -> Option<String> {
+    let mut out = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let high = protocol::hex_digit(bytes.next()?)?;
+                out.push(high << 4 | protocol::hex_digit(bytes.next()?)?);
+            }
+            b'+' => out.push(b' '),
+            b => out.push(b),
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+pub fn query_param<'a>(uri: &'a str, name: &str) -> Option<&'a str> {
+    let (_, query) = uri.split_once('?')?;
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+pub fn level_name(level: LevelFilter) -> &'static str {
+    match level {
+        LevelFilter::Off => "off",
+        LevelFilter::Error => "error",
+        LevelFilter::Warn => "warn",
+        LevelFilter::Info => "info",
+        LevelFilter::Debug => "debug",
+        LevelFilter::Trace => "trace",
+    }
+}
+
+fn method_name(method: Method) -> &'static str {
+    match method {
+        Method::Get => "GET",
+        Method::Post => "POST",
+        Method::Put => "PUT",
+        Method::Delete => "DELETE",
+        _ => "?",
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Standard alphabet, padded
+fn to_base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let bits = group.iter().enumerate().fold(0u32, |bits, (i, &b)| bits | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= group.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// "#rrggbb" or "rrggbb"
+fn parse_color(color: &str) -> Option<[u8; 3]> {
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    protocol::from_hex(hex)?.try_into().ok()
+}
@@ -0,0 +1,82 @@
+// Saved IR remotes on the Flipper SD card, in the Flipper's own .ir format:
+//
+//   Filetype: IR signals file
+//   Version: 1
+//   #
+//   name: Power
+//   type: raw
+//   frequency: 38000
+//   duty_cycle: 0.330000
+//   data: 9024 4512 564 ...
+//
+// The Ecco app only transmits raw timings (at a fixed 38kHz), so parsed
+// signals (type: parsed, protocol/address/command) can be listed but not sent.
+
+use crate::protocol::MAX_PAYLOAD;
+
+pub const IR_DIR: &str = "/ext/infrared";
+pub const IR_EXT: &str = ".ir";
+
+// IR_TRANSMIT payload is PROTOCOL(1) + DATA_LEN(2) + u32 timings
+pub const MAX_TIMINGS: usize = (MAX_PAYLOAD - 3) / 4;
+
+pub enum Lookup {
+    Raw(Vec<u32>),
+    // Signal exists but isn't raw; carries its type
+    Unsupported(String),
+    Missing,
+}
+
+// Find the named signal in a .ir file. Long raw signals may be split over
+// several data: lines, which are joined.
+pub fn find_signal(file: &str, name: &str) -> Lookup {
+    for block in file.split("\n#") {
+        let mut found = false;
+        let mut kind = None;
+        let mut timings = Vec::new();
+
+        for line in block.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match key.trim() {
+                "name" => found = value == name,
+                "type" => kind = Some(value.to_string()),
+                "data" => timings.extend(value.split_whitespace().filter_map(|t| t.parse::<u32>().ok())),
+                _ => {}
+            }
+        }
+
+        if !found {
+            continue;
+        }
+        return match kind.as_deref() {
+            Some("raw") => Lookup::Raw(timings),
+            Some(other) => Lookup::Unsupported(other.to_string()),
+            None => Lookup::Unsupported("unknown".into()),
+        };
+    }
+    Lookup::Missing
+}
+
+// Names of all signals in a .ir file, in file order
+pub fn signal_names(file: &str) -> Vec<String> {
+    file.lines()
+        .filter_map(|line| line.strip_prefix("name:"))
+        .map(|name| name.trim().to_string())
+        .collect()
+}
+
+pub fn transmit_payload(timings: &[u32]) -> Vec<u8> {
+    let data_len = timings.len() * 4;
+    let mut payload = Vec::with_capacity(3 + data_len);
+
+    payload.push(0); // protocol (raw)
+    payload.extend_from_slice(&(data_len as u16).to_le_bytes());
+    for t in timings {
+        payload.extend_from_slice(&t.to_le_bytes());
+    }
+
+    payload
+}
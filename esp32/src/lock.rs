@@ -0,0 +1,103 @@
+// Exclusive UART sessions for multi-step work, like a firmware flash, that
+// another client's commands must not land in the middle of. The holder gets
+// a key and sends it with each request; everyone else is refused with 423
+// until the lock is released or its lease runs out. A request that was
+// already running when the lock was taken is left to finish.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::ApiError;
+use crate::ws;
+
+// Longest lease a holder can ask for; a holder that goes away without
+// releasing blocks everyone else for at most this long
+pub const MAX_TTL: Duration = Duration::from_secs(600);
+pub const DEFAULT_TTL: Duration = Duration::from_secs(30);
+pub const MAX_OWNER: usize = 32;
+// Random bytes in a key, sent as hex
+const KEY_BYTES: usize = 8;
+
+struct Lease {
+    key: String,
+    owner: String,
+    expires: Instant,
+}
+
+// Current holder, as /api/lock reports it
+pub struct Holder {
+    pub owner: String,
+    pub expires_in: Duration,
+}
+
+#[derive(Default)]
+pub struct Lock {
+    lease: Mutex<Option<Lease>>,
+}
+
+impl Lock {
+    // Take the lock, or extend it when `key` is the current one. Returns the
+    // key to send with later requests.
+    pub fn acquire(&self, owner: &str, ttl: Duration, key: Option<&str>) -> Result<String, ApiError> {
+        let mut lease = self.lease.lock().unwrap();
+        let expires = Instant::now() + ttl;
+
+        match live(&mut lease) {
+            Some(held) if holds(held, key) => {
+                held.expires = expires;
+                return Ok(held.key.clone());
+            }
+            Some(held) => return Err(ApiError::Locked(held.owner.clone())),
+            None => {}
+        }
+
+        let key = new_key();
+        *lease = Some(Lease { key: key.clone(), owner: owner.to_string(), expires });
+        Ok(key)
+    }
+
+    pub fn release(&self, key: Option<&str>) -> Result<(), ApiError> {
+        let mut lease = self.lease.lock().unwrap();
+        match live(&mut lease) {
+            Some(held) if holds(held, key) => {
+                lease.take();
+                Ok(())
+            }
+            Some(held) => Err(ApiError::Locked(held.owner.clone())),
+            None => Err(ApiError::NotFound("no lock is held".into())),
+        }
+    }
+
+    // Ok when the UART is free or `key` holds it
+    pub fn check(&self, key: Option<&str>) -> Result<(), ApiError> {
+        match live(&mut self.lease.lock().unwrap()) {
+            Some(held) if !holds(held, key) => Err(ApiError::Locked(held.owner.clone())),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn holder(&self) -> Option<Holder> {
+        live(&mut self.lease.lock().unwrap()).map(|held| Holder {
+            owner: held.owner.clone(),
+            expires_in: held.expires.saturating_duration_since(Instant::now()),
+        })
+    }
+}
+
+// The lease if it hasn't run out; an expired one is dropped here
+fn live(lease: &mut Option<Lease>) -> Option<&mut Lease> {
+    if matches!(lease, Some(held) if held.expires <= Instant::now()) {
+        lease.take();
+    }
+    lease.as_mut()
+}
+
+fn holds(lease: &Lease, key: Option<&str>) -> bool {
+    key.is_some_and(|key| ws::token_matches(key, &lease.key))
+}
+
+fn new_key() -> String {
+    let mut bytes = [0u8; KEY_BYTES];
+    unsafe { esp_idf_sys::esp_fill_random(bytes.as_mut_ptr() as *mut _, bytes.len()) };
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,110 @@
+// Log sink wrapping EspLogger. Every line still goes to the serial console,
+// and is also kept in a short history and streamed to ecco-log WebSocket clients.
+
+use esp_idf_svc::log::EspLogger;
+use log::{warn, LevelFilter, Log, Metadata, Record};
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+
+// Matches CONFIG_LOG_DEFAULT_LEVEL in sdkconfig.defaults. The build keeps
+// everything up to verbose (CONFIG_LOG_MAXIMUM_LEVEL) so set_level can go higher.
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+// Lines kept for /api/debug/log/download
+const HISTORY: usize = 128;
+// Of those, how many are replayed to an ecco-log client when it subscribes
+const REPLAY: usize = 32;
+// Lines queued per subscriber before new ones are dropped
+const BACKLOG: usize = 16;
+
+static LOGGER: Logger = Logger {
+    esp: EspLogger,
+    history: Mutex::new(VecDeque::new()),
+    subscribers: Mutex::new(Vec::new()),
+};
+
+struct Logger {
+    esp: EspLogger,
+    // Timestamp (ms since boot) and formatted line
+    history: Mutex<VecDeque<(u32, String)>>,
+    subscribers: Mutex<Vec<SyncSender<String>>>,
+}
+
+pub fn init() {
+    log::set_logger(&LOGGER)
+        .map(|()| log::set_max_level(DEFAULT_LEVEL))
+        .unwrap();
+}
+
+// Applies to Rust logs on every sink and to IDF's own C logs. Capped at what
+// the build compiled in.
+pub fn set_level(level: LevelFilter) {
+    let level = level.min(LOGGER.esp.get_max_level());
+    log::set_max_level(level);
+    if let Err(e) = LOGGER.esp.set_target_level("*", level) {
+        warn!("Setting IDF log level failed: {}", e);
+    }
+}
+
+pub fn level() -> LevelFilter {
+    log::max_level()
+}
+
+// Recent lines plus a stream of new ones. Dropping the receiver unsubscribes.
+pub fn subscribe() -> (Vec<String>, Receiver<String>) {
+    let (tx, rx) = mpsc::sync_channel(BACKLOG);
+    let history = {
+        let history = LOGGER.history.lock().unwrap();
+        history.iter().skip(history.len().saturating_sub(REPLAY)).map(|(_, line)| line.clone()).collect()
+    };
+    LOGGER.subscribers.lock().unwrap().push(tx);
+    (history, rx)
+}
+
+// Kept lines logged at or after `since` (ms since boot), newest `lines` at most
+pub fn history(since: u32, lines: usize) -> Vec<String> {
+    let history = LOGGER.history.lock().unwrap();
+    let matching: Vec<&String> = history.iter().filter(|(at, _)| *at >= since).map(|(_, line)| line).collect();
+    matching[matching.len().saturating_sub(lines)..].iter().map(|line| line.to_string()).collect()
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.esp.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.esp.log(record);
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // Same layout as the console, minus colors
+        let timestamp = unsafe { esp_idf_sys::esp_log_timestamp() };
+        let line = format!("{} ({}) {}: {}", marker(record.level()), timestamp, record.target(), record.args());
+
+        self.subscribers.lock().unwrap().retain(|tx| match tx.try_send(line.clone()) {
+            Ok(()) | Err(TrySendError::Full(_)) => true,
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+
+        let mut history = self.history.lock().unwrap();
+        if history.len() == HISTORY {
+            history.pop_front();
+        }
+        history.push_back((timestamp, line));
+    }
+
+    fn flush(&self) {}
+}
+
+fn marker(level: log::Level) -> &'static str {
+    match level {
+        log::Level::Error => "E",
+        log::Level::Warn => "W",
+        log::Level::Info => "I",
+        log::Level::Debug => "D",
+        log::Level::Trace => "V",
+    }
+}
@@ -0,0 +1,37 @@
+// mDNS responder. The board answers as <mdns_host>.local on both the AP and
+// the upstream network, and advertises the web UI as an _http._tcp service
+// whose TXT record carries the firmware version and whatever mdns_txt adds
+// (a role or site, say), so discovery tools can filter a fleet.
+
+use anyhow::Result;
+use esp_idf_svc::mdns::EspMdns;
+use log::*;
+
+use crate::config::Config;
+use crate::version;
+
+pub fn start(config: &Config) -> Result<EspMdns> {
+    let mut mdns = EspMdns::take()?;
+    mdns.set_hostname(&config.mdns_host)?;
+    mdns.set_instance_name(&config.mdns_name)?;
+
+    // With TLS on, port 80 only redirects; tls=1 tells clients to use https
+    let tls = if config.tls.is_some() { "1" } else { "0" };
+    let mut txt = vec![
+        ("version", version::VERSION),
+        ("git", version::GIT_HASH),
+        ("path", "/"),
+        ("tls", tls),
+    ];
+    for (key, value) in &config.mdns_txt {
+        if txt.iter().any(|(built_in, _)| built_in.eq_ignore_ascii_case(key)) {
+            warn!("mdns_txt can't replace the built-in {} item, ignoring it", key);
+            continue;
+        }
+        txt.push((key, value));
+    }
+
+    mdns.add_service(Some(&config.mdns_name), "_http", "_tcp", 80, &txt)?;
+    info!("mDNS: {}.local, {:?} on _http._tcp with {} TXT items", config.mdns_host, config.mdns_name, txt.len());
+    Ok(mdns)
+}
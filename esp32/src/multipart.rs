@@ -0,0 +1,192 @@
+// Streaming multipart/form-data reader for uploads from a plain HTML form.
+// Parts are handed out one at a time and their bodies read in pieces, so a
+// file never has to fit in memory. Only the delimiter length is held back
+// while looking for the boundary.
+
+use embedded_svc::io::Read;
+use esp_idf_svc::io::EspIOError;
+
+use crate::error::ApiError;
+
+// Unconsumed body bytes kept at once
+const BUF_SIZE: usize = 1536;
+// Part header lines longer than this are rejected
+const MAX_HEADER_LINE: usize = 512;
+// RFC 2046 limit
+const MAX_BOUNDARY: usize = 70;
+
+pub struct Part {
+    pub name: String,
+    // Set for file inputs
+    pub filename: Option<String>,
+}
+
+pub struct Multipart<R> {
+    reader: R,
+    // CRLF "--" boundary
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    // Inside a part body. The preamble before the first boundary counts as
+    // one, so it gets skipped the same way.
+    in_body: bool,
+    finished: bool,
+}
+
+impl<R: Read<Error = EspIOError>> Multipart<R> {
+    pub fn new(reader: R, content_type: &str) -> Result<Self, ApiError> {
+        let (kind, params) = content_type.split_once(';').unwrap_or((content_type, ""));
+        if !kind.trim().eq_ignore_ascii_case("multipart/form-data") {
+            return Err(ApiError::BadRequest("expected multipart/form-data".into()));
+        }
+        let boundary = param(params, "boundary")
+            .filter(|b| !b.is_empty() && b.len() <= MAX_BOUNDARY)
+            .ok_or_else(|| ApiError::BadRequest("multipart boundary missing".into()))?;
+
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+        Ok(Self {
+            reader,
+            delimiter,
+            // The first boundary may start the body without a CRLF before it
+            buf: b"\r\n".to_vec(),
+            in_body: true,
+            finished: false,
+        })
+    }
+
+    // Skip what's left of the current part and parse the next one's headers.
+    // None after the closing boundary.
+    pub fn next_part(&mut self) -> Result<Option<Part>, ApiError> {
+        let mut scratch = [0u8; 256];
+        while self.read(&mut scratch)? > 0 {}
+        if self.finished {
+            return Ok(None);
+        }
+
+        // The boundary is followed by "--" on the last one, else CRLF
+        if self.fill_to(2)? && self.buf.starts_with(b"--") {
+            self.finished = true;
+            return Ok(None);
+        }
+        self.line()?;
+
+        let mut part = None;
+        loop {
+            let line = self.line()?;
+            if line.is_empty() {
+                break;
+            }
+            let Some((header, value)) = line.split_once(':') else {
+                continue;
+            };
+            if header.trim().eq_ignore_ascii_case("content-disposition") {
+                let name = param(value, "name").unwrap_or_default();
+                part = Some(Part { name, filename: param(value, "filename") });
+            }
+        }
+
+        let part = part.ok_or_else(|| ApiError::BadRequest("part without Content-Disposition".into()))?;
+        self.in_body = true;
+        Ok(Some(part))
+    }
+
+    // Body of the current part; 0 once it ends
+    pub fn read(&mut self, out: &mut [u8]) -> Result<usize, ApiError> {
+        if !self.in_body || out.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            if let Some(at) = find(&self.buf, &self.delimiter) {
+                if at == 0 {
+                    self.buf.drain(..self.delimiter.len());
+                    self.in_body = false;
+                    return Ok(0);
+                }
+                return Ok(self.take(at, out));
+            }
+
+            // Bytes that can't be the start of a delimiter are safe to return
+            let safe = self.buf.len().saturating_sub(self.delimiter.len() - 1);
+            if safe > 0 {
+                return Ok(self.take(safe, out));
+            }
+            if !self.fill()? {
+                return Err(ApiError::BadRequest("body ended inside a multipart part".into()));
+            }
+        }
+    }
+
+    // The rest of the current part as text, for plain form fields
+    pub fn read_text(&mut self, max: usize) -> Result<String, ApiError> {
+        let mut value = Vec::new();
+        let mut chunk = [0u8; 128];
+        loop {
+            let n = self.read(&mut chunk)?;
+            if n == 0 {
+                break;
+            }
+            if value.len() + n > max {
+                return Err(ApiError::BadRequest(format!("form field longer than {} bytes", max)));
+            }
+            value.extend_from_slice(&chunk[..n]);
+        }
+        String::from_utf8(value).map_err(|_| ApiError::BadRequest("form field is not UTF-8".into()))
+    }
+
+    fn take(&mut self, available: usize, out: &mut [u8]) -> usize {
+        let n = available.min(out.len());
+        out[..n].copy_from_slice(&self.buf[..n]);
+        self.buf.drain(..n);
+        n
+    }
+
+    // One CRLF-terminated header line, without the CRLF
+    fn line(&mut self) -> Result<String, ApiError> {
+        loop {
+            if let Some(end) = find(&self.buf, b"\r\n") {
+                let line = String::from_utf8_lossy(&self.buf[..end]).into_owned();
+                self.buf.drain(..end + 2);
+                return Ok(line);
+            }
+            if self.buf.len() > MAX_HEADER_LINE {
+                return Err(ApiError::BadRequest("multipart header line too long".into()));
+            }
+            if !self.fill()? {
+                return Err(ApiError::BadRequest("body ended inside multipart headers".into()));
+            }
+        }
+    }
+
+    // Read until at least `len` bytes are buffered; false if the body ends first
+    fn fill_to(&mut self, len: usize) -> Result<bool, ApiError> {
+        while self.buf.len() < len {
+            if !self.fill()? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // Callers only read more when what's buffered is short of a delimiter or
+    // a header line, so there is always room
+    fn fill(&mut self) -> Result<bool, ApiError> {
+        let mut chunk = [0u8; 512];
+        let room = BUF_SIZE.saturating_sub(self.buf.len()).min(chunk.len());
+        let n = self.reader.read(&mut chunk[..room])?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n > 0)
+    }
+}
+
+// `key=value` or `key="value"` from a `; `-separated header parameter list
+fn param(params: &str, key: &str) -> Option<String> {
+    params.split(';').find_map(|pair| {
+        let (k, v) = pair.split_once('=')?;
+        k.trim().eq_ignore_ascii_case(key).then(|| v.trim().trim_matches('"').to_string())
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
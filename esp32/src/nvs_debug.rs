@@ -0,0 +1,165 @@
+// Raw view of the settings in NVS for /api/debug/nvs, to check what is
+// actually stored when a setting doesn't seem to take effect. Values are
+// shown as stored, before Config validates them. Secrets are never shown.
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_sys::{self as sys, EspError};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::ffi::{CStr, CString};
+use std::ptr;
+
+use crate::config::NAMESPACE;
+use crate::error::ApiError;
+
+// Keys whose values are write-only
+const SECRET_KEYS: &[&str] = &["sta_pass", "tls_key", "ws_token"];
+// NVS_KEY_NAME_MAX_SIZE, less the NUL
+const MAX_KEY_LEN: usize = 15;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    U8,
+    U16,
+    U32,
+    Str,
+    Blob,
+    // Types the firmware never writes
+    Other,
+}
+
+impl Kind {
+    fn from_raw(kind: sys::nvs_type_t) -> Self {
+        match kind {
+            sys::nvs_type_t_NVS_TYPE_U8 => Kind::U8,
+            sys::nvs_type_t_NVS_TYPE_U16 => Kind::U16,
+            sys::nvs_type_t_NVS_TYPE_U32 => Kind::U32,
+            sys::nvs_type_t_NVS_TYPE_STR => Kind::Str,
+            sys::nvs_type_t_NVS_TYPE_BLOB => Kind::Blob,
+            _ => Kind::Other,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Entry {
+    pub key: String,
+    #[serde(rename = "type")]
+    pub kind: Kind,
+    // None for secrets, blobs and other types
+    pub value: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub len: Option<usize>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub redacted: bool,
+}
+
+// Every key in the namespace, in NVS order
+pub fn list(partition: &EspDefaultNvsPartition) -> Result<Vec<Entry>, EspError> {
+    let nvs = EspNvs::new(partition.clone(), NAMESPACE, true)?;
+
+    stored_keys()?
+        .into_iter()
+        .map(|(key, kind)| {
+            let redacted = is_secret(&key);
+            let (value, len) = match kind {
+                _ if redacted => (None, None),
+                Kind::U8 => (nvs.get_u8(&key)?.map(Value::from), None),
+                Kind::U16 => (nvs.get_u16(&key)?.map(Value::from), None),
+                Kind::U32 => (nvs.get_u32(&key)?.map(Value::from), None),
+                Kind::Str => {
+                    let mut buf = vec![0u8; nvs.str_len(&key)?.unwrap_or(1)];
+                    (nvs.get_str(&key, &mut buf)?.map(Value::from), None)
+                }
+                Kind::Blob => (None, nvs.blob_len(&key)?),
+                Kind::Other => (None, None),
+            };
+            Ok(Entry { key, kind, value, len, redacted })
+        })
+        .collect()
+}
+
+// Store one key, or remove it when `value` is None. The type is the stored
+// entry's, or `kind` for a new key. Settings are read at boot, so nothing
+// changes until a restart.
+pub fn set(partition: &EspDefaultNvsPartition, key: &str, kind: Option<Kind>, value: Option<&Value>) -> Result<Kind, ApiError> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        return Err(ApiError::BadRequest(format!("key must be 1 to {} characters", MAX_KEY_LEN)));
+    }
+    let stored = stored_keys().map_err(nvs_error)?.into_iter().find(|(k, _)| k == key).map(|(_, kind)| kind);
+    if let (Some(stored), Some(kind)) = (stored, kind) {
+        if stored != kind {
+            return Err(ApiError::BadRequest(format!("{} is stored with a different type", key)));
+        }
+    }
+
+    let mut nvs = EspNvs::new(partition.clone(), NAMESPACE, true).map_err(nvs_error)?;
+    let Some(value) = value else {
+        let stored = stored.ok_or_else(|| ApiError::NotFound(format!("{} is not stored", key)))?;
+        nvs.remove(key).map_err(nvs_error)?;
+        return Ok(stored);
+    };
+
+    let kind = stored
+        .or(kind)
+        .ok_or_else(|| ApiError::BadRequest(format!("{} is not stored yet, give its type", key)))?;
+    let number = |max: u64| {
+        value
+            .as_u64()
+            .filter(|n| *n <= max)
+            .ok_or_else(|| ApiError::BadRequest(format!("value must be an integer from 0 to {}", max)))
+    };
+    let stored = match kind {
+        Kind::U8 => nvs.set_u8(key, number(u8::MAX as u64)? as u8),
+        Kind::U16 => nvs.set_u16(key, number(u16::MAX as u64)? as u16),
+        Kind::U32 => nvs.set_u32(key, number(u32::MAX as u64)? as u32),
+        Kind::Str => {
+            let text = value.as_str().ok_or_else(|| ApiError::BadRequest("value must be a string".into()))?;
+            nvs.set_str(key, text)
+        }
+        Kind::Blob | Kind::Other => {
+            return Err(ApiError::BadRequest("only u8, u16, u32 and str keys can be set here".into()));
+        }
+    };
+    stored.map_err(nvs_error)?;
+    Ok(kind)
+}
+
+pub fn is_secret(key: &str) -> bool {
+    SECRET_KEYS.contains(&key)
+}
+
+// EspError alone would be reported as a UART failure
+pub fn nvs_error(e: EspError) -> ApiError {
+    ApiError::Internal(format!("NVS: {}", e))
+}
+
+// EspNvs can't enumerate, so this walks the namespace with the IDF iterator
+fn stored_keys() -> Result<Vec<(String, Kind)>, EspError> {
+    let namespace = CString::new(NAMESPACE).unwrap();
+    let mut keys = Vec::new();
+    let mut iter: sys::nvs_iterator_t = ptr::null_mut();
+
+    let mut err = unsafe {
+        sys::nvs_entry_find(c"nvs".as_ptr(), namespace.as_ptr(), sys::nvs_type_t_NVS_TYPE_ANY, &mut iter)
+    };
+    while err == sys::ESP_OK {
+        let mut info: sys::nvs_entry_info_t = unsafe { std::mem::zeroed() };
+        if let Err(e) = sys::esp!(unsafe { sys::nvs_entry_info(iter, &mut info) }) {
+            unsafe { sys::nvs_release_iterator(iter) };
+            return Err(e);
+        }
+        let key = unsafe { CStr::from_ptr(info.key.as_ptr()) }.to_string_lossy().into_owned();
+        keys.push((key, Kind::from_raw(info.type_)));
+        err = unsafe { sys::nvs_entry_next(&mut iter) };
+    }
+    // Releasing the null iterator the search ends with is allowed
+    unsafe { sys::nvs_release_iterator(iter) };
+
+    // The search reports running out of entries as NOT_FOUND
+    if err != sys::ESP_ERR_NVS_NOT_FOUND {
+        sys::esp!(err)?;
+    }
+    Ok(keys)
+}
@@ -0,0 +1,151 @@
+// Flipper presence and the on_connect commands. With on_connect set, a task
+// PINGs the Flipper every presence_s while the link is otherwise idle. Each
+// time it starts answering (at boot, or after it stopped, e.g. the Ecco app
+// was closed and reopened) the commands run as one batch, so nothing else
+// lands between them. Results go to the log, and from there to ecco-log.
+// With boot_wait_s set, another task PINGs right after boot until the
+// Flipper first answers, and readiness is held back until then.
+
+use anyhow::Result;
+use log::*;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::protocol::{self, cmd, status};
+use crate::uart::{Bridge, Mode};
+
+const STACK: usize = 4096;
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+// Pause between boot PINGs, and how often the wait is logged
+const BOOT_RETRY: Duration = Duration::from_secs(1);
+const BOOT_PROGRESS: Duration = Duration::from_secs(5);
+
+const UNKNOWN: u8 = 0;
+const PRESENT: u8 = 1;
+const ABSENT: u8 = 2;
+static STATE: AtomicU8 = AtomicU8::new(UNKNOWN);
+// Still waiting for the Flipper after boot
+static STARTING: AtomicBool = AtomicBool::new(false);
+
+// None until the first PING has been answered or missed, or when the
+// watcher isn't running
+pub fn present() -> Option<bool> {
+    match STATE.load(Ordering::Relaxed) {
+        PRESENT => Some(true),
+        ABSENT => Some(false),
+        _ => None,
+    }
+}
+
+pub fn starting() -> bool {
+    STARTING.load(Ordering::Relaxed)
+}
+
+// Holds `starting` until the Flipper answers a PING or `timeout` is up.
+// Only readiness waits; the API and WebSockets serve from the start.
+pub fn wait_at_boot(bridge: Arc<Bridge>, timeout: Duration) -> Result<()> {
+    STARTING.store(true, Ordering::Relaxed);
+    thread::Builder::new()
+        .name("boot-wait".into())
+        .stack_size(STACK)
+        .spawn(move || {
+            let started = Instant::now();
+            let mut logged = started;
+            info!("Waiting up to {:?} for the Flipper", timeout);
+            loop {
+                if bridge.listen_only() {
+                    info!("Listen-only mode, not waiting for the Flipper");
+                    break;
+                }
+                match bridge.command(cmd::PING, &[], PING_TIMEOUT) {
+                    Ok(_) => {
+                        info!("Flipper answered {:.1}s after boot", started.elapsed().as_secs_f32());
+                        break;
+                    }
+                    Err(e) if started.elapsed() >= timeout => {
+                        warn!("No answer from the Flipper after {:?} ({}), ready anyway", timeout, e);
+                        break;
+                    }
+                    Err(e) => {
+                        if logged.elapsed() >= BOOT_PROGRESS {
+                            info!(
+                                "Still waiting for the Flipper, {}s of {}s: {}",
+                                started.elapsed().as_secs(),
+                                timeout.as_secs(),
+                                e
+                            );
+                            logged = Instant::now();
+                        }
+                    }
+                }
+                thread::sleep(BOOT_RETRY);
+            }
+            STARTING.store(false, Ordering::Relaxed);
+        })?;
+    Ok(())
+}
+
+// `settle` is the pause between on_connect commands, from batch_settle_ms
+pub fn start(bridge: Arc<Bridge>, interval: Duration, commands: Vec<(u8, Vec<u8>)>, settle: Duration) -> Result<()> {
+    let count = commands.len();
+    let requests: Vec<(u8, Vec<u8>, Duration, Duration)> = commands
+        .into_iter()
+        .map(|(command, payload)| (command, payload, protocol::response_timeout(command), settle))
+        .collect();
+
+    thread::Builder::new()
+        .name("presence".into())
+        .stack_size(STACK)
+        .spawn(move || loop {
+            // Never cuts into raw passthrough or a lock holder's sequence, and
+            // sends nothing in listen-only mode; the state stays as it was
+            // until the next look
+            if bridge.mode() == Mode::Idle && bridge.lock().holder().is_none() && !bridge.listen_only() {
+                check(&bridge, &requests);
+            }
+            thread::sleep(interval);
+        })?;
+
+    info!("Watching for the Flipper every {:?}, {} on_connect commands", interval, count);
+    Ok(())
+}
+
+fn check(bridge: &Bridge, requests: &[(u8, Vec<u8>, Duration, Duration)]) {
+    match bridge.command(cmd::PING, &[], PING_TIMEOUT) {
+        Ok(_) => {
+            if STATE.swap(PRESENT, Ordering::Relaxed) != PRESENT {
+                info!("Flipper connected, running on_connect");
+                run(bridge, requests);
+            }
+        }
+        Err(e) => {
+            if STATE.swap(ABSENT, Ordering::Relaxed) == PRESENT {
+                warn!("Flipper stopped answering: {}", e);
+            }
+        }
+    }
+}
+
+// Every command runs even if an earlier one failed; each is set up
+// independently, like entering an app and then choosing a mode
+fn run(bridge: &Bridge, requests: &[(u8, Vec<u8>, Duration, Duration)]) {
+    let results = bridge.command_batch(requests, |_| true);
+    for (i, ((command, _, _, _), result)) in requests.iter().zip(&results).enumerate() {
+        let name = protocol::cmd_name(*command).map_or_else(|| format!("0x{:02x}", command), String::from);
+        match result {
+            Ok(frame) if frame.status == status::OK => {
+                info!("on_connect {}/{} {}: OK, {} bytes", i + 1, requests.len(), name, frame.payload.len())
+            }
+            Ok(frame) => warn!(
+                "on_connect {}/{} {}: {}",
+                i + 1,
+                requests.len(),
+                name,
+                protocol::status_name(frame.status).unwrap_or("unrecognized status")
+            ),
+            Err(e) => warn!("on_connect {}/{} {}: {}", i + 1, requests.len(), name, e),
+        }
+    }
+}
@@ -0,0 +1,194 @@
+// Ecco frame format, shared with the Flipper app and the webapp.
+// See protocol/schema.md for the full spec.
+
+use std::time::Duration;
+
+pub const FRAME_START: u8 = 0xEC;
+pub const MAX_PAYLOAD: usize = 1024;
+// START + LENGTH(2) + SEQ + CMD + STATUS + CHECKSUM
+pub const FRAME_OVERHEAD: usize = 7;
+pub const MAX_FRAME: usize = MAX_PAYLOAD + FRAME_OVERHEAD;
+
+// Full command table from the spec; not every ID is used by the bridge itself
+#[allow(dead_code)]
+pub mod cmd {
+    pub const PING: u8 = 0x01;
+    pub const DEVICE_INFO: u8 = 0x02;
+    pub const NOTIFY: u8 = 0x03;
+    pub const SUBGHZ_CAPTURE: u8 = 0x10;
+    pub const SUBGHZ_TRANSMIT: u8 = 0x11;
+    pub const NFC_READ: u8 = 0x20;
+    pub const NFC_EMULATE: u8 = 0x21;
+    pub const IR_RECEIVE: u8 = 0x30;
+    pub const IR_TRANSMIT: u8 = 0x31;
+    pub const RFID_READ: u8 = 0x40;
+    pub const STORAGE_LIST: u8 = 0x50;
+    pub const STORAGE_READ: u8 = 0x51;
+    pub const STORAGE_WRITE: u8 = 0x52;
+    pub const STORAGE_MKDIR: u8 = 0x53;
+    pub const DATA_CONTINUE: u8 = 0x60;
+    pub const GUI_SCREENSHOT: u8 = 0x70;
+}
+
+pub mod status {
+    pub const OK: u8 = 0x00;
+    pub const ERR_UNKNOWN: u8 = 0x01;
+    pub const ERR_INVALID: u8 = 0x02;
+    pub const ERR_BUSY: u8 = 0x03;
+    pub const ERR_TIMEOUT: u8 = 0x04;
+    pub const ERR_NOT_FOUND: u8 = 0x05;
+    pub const ERR_NO_DATA: u8 = 0x06;
+    pub const ERR_FULL: u8 = 0x07;
+    pub const ERR_NOT_READY: u8 = 0x08;
+}
+
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub seq: u8,
+    pub cmd: u8,
+    pub status: u8,
+    pub payload: Vec<u8>,
+}
+
+pub enum Parsed {
+    // Need more bytes
+    Incomplete,
+    // Bad start byte, length or checksum - caller should drop a byte and retry
+    Invalid,
+    // A complete frame and the number of bytes it used
+    Frame(Frame, usize),
+}
+
+impl Frame {
+    pub fn request(seq: u8, cmd: u8, payload: Vec<u8>) -> Self {
+        Self { seq, cmd, status: status::OK, payload }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let len = self.payload.len();
+        let mut buf = Vec::with_capacity(FRAME_OVERHEAD + len);
+
+        buf.push(FRAME_START);
+        buf.push((len & 0xFF) as u8);
+        buf.push(((len >> 8) & 0xFF) as u8);
+        buf.push(self.seq);
+        buf.push(self.cmd);
+        buf.push(self.status);
+        buf.extend_from_slice(&self.payload);
+
+        // Checksum: XOR of bytes 1 to end-1
+        let sum = checksum(&buf[1..]);
+        buf.push(sum);
+
+        buf
+    }
+}
+
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, b| sum ^ b)
+}
+
+// Payloads as they appear in JSON and NVS, whitespace allowed between digits
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = s.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if digits.len() % 2 == 1 {
+        return None;
+    }
+    // Bytes, not chars, so nothing but 0-9 a-f A-F gets through: no sign
+    // from_str_radix would take, no slice landing inside a character
+    digits
+        .chunks_exact(2)
+        .map(|pair| Some((hex_digit(pair[0])? << 4) | hex_digit(pair[1])?))
+        .collect()
+}
+
+pub fn hex_digit(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+// Same rules as ecco_parse_frame() on the Flipper side
+pub fn parse_frame(buf: &[u8]) -> Parsed {
+    if buf.len() < FRAME_OVERHEAD {
+        return Parsed::Incomplete;
+    }
+
+    if buf[0] != FRAME_START {
+        return Parsed::Invalid;
+    }
+
+    let payload_len = buf[1] as usize | (buf[2] as usize) << 8;
+    if payload_len > MAX_PAYLOAD {
+        return Parsed::Invalid;
+    }
+
+    let frame_len = FRAME_OVERHEAD + payload_len;
+    if buf.len() < frame_len {
+        return Parsed::Incomplete;
+    }
+
+    if buf[frame_len - 1] != checksum(&buf[1..frame_len - 1]) {
+        return Parsed::Invalid;
+    }
+
+    let frame = Frame {
+        seq: buf[3],
+        cmd: buf[4],
+        status: buf[5],
+        payload: buf[6..6 + payload_len].to_vec(),
+    };
+
+    Parsed::Frame(frame, frame_len)
+}
+
+// Spec names, for logs
+pub fn cmd_name(command: u8) -> Option<&'static str> {
+    Some(match command {
+        cmd::PING => "PING",
+        cmd::DEVICE_INFO => "DEVICE_INFO",
+        cmd::NOTIFY => "NOTIFY",
+        cmd::SUBGHZ_CAPTURE => "SUBGHZ_CAPTURE",
+        cmd::SUBGHZ_TRANSMIT => "SUBGHZ_TRANSMIT",
+        cmd::NFC_READ => "NFC_READ",
+        cmd::NFC_EMULATE => "NFC_EMULATE",
+        cmd::IR_RECEIVE => "IR_RECEIVE",
+        cmd::IR_TRANSMIT => "IR_TRANSMIT",
+        cmd::RFID_READ => "RFID_READ",
+        cmd::STORAGE_LIST => "STORAGE_LIST",
+        cmd::STORAGE_READ => "STORAGE_READ",
+        cmd::STORAGE_WRITE => "STORAGE_WRITE",
+        cmd::STORAGE_MKDIR => "STORAGE_MKDIR",
+        cmd::DATA_CONTINUE => "DATA_CONTINUE",
+        cmd::GUI_SCREENSHOT => "GUI_SCREENSHOT",
+        _ => return None,
+    })
+}
+
+// CMD by its spec name, e.g. "DEVICE_INFO"
+pub fn cmd_by_name(name: &str) -> Option<u8> {
+    (0..=u8::MAX).find(|&command| cmd_name(command).is_some_and(|known| known.eq_ignore_ascii_case(name)))
+}
+
+pub fn status_name(code: u8) -> Option<&'static str> {
+    Some(match code {
+        status::OK => "OK",
+        status::ERR_UNKNOWN => "ERR_UNKNOWN",
+        status::ERR_INVALID => "ERR_INVALID",
+        status::ERR_BUSY => "ERR_BUSY",
+        status::ERR_TIMEOUT => "ERR_TIMEOUT",
+        status::ERR_NOT_FOUND => "ERR_NOT_FOUND",
+        status::ERR_NO_DATA => "ERR_NO_DATA",
+        status::ERR_FULL => "ERR_FULL",
+        status::ERR_NOT_READY => "ERR_NOT_READY",
+        _ => return None,
+    })
+}
+
+// Response timeouts from the spec: 10s, or 30s for capture operations
+pub fn response_timeout(command: u8) -> Duration {
+    match command {
+        cmd::SUBGHZ_CAPTURE | cmd::NFC_READ | cmd::IR_RECEIVE | cmd::RFID_READ => {
+            Duration::from_secs(30)
+        }
+        _ => Duration::from_secs(10),
+    }
+}
@@ -0,0 +1,139 @@
+// The last few frames sent through /api/command, kept in NVS so they survive
+// a reboot and can be replayed from any browser. Stored as one JSON blob
+// under the "recent" key, written every FLUSH_INTERVAL when something changed
+// and on a planned restart, rather than on every call. Payloads that might
+// hold a secret are dropped before anything is written.
+
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::NAMESPACE;
+
+const KEY: &str = "recent";
+const MAX_ENTRIES: usize = 16;
+// Longer payloads are kept as a placeholder only, which bounds the blob to
+// about 2.5KB. They are bulk data (IR timings, SubGHz captures) anyway.
+const MAX_PAYLOAD: usize = 64;
+// Text payloads containing any of these are redacted. A guess, not a
+// filter: "key" also catches "keyboard", and a secret in hex gets through.
+const SECRET_WORDS: &[&str] = &["pass", "pwd", "token", "secret", "key", "auth"];
+// A script looping on /api/command would otherwise rewrite the blob each call
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+const STACK: usize = 4096;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub cmd: u8,
+    // Hex, or None if it was redacted or too long to keep
+    pub payload: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<Skipped>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Skipped {
+    Redacted,
+    Truncated,
+}
+
+static ENTRIES: Mutex<VecDeque<Entry>> = Mutex::new(VecDeque::new());
+// Set by record(), cleared once the entries are in NVS
+static DIRTY: AtomicBool = AtomicBool::new(false);
+static NVS: OnceLock<EspDefaultNvsPartition> = OnceLock::new();
+
+// Reads what the last boot stored and starts the periodic flush
+pub fn start(nvs: EspDefaultNvsPartition) -> Result<()> {
+    match read(&nvs) {
+        Ok(entries) => *ENTRIES.lock().unwrap() = entries,
+        Err(e) => warn!("Recent commands unreadable ({}), starting empty", e),
+    }
+    let _ = NVS.set(nvs);
+
+    thread::Builder::new()
+        .name("recent".into())
+        .stack_size(STACK)
+        .spawn(|| loop {
+            thread::sleep(FLUSH_INTERVAL);
+            flush();
+        })?;
+    Ok(())
+}
+
+pub fn list() -> Vec<Entry> {
+    ENTRIES.lock().unwrap().iter().cloned().collect()
+}
+
+pub fn record(cmd: u8, payload: &[u8]) {
+    record_all([(cmd, payload)]);
+}
+
+pub fn record_all<'a>(commands: impl IntoIterator<Item = (u8, &'a [u8])>) {
+    let mut entries = ENTRIES.lock().unwrap();
+    for (cmd, payload) in commands {
+        if entries.len() == MAX_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back(entry(cmd, payload));
+    }
+    DIRTY.store(true, Ordering::Relaxed);
+}
+
+// Also called on the way down for a planned restart
+pub fn flush() {
+    let Some(nvs) = NVS.get() else {
+        return;
+    };
+    if !DIRTY.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let entries = ENTRIES.lock().unwrap();
+    if let Err(e) = write(nvs, &entries) {
+        warn!("Saving recent commands failed: {}", e);
+        DIRTY.store(true, Ordering::Relaxed);
+    }
+}
+
+fn entry(cmd: u8, payload: &[u8]) -> Entry {
+    if payload.len() > MAX_PAYLOAD {
+        Entry { cmd, payload: None, skipped: Some(Skipped::Truncated) }
+    } else if looks_secret(payload) {
+        Entry { cmd, payload: None, skipped: Some(Skipped::Redacted) }
+    } else {
+        Entry { cmd, payload: Some(payload.iter().map(|b| format!("{:02x}", b)).collect()), skipped: None }
+    }
+}
+
+// Payload text that names a credential
+fn looks_secret(payload: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(payload).to_ascii_lowercase();
+    SECRET_WORDS.iter().any(|word| text.contains(word))
+}
+
+fn read(nvs: &EspDefaultNvsPartition) -> Result<VecDeque<Entry>> {
+    let nvs = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+    let Some(len) = nvs.blob_len(KEY)? else {
+        return Ok(VecDeque::new());
+    };
+
+    let mut buf = vec![0u8; len];
+    let Some(blob) = nvs.get_blob(KEY, &mut buf)? else {
+        return Ok(VecDeque::new());
+    };
+    let mut entries: VecDeque<Entry> = serde_json::from_slice(blob)?;
+    entries.truncate(MAX_ENTRIES);
+    Ok(entries)
+}
+
+fn write(nvs: &EspDefaultNvsPartition, entries: &VecDeque<Entry>) -> Result<()> {
+    let mut nvs = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+    nvs.set_blob(KEY, &serde_json::to_vec(entries)?)?;
+    Ok(())
+}
@@ -0,0 +1,91 @@
+// GUI_SCREENSHOT framebuffer to PNG, for /api/flipper/screenshot.png. The
+// image is two-colour indexed, dark pixels on the display's orange, and
+// scaled up by whole pixels. The zlib stream uses stored (uncompressed)
+// blocks: the largest image is about 16KB, and mostly background, so it goes
+// out as it is.
+
+pub const WIDTH: usize = 128;
+pub const HEIGHT: usize = 64;
+pub const FRAMEBUFFER_LEN: usize = WIDTH * HEIGHT / 8;
+pub const MAX_SCALE: usize = 4;
+
+use crate::gzip::crc32;
+
+// Background, then pixels that are set
+const PALETTE: [[u8; 3]; 2] = [[0xff, 0x82, 0x00], [0x00, 0x00, 0x00]];
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+// Longest stored deflate block
+const MAX_STORED: usize = 65535;
+
+// `framebuffer` as in the spec: 8 pages of 128 single-column bytes, LSB at
+// the top. The caller checks its length.
+pub fn to_png(framebuffer: &[u8], scale: usize) -> Vec<u8> {
+    let (width, height) = (WIDTH * scale, HEIGHT * scale);
+    let stride = width.div_ceil(8);
+
+    // Filter byte 0 (none), then the row packed MSB first
+    let mut raw = Vec::with_capacity((stride + 1) * height);
+    for y in 0..HEIGHT {
+        let start = raw.len();
+        raw.push(0);
+        raw.resize(start + 1 + stride, 0);
+        for x in 0..WIDTH {
+            if framebuffer[(y / 8) * WIDTH + x] & (1 << (y % 8)) == 0 {
+                continue;
+            }
+            for px in x * scale..(x + 1) * scale {
+                raw[start + 1 + px / 8] |= 0x80 >> (px % 8);
+            }
+        }
+        for _ in 1..scale {
+            raw.extend_from_within(start..start + 1 + stride);
+        }
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // Bit depth 1, colour type 3 (indexed), default compression, filter and
+    // no interlace
+    header.extend_from_slice(&[1, 3, 0, 0, 0]);
+
+    let mut png = SIGNATURE.to_vec();
+    chunk(&mut png, b"IHDR", &header);
+    chunk(&mut png, b"PLTE", &PALETTE.concat());
+    chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // 32K window, no preset dictionary, "fastest"
+    let mut out = vec![0x78, 0x01];
+    let blocks = data.len().div_ceil(MAX_STORED).max(1);
+    for i in 0..blocks {
+        let block = &data[i * MAX_STORED..data.len().min((i + 1) * MAX_STORED)];
+        out.push((i + 1 == blocks) as u8);
+        out.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
@@ -0,0 +1,102 @@
+// Planned restarts. Anything that wants the board to go down (the button,
+// /api/reboot) calls request(); main() sees it and runs the same sequence
+// every time: WebSocket clients get a close frame, the HTTP server stops,
+// the UART bridge sends what it has queued and joins its tasks, WiFi stops,
+// and only then is the chip reset.
+
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_sys as sys;
+use log::*;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::events::{self, Event};
+use crate::recent;
+use crate::stats;
+use crate::uart::Bridge;
+use crate::ws;
+
+// Lets the handler that asked for the restart finish its response
+const GRACE: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reason {
+    Restart,
+    // Erase the ecco namespace once everything is stopped, then restart
+    FactoryReset,
+}
+
+const NONE: u8 = 0;
+static REQUESTED: AtomicU8 = AtomicU8::new(NONE);
+
+impl Reason {
+    fn to_raw(self) -> u8 {
+        match self {
+            Reason::Restart => 1,
+            Reason::FactoryReset => 2,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Reason::Restart => "restart",
+            Reason::FactoryReset => "factory_reset",
+        }
+    }
+
+    fn from_raw(raw: u8) -> Option<Self> {
+        match raw {
+            1 => Some(Reason::Restart),
+            2 => Some(Reason::FactoryReset),
+            _ => None,
+        }
+    }
+}
+
+// Only the first request counts; a restart already under way isn't turned
+// into a factory reset or the other way round
+pub fn request(reason: Reason) {
+    if REQUESTED.compare_exchange(NONE, reason.to_raw(), Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+        info!("Shutdown requested ({:?})", reason);
+    }
+}
+
+pub fn requested() -> Option<Reason> {
+    Reason::from_raw(REQUESTED.load(Ordering::Relaxed))
+}
+
+// Runs on the main task. Each step logs and carries on if it fails, since
+// the reset at the end happens regardless.
+pub fn run(reason: Reason, server: EspHttpServer<'static>, bridge: &Bridge) {
+    events::record(Event::Restart { reason: reason.name().into() });
+    thread::sleep(GRACE);
+
+    ws::close_all();
+    // Stopping httpd closes whatever sockets are left
+    drop(server);
+    info!("HTTP server stopped");
+
+    bridge.stop();
+    info!("UART bridge stopped");
+    // Before a factory reset, which then erases them with everything else
+    stats::flush();
+    recent::flush();
+    events::save();
+
+    // The station task owns its driver, so this goes around EspWifi
+    if let Err(e) = sys::esp!(unsafe { sys::esp_wifi_stop() }) {
+        warn!("WiFi stop failed: {}", e);
+    }
+
+    if reason == Reason::FactoryReset {
+        match Config::erase_all() {
+            Ok(()) => warn!("Settings erased"),
+            Err(e) => error!("Factory reset failed: {}", e),
+        }
+    }
+
+    info!("Restarting");
+    esp_idf_hal::reset::restart();
+}
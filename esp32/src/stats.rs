@@ -0,0 +1,159 @@
+// Lifetime usage totals for /api/stats/lifetime: bytes through the UART,
+// commands, uptime, boots and UART reinits, summed over every boot the
+// board has had.
+// This boot's counts live in RAM and are added to the stored totals in one
+// JSON blob under "lifetime", every FLUSH_INTERVAL and on a planned restart.
+// A crash loses at most one interval of counts, never the totals.
+
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_sys as sys;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::NAMESPACE;
+
+const KEY: &str = "lifetime";
+// Each flush rewrites a ~100 byte blob, which NVS wear levelling spreads
+// over the partition; at this rate that is under 300 writes a day
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const STACK: usize = 4096;
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Totals {
+    pub boots: u32,
+    // Boots after a panic, watchdog or brownout rather than a reset or power-on
+    pub unplanned_resets: u32,
+    pub uptime_s: u64,
+    // Bytes read from and written to the Flipper, raw and framed alike
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    // Request/response exchanges: API calls, ecco-rpc frames, PINGs
+    pub commands: u64,
+    // Times a failed UART driver was torn down and set up again
+    #[serde(default)]
+    pub uart_reinits: u32,
+}
+
+const ZERO: Totals = Totals {
+    boots: 0,
+    unplanned_resets: 0,
+    uptime_s: 0,
+    rx_bytes: 0,
+    tx_bytes: 0,
+    commands: 0,
+    uart_reinits: 0,
+};
+
+// Totals as of the last boot, this boot included
+static STORED: Mutex<Totals> = Mutex::new(ZERO);
+// Since boot; uptime comes from the clock instead
+static SESSION: Mutex<Totals> = Mutex::new(ZERO);
+static NVS: OnceLock<EspDefaultNvsPartition> = OnceLock::new();
+
+// Counts this boot, stores it straight away so a crash loop still shows up,
+// and starts the periodic flush
+pub fn start(nvs: EspDefaultNvsPartition) -> Result<()> {
+    let mut stored = match read(&nvs) {
+        Ok(totals) => totals,
+        Err(e) => {
+            warn!("Lifetime stats unreadable ({}), starting from zero", e);
+            ZERO
+        }
+    };
+    stored.boots += 1;
+    if unplanned_reset() {
+        stored.unplanned_resets += 1;
+    }
+    *STORED.lock().unwrap() = stored;
+    let _ = NVS.set(nvs);
+    flush();
+
+    thread::Builder::new()
+        .name("stats".into())
+        .stack_size(STACK)
+        .spawn(|| loop {
+            thread::sleep(FLUSH_INTERVAL);
+            flush();
+        })?;
+    Ok(())
+}
+
+pub fn add_rx(bytes: usize) {
+    SESSION.lock().unwrap().rx_bytes += bytes as u64;
+}
+
+pub fn add_tx(bytes: usize) {
+    SESSION.lock().unwrap().tx_bytes += bytes as u64;
+}
+
+pub fn add_command() {
+    SESSION.lock().unwrap().commands += 1;
+}
+
+pub fn add_uart_reinit() {
+    SESSION.lock().unwrap().uart_reinits += 1;
+}
+
+// This boot's counts
+pub fn session() -> Totals {
+    Totals { uptime_s: uptime().as_secs(), ..*SESSION.lock().unwrap() }
+}
+
+// Stored totals plus this boot's counts
+pub fn lifetime() -> Totals {
+    let stored = *STORED.lock().unwrap();
+    let session = session();
+    Totals {
+        boots: stored.boots,
+        unplanned_resets: stored.unplanned_resets,
+        uptime_s: stored.uptime_s + session.uptime_s,
+        rx_bytes: stored.rx_bytes + session.rx_bytes,
+        tx_bytes: stored.tx_bytes + session.tx_bytes,
+        commands: stored.commands + session.commands,
+        uart_reinits: stored.uart_reinits + session.uart_reinits,
+    }
+}
+
+// Also called on the way down for a planned restart
+pub fn flush() {
+    let Some(nvs) = NVS.get() else {
+        return;
+    };
+    if let Err(e) = write(nvs, &lifetime()) {
+        warn!("Saving lifetime stats failed: {}", e);
+    }
+}
+
+fn uptime() -> Duration {
+    Duration::from_micros(unsafe { sys::esp_timer_get_time() } as u64)
+}
+
+fn unplanned_reset() -> bool {
+    matches!(
+        unsafe { sys::esp_reset_reason() },
+        sys::esp_reset_reason_t_ESP_RST_PANIC
+            | sys::esp_reset_reason_t_ESP_RST_INT_WDT
+            | sys::esp_reset_reason_t_ESP_RST_TASK_WDT
+            | sys::esp_reset_reason_t_ESP_RST_WDT
+            | sys::esp_reset_reason_t_ESP_RST_BROWNOUT
+    )
+}
+
+fn read(nvs: &EspDefaultNvsPartition) -> Result<Totals> {
+    let nvs = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+    let mut buf = [0u8; 256];
+    match nvs.get_blob(KEY, &mut buf)? {
+        Some(blob) => Ok(serde_json::from_slice(blob)?),
+        None => Ok(ZERO),
+    }
+}
+
+fn write(nvs: &EspDefaultNvsPartition, totals: &Totals) -> Result<()> {
+    let mut nvs = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+    nvs.set_blob(KEY, &serde_json::to_vec(totals)?)?;
+    Ok(())
+}
@@ -0,0 +1,216 @@
+// Streaming tar reader for /api/flipper/upload-bundle. Entries are handed
+// out one at a time and their data read in pieces, so an archive of any
+// size passes through in a few hundred bytes. Plain ustar and what GNU tar
+// and bsdtar write by default are understood: long names come from a GNU
+// 'L' entry or a pax "path" record before the entry they name.
+
+use embedded_svc::io::Read;
+use esp_idf_svc::io::EspIOError;
+
+use crate::error::ApiError;
+
+const BLOCK: usize = 512;
+// Longest name taken from a GNU 'L' entry or a pax header; longer ones
+// wouldn't fit a Flipper path anyway
+const MAX_LONG_NAME: u64 = 1024;
+// pax headers bigger than this are skipped rather than read
+const MAX_PAX: u64 = 4096;
+
+#[derive(Debug, PartialEq)]
+pub enum Kind {
+    File,
+    Dir,
+    // Links, devices, FIFOs; skipped by the caller
+    Other(u8),
+}
+
+pub struct Entry {
+    pub path: String,
+    pub kind: Kind,
+    pub size: u64,
+}
+
+pub struct Tar<R> {
+    reader: R,
+    // Data bytes left in the current entry, then the padding to the next block
+    remaining: u64,
+    padding: u64,
+    finished: bool,
+}
+
+impl<R: Read<Error = EspIOError>> Tar<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, remaining: 0, padding: 0, finished: false }
+    }
+
+    // Skip what's left of the current entry and parse the next header. None
+    // at the end-of-archive blocks, or if the body ends between entries.
+    pub fn next_entry(&mut self) -> Result<Option<Entry>, ApiError> {
+        let mut long_name = None;
+        loop {
+            self.skip()?;
+            if self.finished {
+                return Ok(None);
+            }
+
+            let mut header = [0u8; BLOCK];
+            if !self.read_block(&mut header)? || header.iter().all(|&b| b == 0) {
+                self.finished = true;
+                return Ok(None);
+            }
+            if !checksum_ok(&header) {
+                return Err(ApiError::BadRequest("tar header checksum mismatch".into()));
+            }
+
+            let size = octal(&header[124..136])
+                .ok_or_else(|| ApiError::BadRequest("tar entry size unreadable".into()))?;
+            self.remaining = size;
+            self.padding = (BLOCK as u64 - size % BLOCK as u64) % BLOCK as u64;
+
+            let kind = match header[156] {
+                b'0' | 0 | b'7' => Kind::File,
+                b'5' => Kind::Dir,
+                // GNU long name for the next entry
+                b'L' => {
+                    long_name = Some(self.read_name(size)?);
+                    continue;
+                }
+                // pax header for the next entry; only the path matters here
+                b'x' => {
+                    if let Some(path) = self.read_pax_path(size)? {
+                        long_name = Some(path);
+                    }
+                    continue;
+                }
+                // pax global header
+                b'g' => continue,
+                other => Kind::Other(other),
+            };
+
+            let path = match long_name.take() {
+                Some(path) => path,
+                None => header_name(&header),
+            };
+            return Ok(Some(Entry { path, kind, size }));
+        }
+    }
+
+    // Data of the current entry; 0 once it ends
+    pub fn read(&mut self, out: &mut [u8]) -> Result<usize, ApiError> {
+        let len = (out.len() as u64).min(self.remaining) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+        let n = self.reader.read(&mut out[..len])?;
+        if n == 0 {
+            return Err(ApiError::BadRequest("body ended inside a tar entry".into()));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+
+    fn skip(&mut self) -> Result<(), ApiError> {
+        let mut scratch = [0u8; 256];
+        while self.read(&mut scratch)? > 0 {}
+        while self.padding > 0 {
+            let len = (scratch.len() as u64).min(self.padding) as usize;
+            let n = self.reader.read(&mut scratch[..len])?;
+            if n == 0 {
+                return Err(ApiError::BadRequest("body ended inside tar padding".into()));
+            }
+            self.padding -= n as u64;
+        }
+        Ok(())
+    }
+
+    // false if the body ends cleanly before the block starts
+    fn read_block(&mut self, block: &mut [u8; BLOCK]) -> Result<bool, ApiError> {
+        let mut len = 0;
+        while len < BLOCK {
+            let n = self.reader.read(&mut block[len..])?;
+            if n == 0 {
+                if len == 0 {
+                    return Ok(false);
+                }
+                return Err(ApiError::BadRequest("body ended inside a tar header".into()));
+            }
+            len += n;
+        }
+        Ok(true)
+    }
+
+    fn read_data(&mut self, size: u64) -> Result<Vec<u8>, ApiError> {
+        let mut data = vec![0u8; size as usize];
+        let mut len = 0;
+        while len < data.len() {
+            len += self.read(&mut data[len..])?;
+        }
+        Ok(data)
+    }
+
+    fn read_name(&mut self, size: u64) -> Result<String, ApiError> {
+        if size > MAX_LONG_NAME {
+            return Err(ApiError::BadRequest(format!("tar long name over {} bytes", MAX_LONG_NAME)));
+        }
+        let data = self.read_data(size)?;
+        Ok(text(&data))
+    }
+
+    // Records are "LEN key=value\n"
+    fn read_pax_path(&mut self, size: u64) -> Result<Option<String>, ApiError> {
+        if size > MAX_PAX {
+            return Ok(None);
+        }
+        let data = self.read_data(size)?;
+        let mut rest = &data[..];
+        while let Some(space) = rest.iter().position(|&b| b == b' ') {
+            let Some(len) = std::str::from_utf8(&rest[..space]).ok().and_then(|len| len.parse::<usize>().ok()) else {
+                break;
+            };
+            if len <= space + 1 || len > rest.len() {
+                break;
+            }
+            let record = &rest[space + 1..len];
+            if let Some(path) = record.strip_prefix(b"path=") {
+                return Ok(Some(text(path.strip_suffix(b"\n").unwrap_or(path))));
+            }
+            rest = &rest[len..];
+        }
+        Ok(None)
+    }
+}
+
+// ustar splits long names into a prefix and a name
+fn header_name(header: &[u8; BLOCK]) -> String {
+    let name = text(&header[..100]);
+    if &header[257..262] == b"ustar" && header[345] != 0 {
+        return format!("{}/{}", text(&header[345..500]), name);
+    }
+    name
+}
+
+// Sum of all header bytes, with the checksum field itself counted as spaces
+fn checksum_ok(header: &[u8; BLOCK]) -> bool {
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' as u64 } else { b as u64 })
+        .sum();
+    octal(&header[148..156]) == Some(sum)
+}
+
+// NUL- or space-terminated octal, as tar writes numbers
+fn octal(field: &[u8]) -> Option<u64> {
+    let digits: Vec<u8> = field
+        .iter()
+        .copied()
+        .skip_while(|&b| b == b' ')
+        .take_while(|&b| b != 0 && b != b' ')
+        .collect();
+    u64::from_str_radix(std::str::from_utf8(&digits).ok()?, 8).ok()
+}
+
+fn text(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
@@ -0,0 +1,48 @@
+// Flipper file transfers: downloads, uploads and bundles, as they show up in
+// /api/events when they start and end. httpd runs every handler on its one
+// task, so transfers never overlap; a second one waits for the first to
+// finish like any other request, and there is nothing to limit.
+
+use std::time::Instant;
+
+use crate::events::{self, Event};
+
+// Records the end when dropped, however the transfer ends. One dropped
+// without done() is recorded as failed.
+pub struct Transfer {
+    kind: &'static str,
+    path: Option<String>,
+    started: Instant,
+    ok: bool,
+    bytes: Option<u64>,
+}
+
+impl Transfer {
+    // Downloads know it once the query is checked, uploads once the form names it
+    pub fn set_path(&mut self, path: &str) {
+        self.path = Some(path.to_string());
+    }
+
+    pub fn done(&mut self, bytes: u64) {
+        self.ok = true;
+        self.bytes = Some(bytes);
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        events::record(Event::TransferFinished {
+            kind: self.kind.into(),
+            path: self.path.take(),
+            ok: self.ok,
+            bytes: self.bytes,
+            ms: self.started.elapsed().as_millis() as u64,
+        });
+    }
+}
+
+// `kind` is "download", "upload" or "bundle"
+pub fn begin(kind: &'static str) -> Transfer {
+    events::record(Event::TransferStarted { kind: kind.into() });
+    Transfer { kind, path: None, started: Instant::now(), ok: false, bytes: None }
+}
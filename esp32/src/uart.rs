@@ -0,0 +1,884 @@
+use esp_idf_hal::delay::{Ets, TickType};
+use esp_idf_hal::gpio;
+use esp_idf_hal::prelude::*;
+use esp_idf_hal::uart::{self, UartDriver, UartEventPayload};
+use esp_idf_sys::{self as sys, EspError};
+use log::*;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::lock::Lock;
+use crate::protocol::{self, Frame, Parsed};
+use crate::stats;
+
+pub const UART_BAUD: u32 = 115200;
+
+// Pause after a failed UART read before trying again
+const READ_RETRY: Duration = Duration::from_millis(50);
+// Failed reads in a row after which the driver counts as broken
+const FATAL_READ_ERRORS: u32 = 10;
+// First wait before reinitializing a broken driver; doubles up to uart_reinit_max_s
+const REINIT_FIRST: Duration = Duration::from_secs(1);
+// How often the event thread lets go of the driver, so it can be replaced
+const EVENT_POLL: Duration = Duration::from_millis(100);
+const READER_STACK: usize = 4096;
+const WRITER_STACK: usize = 4096;
+const KEEPALIVE_STACK: usize = 4096;
+const EVENTS_STACK: usize = 3072;
+// Driver events queued before the ISR starts dropping them
+const EVENT_QUEUE: usize = 16;
+// Traced payloads longer than this are logged as a byte count
+const TRACE_INLINE: usize = 32;
+// Line errors come in bursts at a wrong baud rate; log at most this often
+const ERROR_REPORT: Duration = Duration::from_secs(1);
+// Keepalive PINGs are cheap, so give up on them quickly
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(2);
+// Writes waiting for the UART before write() starts returning Busy
+const TX_QUEUE: usize = 8;
+// The bridge counts as in raw mode for this long after the last raw write
+const RAW_IDLE: Duration = Duration::from_secs(2);
+// How often the writer checks for a stop while its queue is empty
+const WRITER_POLL: Duration = Duration::from_millis(100);
+// Longest stop() waits for the hardware to send what was queued
+const TX_DRAIN: Duration = Duration::from_secs(1);
+// Paced bytes each wait for the wire; one byte takes under 0.1ms at UART_BAUD
+const TX_BYTE_DONE: Duration = Duration::from_millis(10);
+// Gaps shorter than a FreeRTOS tick are busy-waited. The writer sleeps a tick
+// after this much of that, so the idle task still runs and the watchdog
+// stays quiet during a long paced write.
+const PACE_TICK: Duration = Duration::from_millis(10);
+const PACE_BUSY: Duration = Duration::from_millis(100);
+
+// Who is using the UART right now, as reported by /api/status
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Idle,
+    // Passthrough bytes from an ecco-raw client
+    Raw,
+    // A framed request/response exchange is in flight
+    Rpc,
+}
+
+impl Mode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Idle => "idle",
+            Mode::Raw => "raw",
+            Mode::Rpc => "rpc",
+        }
+    }
+}
+
+// What went wrong on the line or in the driver
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LineError {
+    // Bad stop bit, usually a baud rate mismatch
+    Framing,
+    Parity,
+    // Bytes arrived faster than the driver emptied the hardware FIFO
+    Overrun,
+    // The driver's RX buffer was full, so incoming bytes were dropped
+    BufferFull,
+    // RX held low for longer than a byte, e.g. a loose wire
+    Break,
+    // uart_read_bytes / uart_write_bytes failed
+    Read,
+    Write,
+}
+
+// Counts since boot, reported by /api/status
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LineErrors {
+    pub framing: u32,
+    pub parity: u32,
+    pub overrun: u32,
+    pub buffer_full: u32,
+    #[serde(rename = "break")]
+    pub line_break: u32,
+    pub read: u32,
+    pub write: u32,
+    pub last: Option<LastError>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct LastError {
+    pub kind: LineError,
+    // ms since boot, as on log lines
+    pub at: u32,
+}
+
+impl LineErrors {
+    fn count(&mut self, kind: LineError) {
+        let counter = match kind {
+            LineError::Framing => &mut self.framing,
+            LineError::Parity => &mut self.parity,
+            LineError::Overrun => &mut self.overrun,
+            LineError::BufferFull => &mut self.buffer_full,
+            LineError::Break => &mut self.line_break,
+            LineError::Read => &mut self.read,
+            LineError::Write => &mut self.write,
+        };
+        *counter = counter.saturating_add(1);
+        self.last = Some(LastError { kind, at: unsafe { sys::esp_log_timestamp() } });
+    }
+}
+
+// Load on the arbiter, reported by /api/status, to tell contention for the
+// UART apart from a slow Flipper
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueueStats {
+    // Writes handed to the writer and not yet on the wire, and their size
+    pub tx_queued: usize,
+    pub tx_bytes: usize,
+    // Callers blocked on the exchange right now
+    pub waiting: u32,
+    // Exchanges taken since boot, and how long callers waited for them
+    pub exchanges: u32,
+    pub wait_ms_total: u64,
+    pub wait_ms_max: u32,
+    pub wait_ms_last: u32,
+}
+
+#[derive(Default)]
+struct ErrorLog {
+    counts: LineErrors,
+    reported: Option<Instant>,
+    // Errors since the last warning
+    unreported: u32,
+}
+
+pub fn setup_uart<'a>(
+    uart: uart::UART1,
+    tx: gpio::Gpio17,
+    rx: gpio::Gpio18,
+) -> anyhow::Result<UartDriver<'a>> {
+    let driver = open(uart, tx, rx)?;
+    info!("UART initialized at {} baud", UART_BAUD);
+    Ok(driver)
+}
+
+fn open<'a>(uart: uart::UART1, tx: gpio::Gpio17, rx: gpio::Gpio18) -> Result<UartDriver<'a>, EspError> {
+    let config = uart::config::Config::default()
+        .baudrate(Hertz(UART_BAUD))
+        .queue_size(EVENT_QUEUE);
+
+    UartDriver::new(
+        uart,
+        tx,
+        rx,
+        Option::<gpio::Gpio0>::None,
+        Option::<gpio::Gpio1>::None,
+        &config,
+    )
+}
+
+// Called from the reader thread with every chunk received from the Flipper.
+// Returning false unregisters the tap. Taps must not block.
+pub type Tap = Box<dyn FnMut(&[u8]) -> bool + Send>;
+
+struct Pending {
+    seq: u8,
+    cmd: u8,
+    // Bytes read since the request went out that weren't its response
+    received: usize,
+    // The response, or the write error that kept the request from going out
+    reply: SyncSender<Result<Frame, ApiError>>,
+}
+
+// Owns the UART to the Flipper. A reader thread drains RX, hands raw bytes
+// to any taps and routes response frames back to the waiting command().
+// A writer thread drains a bounded TX queue, so a Flipper that stops reading
+// fills the queue instead of blocking callers.
+pub struct Bridge {
+    // None only while a broken driver is being replaced
+    uart: RwLock<Option<UartDriver<'static>>>,
+    // Set when the driver fails for good; the reader then reinitializes it
+    broken: AtomicBool,
+    // Longest wait between attempts at that; zero leaves a broken driver be
+    reinit_max: Duration,
+    seq: AtomicU8,
+    // Held for a whole request/response exchange, so commands never interleave
+    exchange: Mutex<()>,
+    // Callers blocked on `exchange`, and the waits of those that got it
+    waiting: AtomicU32,
+    waits: Mutex<QueueStats>,
+    // Each entry is written whole, so raw writes and frames never interleave
+    tx: SyncSender<Vec<u8>>,
+    // Entries and bytes in `tx` or being written
+    tx_queued: AtomicUsize,
+    tx_bytes: AtomicUsize,
+    pending: Mutex<Option<Pending>>,
+    taps: Mutex<Vec<(u32, Tap)>>,
+    next_tap: AtomicU32,
+    // Tells the reader to drop its partial frame and the driver's RX buffer
+    flush_rx: AtomicBool,
+    last_raw: Mutex<Option<Instant>>,
+    // Last time anything was queued for the Flipper
+    last_tx: Mutex<Instant>,
+    // Newest bytes from the Flipper, handed to each new tap
+    rx_history: Mutex<VecDeque<u8>>,
+    rx_history_len: usize,
+    // Wait between writing a request and reading its response
+    cmd_delay: Duration,
+    // Bytes an exchange may read before its response; past that it fails
+    cmd_rx_max: usize,
+    // Quiet time on RX after which a partial chunk is handed on
+    rx_idle: Duration,
+    // Extra attempts at a write that failed with a transient error, and the
+    // pause before each
+    tx_retries: u8,
+    tx_retry: Duration,
+    // Pause after each byte written; zero writes whole chunks
+    tx_gap: Duration,
+    errors: Mutex<ErrorLog>,
+    // Log every frame sent and received, decoded
+    trace: AtomicBool,
+    // Refuse every write, so the Flipper is only watched
+    listen_only: AtomicBool,
+    // Exclusive session; checked by the API and WebSocket front ends, which
+    // know who is asking
+    lock: Lock,
+    // Set by stop(); the reader and writer exit when they see it
+    stopping: AtomicBool,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Bridge {
+    // Every tunable is read from the config, not passed one by one
+    pub fn start(uart: UartDriver<'static>, config: &Config) -> anyhow::Result<Arc<Self>> {
+        let rx_history = config.raw_history;
+        let (tx, tx_queue) = mpsc::sync_channel(TX_QUEUE);
+        let bridge = Arc::new(Self {
+            uart: RwLock::new(Some(uart)),
+            broken: AtomicBool::new(false),
+            reinit_max: config.uart_reinit_max,
+            seq: AtomicU8::new(0),
+            exchange: Mutex::new(()),
+            waiting: AtomicU32::new(0),
+            waits: Mutex::new(QueueStats::default()),
+            tx,
+            tx_queued: AtomicUsize::new(0),
+            tx_bytes: AtomicUsize::new(0),
+            pending: Mutex::new(None),
+            taps: Mutex::new(Vec::new()),
+            next_tap: AtomicU32::new(0),
+            flush_rx: AtomicBool::new(false),
+            last_raw: Mutex::new(None),
+            last_tx: Mutex::new(Instant::now()),
+            rx_history: Mutex::new(VecDeque::with_capacity(rx_history)),
+            rx_history_len: rx_history,
+            cmd_delay: config.cmd_delay,
+            cmd_rx_max: config.cmd_rx_max,
+            rx_idle: config.rx_idle,
+            tx_retries: config.tx_retries,
+            tx_retry: config.tx_retry,
+            tx_gap: config.tx_gap,
+            errors: Mutex::new(ErrorLog::default()),
+            trace: AtomicBool::new(config.frame_trace),
+            listen_only: AtomicBool::new(config.listen_only),
+            lock: Lock::default(),
+            stopping: AtomicBool::new(false),
+            workers: Mutex::new(Vec::new()),
+        });
+
+        let reader = bridge.clone();
+        let reader = thread::Builder::new()
+            .name("uart-rx".into())
+            .stack_size(READER_STACK)
+            .spawn(move || reader.read_loop())?;
+
+        let writer = bridge.clone();
+        let writer = thread::Builder::new()
+            .name("uart-tx".into())
+            .stack_size(WRITER_STACK)
+            .spawn(move || writer.write_loop(tx_queue))?;
+        *bridge.workers.lock().unwrap() = vec![reader, writer];
+
+        let events = bridge.clone();
+        thread::Builder::new()
+            .name("uart-events".into())
+            .stack_size(EVENTS_STACK)
+            .spawn(move || events.event_loop())?;
+
+        Ok(bridge)
+    }
+
+    // Send one request frame and wait for the response with the matching SEQ.
+    // The exchange is held for at most `timeout`, so a hung Flipper can't wedge it.
+    pub fn command(&self, cmd: u8, payload: &[u8], timeout: Duration) -> Result<Frame, ApiError> {
+        let _exchange = self.take_exchange();
+        self.exchange_locked(cmd, payload, timeout)
+    }
+
+    // Several commands back to back under one exchange, so no other client's
+    // frames or raw bytes land between them. `keep_going` sees each result and
+    // decides whether the next command runs. Each step waits for its response,
+    // then for its settle time before the next one is sent.
+    pub fn command_batch<F>(
+        &self,
+        requests: &[(u8, Vec<u8>, Duration, Duration)],
+        mut keep_going: F,
+    ) -> Vec<Result<Frame, ApiError>>
+    where
+        F: FnMut(&Result<Frame, ApiError>) -> bool,
+    {
+        let _exchange = self.take_exchange();
+        let mut results = Vec::with_capacity(requests.len());
+
+        for (i, (cmd, payload, timeout, settle)) in requests.iter().enumerate() {
+            let result = self.exchange_locked(*cmd, payload, *timeout);
+            let go_on = keep_going(&result);
+            results.push(result);
+            if !go_on {
+                break;
+            }
+            if i + 1 < requests.len() && !settle.is_zero() {
+                thread::sleep(*settle);
+            }
+        }
+        results
+    }
+
+    // Waits for the exchange, keeping count of who is waiting and for how long
+    fn take_exchange(&self) -> MutexGuard<'_, ()> {
+        let started = Instant::now();
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        let exchange = self.exchange.lock().unwrap();
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+
+        let waited = started.elapsed().as_millis() as u32;
+        let mut waits = self.waits.lock().unwrap();
+        waits.exchanges = waits.exchanges.wrapping_add(1);
+        waits.wait_ms_total += waited as u64;
+        waits.wait_ms_max = waits.wait_ms_max.max(waited);
+        waits.wait_ms_last = waited;
+        exchange
+    }
+
+    pub fn queue_stats(&self) -> QueueStats {
+        QueueStats {
+            tx_queued: self.tx_queued.load(Ordering::Relaxed),
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            waiting: self.waiting.load(Ordering::Relaxed),
+            ..self.waits.lock().unwrap().clone()
+        }
+    }
+
+    // One request/response; the caller holds the exchange
+    fn exchange_locked(&self, cmd: u8, payload: &[u8], timeout: Duration) -> Result<Frame, ApiError> {
+        if self.listen_only() {
+            return Err(ApiError::ListenOnly);
+        }
+        if payload.len() > protocol::MAX_PAYLOAD {
+            return Err(ApiError::BadRequest(format!(
+                "payload is {} bytes, max {}",
+                payload.len(),
+                protocol::MAX_PAYLOAD
+            )));
+        }
+
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let request = Frame::request(seq, cmd, payload.to_vec());
+        stats::add_command();
+        if self.tracing() {
+            info!("frame {}", trace_line("tx", &request));
+        }
+
+        let (reply, response) = mpsc::sync_channel(1);
+        *self.pending.lock().unwrap() = Some(Pending { seq, cmd, received: 0, reply });
+
+        // The reader keeps buffering during the delay, so nothing is lost; the
+        // wait only holds the exchange, spacing commands for slow targets
+        let result = self.send(request.encode()).and_then(|()| {
+            if !self.cmd_delay.is_zero() {
+                thread::sleep(self.cmd_delay);
+            }
+            response.recv_timeout(timeout).map_err(|_| ApiError::Timeout)?
+        });
+        self.pending.lock().unwrap().take();
+
+        let gave_up = match &result {
+            Err(ApiError::Timeout) => {
+                warn!("Command 0x{:02x} seq={} timed out after {:?}", cmd, seq, timeout);
+                true
+            }
+            Err(ApiError::Overrun(max)) => {
+                warn!("Command 0x{:02x} seq={} read over {} bytes without its response", cmd, seq, max);
+                true
+            }
+            _ => false,
+        };
+        if gave_up {
+            // Give up on this exchange. Whatever the Flipper sent so far,
+            // or sends late, must not be mistaken for the next response.
+            self.flush_rx.store(true, Ordering::Relaxed);
+        }
+
+        result
+    }
+
+    // Raw passthrough, bypassing request/response matching. Refused while an
+    // exchange is in flight, so raw bytes can't land in the middle of a
+    // request or provoke output that gets mistaken for its response.
+    pub fn write(&self, data: &[u8]) -> Result<(), ApiError> {
+        if self.listen_only() {
+            return Err(ApiError::ListenOnly);
+        }
+        let Ok(_exchange) = self.exchange.try_lock() else {
+            return Err(ApiError::Busy);
+        };
+
+        *self.last_raw.lock().unwrap() = Some(Instant::now());
+        self.send(data.to_vec())
+    }
+
+    pub fn mode(&self) -> Mode {
+        if self.exchange.try_lock().is_err() {
+            return Mode::Rpc;
+        }
+        match *self.last_raw.lock().unwrap() {
+            Some(at) if at.elapsed() < RAW_IDLE => Mode::Raw,
+            _ => Mode::Idle,
+        }
+    }
+
+    // PING the Flipper whenever the link has been quiet for `interval` and
+    // `wanted` says someone is listening. Goes through command(), so it waits
+    // its turn behind real exchanges and never splits a raw write.
+    pub fn start_keepalive<F>(self: &Arc<Self>, interval: Duration, wanted: F) -> anyhow::Result<()>
+    where
+        F: Fn() -> bool + Send + 'static,
+    {
+        let bridge = self.clone();
+        thread::Builder::new()
+            .name("uart-keepalive".into())
+            .stack_size(KEEPALIVE_STACK)
+            .spawn(move || loop {
+                let quiet = bridge.last_tx.lock().unwrap().elapsed();
+                if quiet < interval {
+                    thread::sleep(interval - quiet);
+                    continue;
+                }
+
+                // A lock holder's sequence isn't interrupted, not even by a PING,
+                // and listen-only mode sends nothing at all
+                if wanted() && bridge.mode() == Mode::Idle && bridge.lock.holder().is_none() && !bridge.listen_only() {
+                    match bridge.command(protocol::cmd::PING, &[], KEEPALIVE_TIMEOUT) {
+                        Ok(_) => debug!("Keepalive PING answered"),
+                        Err(e) => warn!("Keepalive PING failed: {}", e),
+                    }
+                } else {
+                    // Nobody listening, or the link is in use; look again later
+                    *bridge.last_tx.lock().unwrap() = Instant::now();
+                }
+            })?;
+
+        info!("UART keepalive every {:?}", interval);
+        Ok(())
+    }
+
+    // Only queues the data; a full queue means the Flipper isn't keeping up
+    fn send(&self, data: Vec<u8>) -> Result<(), ApiError> {
+        if self.stopping.load(Ordering::Relaxed) {
+            return Err(ApiError::Internal("UART writer stopped".into()));
+        }
+        // Counted first, so the writer can't take it off before it's added
+        let len = data.len();
+        self.tx_queued.fetch_add(1, Ordering::Relaxed);
+        self.tx_bytes.fetch_add(len, Ordering::Relaxed);
+
+        match self.tx.try_send(data) {
+            Ok(()) => {
+                *self.last_tx.lock().unwrap() = Instant::now();
+                Ok(())
+            }
+            Err(e) => {
+                self.tx_queued.fetch_sub(1, Ordering::Relaxed);
+                self.tx_bytes.fetch_sub(len, Ordering::Relaxed);
+                match e {
+                    TrySendError::Full(_) => Err(ApiError::Busy),
+                    TrySendError::Disconnected(_) => Err(ApiError::Internal("UART writer stopped".into())),
+                }
+            }
+        }
+    }
+
+    pub fn lock(&self) -> &Lock {
+        &self.lock
+    }
+
+    pub fn tracing(&self) -> bool {
+        self.trace.load(Ordering::Relaxed)
+    }
+
+    pub fn set_tracing(&self, on: bool) {
+        self.trace.store(on, Ordering::Relaxed);
+    }
+
+    // The driver is installed and hasn't failed
+    pub fn uart_ok(&self) -> bool {
+        !self.broken.load(Ordering::Relaxed) && self.uart.read().unwrap().is_some()
+    }
+
+    pub fn listen_only(&self) -> bool {
+        self.listen_only.load(Ordering::Relaxed)
+    }
+
+    // Writes already queued still go out; everything after is refused
+    pub fn set_listen_only(&self, on: bool) {
+        self.listen_only.store(on, Ordering::Relaxed);
+    }
+
+    pub fn line_errors(&self) -> LineErrors {
+        self.errors.lock().unwrap().counts.clone()
+    }
+
+    // Count the error and warn about it, at most once per ERROR_REPORT with
+    // the number since the last warning
+    fn line_error(&self, kind: LineError) {
+        let mut errors = self.errors.lock().unwrap();
+        errors.counts.count(kind);
+        errors.unreported += 1;
+
+        if !matches!(errors.reported, Some(at) if at.elapsed() < ERROR_REPORT) {
+            warn!("UART {:?} error ({} since last report, check wiring and baud rate)", kind, errors.unreported);
+            errors.reported = Some(Instant::now());
+            errors.unreported = 0;
+        }
+    }
+
+    // Error events from the driver. The ISR queues a DATA event for every
+    // chunk too, so this also keeps the queue from filling up with them.
+    fn event_loop(&self) {
+        loop {
+            let driver = self.uart.read().unwrap();
+            let Some(uart) = driver.as_ref() else {
+                // Being reinitialized
+                drop(driver);
+                thread::sleep(EVENT_POLL);
+                continue;
+            };
+            let Some(queue) = uart.event_queue() else {
+                warn!("UART has no event queue, line errors won't be counted");
+                return;
+            };
+            let Some((event, _)) = queue.recv_front(TickType::from(EVENT_POLL).ticks()) else {
+                continue;
+            };
+            let kind = match event.payload() {
+                UartEventPayload::FrameError => LineError::Framing,
+                UartEventPayload::ParityError => LineError::Parity,
+                UartEventPayload::RxFifoOverflow => LineError::Overrun,
+                UartEventPayload::RxBufferFull => LineError::BufferFull,
+                UartEventPayload::Break | UartEventPayload::DataBreak => LineError::Break,
+                _ => continue,
+            };
+            self.line_error(kind);
+        }
+    }
+
+    // The tap is first called with the kept RX history, under the same lock
+    // the reader holds, so it sees every byte once and in order
+    pub fn add_tap(&self, mut tap: Tap) -> u32 {
+        let id = self.next_tap.fetch_add(1, Ordering::Relaxed);
+        let mut taps = self.taps.lock().unwrap();
+
+        let history: Vec<u8> = self.rx_history.lock().unwrap().iter().copied().collect();
+        if history.is_empty() || tap(&history) {
+            taps.push((id, tap));
+        }
+        id
+    }
+
+    pub fn remove_tap(&self, id: u32) {
+        self.taps.lock().unwrap().retain(|(tap_id, _)| *tap_id != id);
+    }
+
+    // For a planned restart: sends whatever is already queued, waits for the
+    // UART to put it on the wire, and joins the reader and writer. Later
+    // writes fail; frames the Flipper sends from here on are dropped.
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+        for worker in self.workers.lock().unwrap().drain(..) {
+            if worker.join().is_err() {
+                warn!("UART worker panicked");
+            }
+        }
+        if let Err(e) = self.with_uart(|uart| uart.wait_tx_done(TickType::from(TX_DRAIN).ticks())) {
+            warn!("UART TX not drained: {}", e);
+        }
+    }
+
+    fn write_loop(&self, queue: Receiver<Vec<u8>>) {
+        loop {
+            let entry = match queue.recv_timeout(WRITER_POLL) {
+                Ok(entry) => entry,
+                // Anything queued before the stop is still sent
+                Err(RecvTimeoutError::Timeout) if self.stopping.load(Ordering::Relaxed) => break,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let mut data = &entry[..];
+            let mut retries = 0;
+            let mut busy = Duration::ZERO;
+            let paced = !self.tx_gap.is_zero();
+            while !data.is_empty() {
+                let chunk = if paced { &data[..1] } else { data };
+                match self.with_uart(|uart| uart.write(chunk)) {
+                    Ok(n) => {
+                        stats::add_tx(n);
+                        data = &data[n..];
+                        if paced && n > 0 {
+                            self.pace(&mut busy);
+                        }
+                    }
+                    Err(e) => {
+                        self.line_error(LineError::Write);
+                        if is_transient(e) && retries < self.tx_retries {
+                            retries += 1;
+                            debug!("UART write error, retry {}/{}: {}", retries, self.tx_retries, e);
+                            thread::sleep(self.tx_retry);
+                            continue;
+                        }
+                        error!("UART write error, dropped {} bytes after {} retries: {}", data.len(), retries, e);
+                        if !is_transient(e) {
+                            self.driver_failed(e);
+                        }
+                        self.fail_pending(e);
+                        break;
+                    }
+                }
+            }
+            self.tx_queued.fetch_sub(1, Ordering::Relaxed);
+            self.tx_bytes.fetch_sub(entry.len(), Ordering::Relaxed);
+        }
+    }
+
+    // Waits for the byte just written to leave, then for tx_gap
+    fn pace(&self, busy: &mut Duration) {
+        if let Err(e) = self.with_uart(|uart| uart.wait_tx_done(TickType::from(TX_BYTE_DONE).ticks())) {
+            debug!("UART TX not done before pacing gap: {}", e);
+        }
+        if self.tx_gap >= PACE_TICK {
+            thread::sleep(self.tx_gap);
+            return;
+        }
+        Ets::delay_us(self.tx_gap.as_micros() as u32);
+        *busy += self.tx_gap;
+        if *busy >= PACE_BUSY {
+            thread::sleep(PACE_TICK);
+            *busy = Duration::ZERO;
+        }
+    }
+
+    // The driver, or INVALID_STATE while it is being replaced
+    fn with_uart<T>(&self, f: impl FnOnce(&UartDriver<'static>) -> Result<T, EspError>) -> Result<T, EspError> {
+        match self.uart.read().unwrap().as_ref() {
+            Some(uart) => f(uart),
+            None => Err(EspError::from_infallible::<{ sys::ESP_ERR_INVALID_STATE as i32 }>()),
+        }
+    }
+
+    // Hands the driver to the reader for reinitializing, if that is on
+    fn driver_failed(&self, e: EspError) {
+        if !self.reinit_max.is_zero() && !self.broken.swap(true, Ordering::Relaxed) {
+            error!("UART driver failed ({}), reinitializing it", e);
+        }
+    }
+
+    // Tears the driver down and sets it up again as at boot. Called from the
+    // reader, so only the writer and event threads have to let go of it.
+    fn reinit(&self) -> Result<(), EspError> {
+        let mut driver = self.uart.write().unwrap();
+        // The old driver goes first, which frees the peripheral and its pins
+        // for the new one
+        drop(driver.take());
+        // SAFETY: main() gave UART1, GPIO17 and GPIO18 to the driver just
+        // dropped and to nothing else, and the write guard keeps every other
+        // user of the driver out until the new one is in place, so these are
+        // the only handles to them.
+        let (port, tx, rx) = unsafe { (uart::UART1::new(), gpio::Gpio17::new(), gpio::Gpio18::new()) };
+        *driver = Some(open(port, tx, rx)?);
+        drop(driver);
+
+        self.broken.store(false, Ordering::Relaxed);
+        // Whatever was half read belongs to the old driver
+        self.flush_rx.store(true, Ordering::Relaxed);
+        stats::add_uart_reinit();
+        warn!("UART driver reinitialized ({} since boot)", stats::session().uart_reinits);
+        Ok(())
+    }
+
+    // Broken up so stop() doesn't wait out a long backoff
+    fn sleep_unless_stopping(&self, total: Duration) {
+        let until = Instant::now() + total;
+        while !self.stopping.load(Ordering::Relaxed) {
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            thread::sleep(left.min(WRITER_POLL));
+        }
+    }
+
+    // A dropped write fails the exchange waiting on it right away instead of
+    // leaving it to time out. Raw writes are refused during an exchange, so
+    // the data was almost certainly its request.
+    fn fail_pending(&self, e: EspError) {
+        if let Some(p) = self.pending.lock().unwrap().take() {
+            let _ = p.reply.try_send(Err(ApiError::Uart(e)));
+        }
+    }
+
+    fn read_loop(&self) {
+        let mut buf = Vec::with_capacity(protocol::MAX_FRAME);
+        let mut chunk = [0u8; 256];
+        let mut read_errors = 0;
+        let mut backoff = REINIT_FIRST;
+
+        while !self.stopping.load(Ordering::Relaxed) {
+            if self.broken.load(Ordering::Relaxed) {
+                self.sleep_unless_stopping(backoff);
+                match self.reinit() {
+                    Ok(()) => backoff = REINIT_FIRST,
+                    Err(e) => {
+                        backoff = (backoff * 2).min(self.reinit_max);
+                        error!("UART reinit failed, next try in {:?}: {}", backoff, e);
+                    }
+                }
+                read_errors = 0;
+                continue;
+            }
+
+            if self.flush_rx.swap(false, Ordering::Relaxed) {
+                buf.clear();
+                if let Err(e) = self.with_uart(|uart| uart.clear_rx()) {
+                    warn!("UART flush failed: {}", e);
+                }
+            }
+
+            // Returns once the chunk is full or RX has been quiet for rx_idle,
+            // so a trailing partial line goes out after at most that long
+            let n = match self.with_uart(|uart| uart.read(&mut chunk, TickType::from(self.rx_idle).ticks())) {
+                Ok(n) => {
+                    read_errors = 0;
+                    n
+                }
+                Err(e) => {
+                    error!("UART read error: {}", e);
+                    self.line_error(LineError::Read);
+                    read_errors += 1;
+                    if !is_transient(e) || read_errors >= FATAL_READ_ERRORS {
+                        self.driver_failed(e);
+                    }
+                    thread::sleep(READ_RETRY);
+                    continue;
+                }
+            };
+            if n == 0 {
+                continue;
+            }
+            stats::add_rx(n);
+
+            let mut taps = self.taps.lock().unwrap();
+            self.remember(&chunk[..n]);
+            taps.retain_mut(|(_, tap)| tap(&chunk[..n]));
+            drop(taps);
+
+            buf.extend_from_slice(&chunk[..n]);
+            loop {
+                match protocol::parse_frame(&buf) {
+                    Parsed::Incomplete => break,
+                    Parsed::Invalid => {
+                        buf.remove(0);
+                    }
+                    Parsed::Frame(frame, used) => {
+                        buf.drain(..used);
+                        self.deliver(frame);
+                    }
+                }
+            }
+            self.count_pending(n);
+        }
+    }
+
+    // Bytes that came in while an exchange still waits count against
+    // cmd_rx_max, so a Flipper streaming output that never holds the
+    // response ends the exchange before its timeout does
+    fn count_pending(&self, n: usize) {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(p) = pending.as_mut() else {
+            return;
+        };
+        p.received += n;
+        if p.received > self.cmd_rx_max {
+            let _ = p.reply.try_send(Err(ApiError::Overrun(self.cmd_rx_max)));
+            pending.take();
+        }
+    }
+
+    fn remember(&self, data: &[u8]) {
+        if self.rx_history_len == 0 {
+            return;
+        }
+        let mut history = self.rx_history.lock().unwrap();
+        history.extend(data);
+        let excess = history.len().saturating_sub(self.rx_history_len);
+        history.drain(..excess);
+    }
+
+    fn deliver(&self, frame: Frame) {
+        if self.tracing() {
+            info!("frame {}", trace_line("rx", &frame));
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        match pending.as_ref() {
+            Some(p) if p.seq == frame.seq && p.cmd == frame.cmd => {
+                let _ = p.reply.try_send(Ok(frame));
+                pending.take();
+            }
+            _ => debug!("Unclaimed frame seq={} cmd=0x{:02x}", frame.seq, frame.cmd),
+        }
+    }
+}
+
+// uart_write_bytes fails with INVALID_STATE or INVALID_ARG when the driver
+// isn't installed, which retrying won't fix. Anything else is retried.
+fn is_transient(e: EspError) -> bool {
+    !matches!(e.code() as u32, sys::ESP_ERR_INVALID_STATE | sys::ESP_ERR_INVALID_ARG)
+}
+
+// One frame as a JSON object. Short payloads are shown as text when they
+// are printable (NUL separators allowed) and as hex otherwise; longer ones
+// only by size, which keeps captures and file data out of the log.
+fn trace_line(direction: &str, frame: &Frame) -> String {
+    let mut line = serde_json::json!({
+        "dir": direction,
+        "seq": frame.seq,
+        "cmd": protocol::cmd_name(frame.cmd).map_or_else(|| format!("0x{:02x}", frame.cmd), String::from),
+    });
+    // Requests always carry status 0, so it is only shown on responses
+    if direction == "rx" {
+        line["status"] =
+            protocol::status_name(frame.status).map_or_else(|| format!("0x{:02x}", frame.status), String::from).into();
+    }
+
+    let payload = &frame.payload;
+    if payload.len() > TRACE_INLINE {
+        line["bytes"] = payload.len().into();
+    } else if payload.iter().all(|&b| b == 0 || (0x20..0x7f).contains(&b)) {
+        line["text"] = String::from_utf8_lossy(payload).into_owned().into();
+    } else {
+        line["hex"] = payload.iter().map(|b| format!("{:02x}", b)).collect::<String>().into();
+    }
+    line.to_string()
+}
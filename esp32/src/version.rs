@@ -0,0 +1,21 @@
+// Which firmware is running. The version comes from Cargo.toml; the git hash
+// and build time are set by build.rs.
+
+use serde::Serialize;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+// Short hash, "-dirty" if built with uncommitted changes, "unknown" outside git
+pub const GIT_HASH: &str = env!("ECCO_GIT_HASH");
+// UTC, RFC 3339
+pub const BUILD_TIME: &str = env!("ECCO_BUILD_TIME");
+
+#[derive(Serialize)]
+pub struct Version {
+    pub version: &'static str,
+    pub git: &'static str,
+    pub built: &'static str,
+}
+
+pub fn current() -> Version {
+    Version { version: VERSION, git: GIT_HASH, built: BUILD_TIME }
+}
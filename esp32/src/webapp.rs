@@ -4,3 +4,13 @@
 pub const INDEX_HTML: &str = include_str!("../../webapp/index.html");
 pub const APP_JS: &str = include_str!("../../webapp/app.js");
 pub const STYLE_CSS: &str = include_str!("../../webapp/style.css");
+pub const SW_JS: &str = include_str!("../../webapp/sw.js");
+
+// The page's <base href>, which every webapp URL is relative to
+const BASE_HREF: &str = r#"<base href="/">"#;
+
+// index.html with its base pointed at `base_path`, for a reverse proxy that
+// mounts the bridge under a prefix
+pub fn index_html(base_path: &str) -> String {
+    INDEX_HTML.replacen(BASE_HREF, &format!(r#"<base href="{}">"#, base_path), 1)
+}
@@ -0,0 +1,482 @@
+use anyhow::Result;
+use esp_idf_hal::modem::Modem;
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_svc::eventloop::{EspEvent, EspSystemEventLoop, EspSystemSubscription};
+use esp_idf_svc::handle::RawHandle;
+use esp_idf_svc::ipv4;
+use esp_idf_svc::netif::{EspNetif, NetifConfiguration, NetifStack};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::wifi::{AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration, EspWifi, WifiDriver};
+use esp_idf_sys::{self as sys, esp};
+use log::*;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, PowerSave, Station};
+use crate::protocol;
+
+pub const WIFI_SSID: &str = "Ecco";
+pub const WIFI_PASS: &str = "eccoflip";
+
+// Non-overlapping 2.4GHz channels tried by ap_channel=0
+const AUTO_CHANNELS: [u8; 3] = [1, 6, 11];
+// 20MHz channels this many apart or more don't overlap
+const CHANNEL_SPREAD: u8 = 5;
+// A station connect that hasn't got an address by then counts as failed
+const CONNECT_WAIT: Duration = Duration::from_secs(15);
+const LINK_POLL: Duration = Duration::from_millis(500);
+const STATION_STACK: usize = 6144;
+// How often the idle watch looks for stations, and for a wake request
+const IDLE_POLL: Duration = Duration::from_secs(1);
+const IDLE_STACK: usize = 3072;
+
+// Where the board is on the network, as reported by /api/status
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    // Only the board's own AP, no station configured
+    Ap,
+    Connecting,
+    Connected,
+    // Station kept failing; the AP is up while the upstream is retried
+    FallbackAp,
+    // AP stopped after ap_idle_min without stations; the button wakes it
+    ApSleeping,
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Ap => "ap",
+            State::Connecting => "connecting",
+            State::Connected => "connected",
+            State::FallbackAp => "fallback-ap",
+            State::ApSleeping => "ap-sleeping",
+        }
+    }
+}
+
+static STATE: AtomicU8 = AtomicU8::new(State::Ap as u8);
+// Set by wake_ap(), picked up by the idle watch
+static WAKE: AtomicBool = AtomicBool::new(false);
+
+pub fn state() -> State {
+    match STATE.load(Ordering::Relaxed) {
+        1 => State::Connecting,
+        2 => State::Connected,
+        3 => State::FallbackAp,
+        4 => State::ApSleeping,
+        _ => State::Ap,
+    }
+}
+
+fn set_state(state: State) {
+    STATE.store(state as u8, Ordering::Relaxed);
+}
+
+// None when the station manager thread owns the driver
+pub fn setup_wifi(
+    modem: impl Peripheral<P = Modem> + 'static,
+    sysloop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+    config: &Config,
+) -> Result<Option<EspWifi<'static>>> {
+    let driver = WifiDriver::new(modem, sysloop, Some(nvs))?;
+
+    // The AP netif has to be built by hand to get a non-default subnet
+    let ap_netif = EspNetif::new_with_conf(&NetifConfiguration {
+        ip_configuration: ipv4::Configuration::Router(ipv4::RouterConfiguration {
+            subnet: ipv4::Subnet {
+                gateway: config.ap_ip,
+                mask: ipv4::Mask(config.ap_prefix),
+            },
+            dhcp_enabled: true,
+            // Handed out by DHCP; the AP itself when the captive portal is on
+            dns: config.dhcp_dns,
+            secondary_dns: None,
+        }),
+        ..NetifConfiguration::wifi_default_router()
+    })?;
+    set_dhcp_range(&ap_netif, config.dhcp_start, config.dhcp_end)?;
+    set_dhcp_lease(&ap_netif, config.dhcp_lease)?;
+
+    let mut wifi = EspWifi::wrap_all(driver, EspNetif::new(NetifStack::Sta)?, ap_netif)?;
+
+    let channel = match config.ap_channel {
+        0 => pick_channel(&mut wifi)?,
+        channel => channel,
+    };
+
+    let ap_config = AccessPointConfiguration {
+        ssid: WIFI_SSID.try_into().unwrap(),
+        password: WIFI_PASS.try_into().unwrap(),
+        auth_method: AuthMethod::WPA2Personal,
+        channel,
+        max_connections: 4,
+        ..Default::default()
+    };
+
+    if let Some(station) = &config.station {
+        if config.ap_idle.is_some() {
+            warn!("ap_idle_min only applies without station mode, ignoring it");
+        }
+        start_station(wifi, station.clone(), ap_config, config.power_save)?;
+        return Ok(None);
+    }
+    if config.power_save.is_some() {
+        warn!("wifi_ps only applies in station mode, ignoring it");
+    }
+
+    wifi.set_configuration(&Configuration::AccessPoint(ap_config))?;
+    wifi.start()?;
+
+    info!(
+        "AP network {}/{} on channel {}, DHCP {}-{} for {} min",
+        config.ap_ip,
+        config.ap_prefix,
+        channel,
+        config.dhcp_start,
+        config.dhcp_end,
+        config.dhcp_lease.as_secs() / 60
+    );
+    if let Some(dns) = config.dhcp_dns {
+        info!("DHCP advertises DNS server {}", dns);
+    }
+    if let Some(idle) = config.ap_idle {
+        if config.button_gpio.is_none() {
+            warn!("ap_idle_min is set but button_gpio is off; only a reset wakes the AP");
+        }
+        watch_idle(idle)?;
+    }
+
+    Ok(Some(wifi))
+}
+
+// Wakes a sleeping AP; false if it wasn't asleep
+pub fn wake_ap() -> bool {
+    if state() != State::ApSleeping {
+        return false;
+    }
+    WAKE.store(true, Ordering::Relaxed);
+    true
+}
+
+// Stops the radio once no station has been associated for `idle`. Without
+// station mode the AP is all the WiFi does, so this powers down all of it.
+// The driver stays initialized, so waking is just a start.
+fn watch_idle(idle: Duration) -> Result<()> {
+    thread::Builder::new()
+        .name("wifi-idle".into())
+        .stack_size(IDLE_STACK)
+        .spawn(move || {
+            let mut last_seen = Instant::now();
+            loop {
+                thread::sleep(IDLE_POLL);
+                if state() == State::ApSleeping {
+                    if !WAKE.swap(false, Ordering::Relaxed) {
+                        continue;
+                    }
+                    match esp!(unsafe { sys::esp_wifi_start() }) {
+                        Ok(()) => {
+                            set_state(State::Ap);
+                            info!("AP {} woken up", WIFI_SSID);
+                        }
+                        Err(e) => error!("Waking the AP failed: {}", e),
+                    }
+                    last_seen = Instant::now();
+                    continue;
+                }
+
+                if ap_clients().is_ok_and(|clients| !clients.is_empty()) {
+                    last_seen = Instant::now();
+                } else if last_seen.elapsed() >= idle {
+                    match esp!(unsafe { sys::esp_wifi_stop() }) {
+                        Ok(()) => {
+                            set_state(State::ApSleeping);
+                            info!("No stations for {:?}, AP {} sleeping until the button is pressed", idle, WIFI_SSID);
+                        }
+                        Err(e) => {
+                            warn!("Stopping the idle AP failed: {}", e);
+                            last_seen = Instant::now();
+                        }
+                    }
+                }
+            }
+        })?;
+
+    info!("AP sleeps after {:?} without stations", idle);
+    Ok(())
+}
+
+// Join the upstream network and keep it joined. After `retries` failed
+// connects in a row the board's own AP comes up next to the station, so it
+// stays reachable, and the upstream is tried every `fallback_retry`. Once
+// that works the AP goes away again.
+fn start_station(
+    mut wifi: EspWifi<'static>,
+    station: Station,
+    ap: AccessPointConfiguration,
+    power_save: Option<PowerSave>,
+) -> Result<()> {
+    let client = ClientConfiguration {
+        ssid: station.ssid.as_str().try_into().unwrap(),
+        password: station.password.as_str().try_into().unwrap(),
+        auth_method: if station.password.is_empty() { AuthMethod::None } else { AuthMethod::WPA2Personal },
+        ..Default::default()
+    };
+    wifi.set_configuration(&Configuration::Client(client.clone()))?;
+    wifi.start()?;
+    if let Some(mode) = power_save {
+        set_power_save(mode)?;
+    }
+    set_state(State::Connecting);
+    info!("Joining {} as a station", station.ssid);
+
+    thread::Builder::new()
+        .name("wifi-station".into())
+        .stack_size(STATION_STACK)
+        .spawn(move || {
+            let mut failures: u8 = 0;
+            loop {
+                match connect(&mut wifi) {
+                    Ok(ip) => {
+                        failures = 0;
+                        if state() == State::FallbackAp {
+                            info!("{} is back, stopping the fallback AP", station.ssid);
+                            if let Err(e) = wifi.set_configuration(&Configuration::Client(client.clone())) {
+                                warn!("Stopping the fallback AP failed: {}", e);
+                            }
+                        }
+                        set_state(State::Connected);
+                        info!("Connected to {} as {}", station.ssid, ip);
+
+                        while wifi.is_connected().unwrap_or(false) {
+                            thread::sleep(LINK_POLL);
+                        }
+                        warn!("Lost the connection to {}", station.ssid);
+                        set_state(State::Connecting);
+                        continue;
+                    }
+                    Err(e) => {
+                        failures = failures.saturating_add(1);
+                        warn!("Joining {} failed ({}), {} in a row", station.ssid, e, failures);
+                    }
+                }
+
+                if state() == State::Connecting && failures >= station.retries {
+                    warn!("Starting the fallback AP {} after {} failed connects", WIFI_SSID, failures);
+                    match wifi.set_configuration(&Configuration::Mixed(client.clone(), ap.clone())) {
+                        Ok(()) => set_state(State::FallbackAp),
+                        Err(e) => error!("Starting the fallback AP failed: {}", e),
+                    }
+                }
+
+                let pause = match state() {
+                    State::FallbackAp => station.fallback_retry,
+                    _ => station.retry_interval,
+                };
+                thread::sleep(pause);
+            }
+        })?;
+
+    Ok(())
+}
+
+// Takes effect once started; the AP side never sleeps
+fn set_power_save(mode: PowerSave) -> Result<()> {
+    let ps = match mode {
+        PowerSave::None => sys::wifi_ps_type_t_WIFI_PS_NONE,
+        PowerSave::Min => sys::wifi_ps_type_t_WIFI_PS_MIN_MODEM,
+        PowerSave::Max => sys::wifi_ps_type_t_WIFI_PS_MAX_MODEM,
+    };
+    esp!(unsafe { sys::esp_wifi_set_ps(ps) })?;
+    info!("WiFi power save {:?}", mode);
+    Ok(())
+}
+
+// Address from the upstream DHCP server once associated
+fn connect(wifi: &mut EspWifi<'static>) -> Result<Ipv4Addr> {
+    wifi.connect()?;
+
+    let started = Instant::now();
+    while !wifi.is_up()? {
+        if started.elapsed() > CONNECT_WAIT {
+            let _ = wifi.disconnect();
+            anyhow::bail!("no address after {:?}", CONNECT_WAIT);
+        }
+        thread::sleep(LINK_POLL);
+    }
+    Ok(wifi.sta_netif().get_ip_info()?.ip)
+}
+
+// Scan as a station and take the auto channel that nearby APs crowd least.
+// Each AP counts by how strong it is and how much its channel overlaps.
+fn pick_channel(wifi: &mut EspWifi<'static>) -> Result<u8> {
+    wifi.set_configuration(&Configuration::Client(Default::default()))?;
+    wifi.start()?;
+    let scan = wifi.scan();
+    wifi.stop()?;
+
+    let aps = match scan {
+        Ok(aps) => aps,
+        Err(e) => {
+            warn!("Channel scan failed ({}), using channel {}", e, AUTO_CHANNELS[1]);
+            return Ok(AUTO_CHANNELS[1]);
+        }
+    };
+
+    let load = |channel: u8| -> u32 {
+        aps.iter()
+            .map(|ap| {
+                let overlap = CHANNEL_SPREAD.saturating_sub(ap.channel.abs_diff(channel)) as u32;
+                // -100dBm is at the noise floor, -30dBm is right next to us
+                let strength = (ap.signal_strength as i32 + 100).clamp(0, 70) as u32;
+                overlap * strength
+            })
+            .sum()
+    };
+
+    let channel = AUTO_CHANNELS.into_iter().min_by_key(|&channel| load(channel)).unwrap();
+    info!("Scanned {} APs, picked channel {}", aps.len(), channel);
+    Ok(channel)
+}
+
+// Channel the AP is on right now
+pub fn ap_channel() -> Result<u8, sys::EspError> {
+    let mut primary = 0;
+    let mut second = 0;
+    esp!(unsafe { sys::esp_wifi_get_channel(&mut primary, &mut second) })?;
+    Ok(primary)
+}
+
+// Must run before the DHCP server starts, i.e. before wifi.start()
+fn set_dhcp_range(netif: &EspNetif, start: Ipv4Addr, end: Ipv4Addr) -> Result<()> {
+    let mut lease = sys::dhcps_lease_t {
+        enable: true,
+        start_ip: sys::ip4_addr_t { addr: u32::from(start).to_be() },
+        end_ip: sys::ip4_addr_t { addr: u32::from(end).to_be() },
+    };
+
+    esp!(unsafe {
+        sys::esp_netif_dhcps_option(
+            netif.handle(),
+            sys::esp_netif_dhcp_option_mode_t_ESP_NETIF_OP_SET,
+            sys::esp_netif_dhcp_option_id_t_ESP_NETIF_REQUESTED_IP_ADDRESS,
+            &mut lease as *mut _ as *mut _,
+            core::mem::size_of_val(&lease) as u32,
+        )
+    })?;
+
+    Ok(())
+}
+
+// The IDF DHCP server counts lease time in minutes
+fn set_dhcp_lease(netif: &EspNetif, lease: std::time::Duration) -> Result<()> {
+    let mut minutes = (lease.as_secs() / 60) as u32;
+
+    esp!(unsafe {
+        sys::esp_netif_dhcps_option(
+            netif.handle(),
+            sys::esp_netif_dhcp_option_mode_t_ESP_NETIF_OP_SET,
+            sys::esp_netif_dhcp_option_id_t_ESP_NETIF_IP_ADDRESS_LEASE_TIME,
+            &mut minutes as *mut _ as *mut _,
+            core::mem::size_of_val(&minutes) as u32,
+        )
+    })?;
+
+    Ok(())
+}
+
+// Kick stations on the blocklist as soon as they associate. The IDF AP has no
+// MAC filter, so this is the earliest point they can be turned away.
+pub fn enforce_blocklist(
+    sysloop: &EspSystemEventLoop,
+    blocked: Vec<[u8; 6]>,
+) -> Result<Option<EspSystemSubscription<'static>>> {
+    if blocked.is_empty() {
+        return Ok(None);
+    }
+    info!("Blocking {} MAC(s) on the AP", blocked.len());
+
+    let subscription = sysloop.subscribe::<EspEvent, _>(move |event| {
+        if event.source.as_ptr() != unsafe { sys::WIFI_EVENT }
+            || event.event_id != sys::wifi_event_t_WIFI_EVENT_AP_STACONNECTED as i32
+        {
+            return;
+        }
+
+        let connected = unsafe { event.as_payload::<sys::wifi_event_ap_staconnected_t>() };
+        if blocked.contains(&connected.mac) {
+            warn!("Refusing blocked station {}", format_mac(&connected.mac));
+            if let Err(e) = esp!(unsafe { sys::esp_wifi_deauth_sta(connected.aid as u16) }) {
+                warn!("Deauth of {} failed: {}", format_mac(&connected.mac), e);
+            }
+        }
+    })?;
+
+    Ok(Some(subscription))
+}
+
+// Deauthenticate one station. Returns false if it isn't associated.
+pub fn kick(mac: &[u8; 6]) -> Result<bool, sys::EspError> {
+    if !ap_clients()?.iter().any(|client| client.mac == *mac) {
+        return Ok(false);
+    }
+
+    let mut aid = 0u16;
+    esp!(unsafe { sys::esp_wifi_ap_get_sta_aid(mac.as_ptr(), &mut aid) })?;
+    esp!(unsafe { sys::esp_wifi_deauth_sta(aid) })?;
+    info!("Kicked station {}", format_mac(mac));
+    Ok(true)
+}
+
+pub struct Client {
+    pub mac: [u8; 6],
+    // None until the DHCP server has handed out a lease
+    pub ip: Option<Ipv4Addr>,
+    pub rssi: i8,
+}
+
+// Stations associated with the AP right now
+pub fn ap_clients() -> Result<Vec<Client>, sys::EspError> {
+    let mut stations = sys::wifi_sta_list_t::default();
+    esp!(unsafe { sys::esp_wifi_ap_get_sta_list(&mut stations) })?;
+
+    let mut leases = sys::esp_netif_sta_list_t::default();
+    esp!(unsafe { sys::esp_netif_get_sta_list(&stations, &mut leases) })?;
+
+    let stations = &stations.sta[..stations.num as usize];
+    let leases = &leases.sta[..leases.num as usize];
+
+    Ok(stations
+        .iter()
+        .map(|sta| {
+            let ip = leases
+                .iter()
+                .find(|lease| lease.mac == sta.mac)
+                .map(|lease| Ipv4Addr::from(u32::from_be(lease.ip.addr)))
+                .filter(|ip| !ip.is_unspecified());
+            Client { mac: sta.mac, ip, rssi: sta.rssi }
+        })
+        .collect())
+}
+
+pub fn format_mac(mac: &[u8; 6]) -> String {
+    mac.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":")
+}
+
+// aa:bb:cc:dd:ee:ff, either case, ':' or '-' separated
+pub fn parse_mac(s: &str) -> Option<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    let mut parts = s.trim().split([':', '-']);
+
+    for byte in mac.iter_mut() {
+        let &[high, low] = parts.next()?.as_bytes() else {
+            return None;
+        };
+        *byte = protocol::hex_digit(high)? << 4 | protocol::hex_digit(low)?;
+    }
+
+    parts.next().is_none().then_some(mac)
+}
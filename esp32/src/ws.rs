@@ -0,0 +1,743 @@
+// WebSocket endpoint. The client picks a behavior when it connects with
+// /ws?protocol=<name>:
+//   ecco-raw  bytes pass straight through to and from the UART (default),
+//             starting with the Flipper's last raw_history bytes
+//   ecco-rpc  each binary message is one Ecco frame, answered with the
+//             Flipper's response frame under the client's SEQ
+//   ecco-log  text stream of the ESP32's own log lines
+// The IDF server completes the handshake before we see the request and can
+// only echo one fixed Sec-WebSocket-Protocol per URI, so the choice is made
+// with a query parameter and unknown names are closed straight away.
+// Likewise, when a ws_token is configured it is checked from ?token= right
+// after the handshake, and a wrong or missing one closes the socket. A
+// client holding the UART lock passes its key as ?lock=.
+// On any of them, a text message holding a JSON object with a "ctl" member
+// is a control message instead of data: it can read the status, switch the
+// session to another protocol or subscribe to events, all on the one socket.
+// Replies are text messages with the same "ctl".
+// /ws/echo sends every message straight back, never touching the UART.
+// httpd sends every client's frames from its one task, so a client that
+// stops reading would hold up all the others. Each socket gets a short send
+// timeout instead, and a client whose send fails or times out is closed;
+// ecco-raw clients that keep falling behind can be closed too.
+// With the frame debug on, every frame sent is logged with its opcode, FIN
+// bit and length; log streams are left out, as each line would log another.
+
+use anyhow::Result;
+use esp_idf_svc::http::server::ws::{EspHttpWsConnection, EspHttpWsDetachedSender};
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::http::Method;
+use esp_idf_svc::ws::FrameType;
+use esp_idf_sys as sys;
+use log::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::mem::ManuallyDrop;
+use std::net::TcpStream;
+use std::os::fd::FromRawFd;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::events::{self, Event};
+use crate::http::{self, query_param};
+use crate::logger;
+use crate::protocol::{self, status, Frame, Parsed};
+use crate::uart::Bridge;
+
+pub const PROTOCOLS: &[&str] = &["ecco-raw", "ecco-rpc", "ecco-log"];
+// What a session can subscribe to with a "subscribe" control message
+pub const EVENTS: &[&str] = &["log"];
+
+// Close code for a protocol we don't speak (RFC 6455 "protocol error")
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+// Close code for a missing or wrong token (RFC 6455 "policy violation")
+const CLOSE_POLICY_VIOLATION: u16 = 1008;
+// Close code for a message over ws_max_message (RFC 6455 "message too big")
+const CLOSE_TOO_BIG: u16 = 1009;
+// Close code for a client over ws_max_clients (RFC 6455 "try again later")
+const CLOSE_TRY_AGAIN: u16 = 1013;
+// Close code sent to everyone before a planned restart (RFC 6455 "going away")
+const CLOSE_GOING_AWAY: u16 = 1001;
+// Messages queued per client before raw bytes are dropped
+const SEND_QUEUE: usize = 8;
+// ecco-rpc requests waiting for the UART before new ones get ERR_BUSY
+const RPC_QUEUE: usize = 4;
+const PUMP_STACK: usize = 4096;
+const RPC_STACK: usize = 6144;
+
+// Open sessions of any protocol; echo sessions aren't counted
+static CLIENTS: AtomicUsize = AtomicUsize::new(0);
+// ecco-rpc jobs waiting for the worker, for /api/status
+static RPC_QUEUED: AtomicUsize = AtomicUsize::new(0);
+// A sender per open session, so close_all() can reach them from outside httpd
+static CLOSERS: Mutex<Vec<EspHttpWsDetachedSender>> = Mutex::new(Vec::new());
+// Log every frame sent, from ws_frame_debug and /api/debug/ws-frames
+static FRAME_DEBUG: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Raw,
+    Rpc,
+    Log,
+}
+
+impl Mode {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ecco-raw" => Some(Mode::Raw),
+            "ecco-rpc" => Some(Mode::Rpc),
+            "ecco-log" => Some(Mode::Log),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Mode::Raw => "ecco-raw",
+            Mode::Rpc => "ecco-rpc",
+            Mode::Log => "ecco-log",
+        }
+    }
+}
+
+// Text messages with a "ctl" member; see the top of this file
+#[derive(Deserialize)]
+#[serde(tag = "ctl", rename_all = "lowercase")]
+enum Control {
+    Status,
+    Mode { mode: String },
+    Subscribe { events: Vec<String> },
+    Unsubscribe { events: Vec<String> },
+}
+
+struct Session {
+    mode: Mode,
+    // Cleared when httpd reports the session closed
+    alive: Arc<AtomicBool>,
+    // Raw sessions are fed by a UART tap
+    tap: Option<u32>,
+    // UART lock key from ?lock=, for a client that holds the lock
+    lock: Option<String>,
+    // Log streams, as ecco-log lines or as "log" events; clearing the flag
+    // stops the stream at its next line
+    log: Option<Arc<AtomicBool>>,
+    log_events: Option<Arc<AtomicBool>>,
+}
+
+struct RpcJob {
+    request: Frame,
+    sender: EspHttpWsDetachedSender,
+    hangup: Hangup,
+    lock: Option<String>,
+}
+
+// Closes one session from outside httpd, e.g. after a send to it failed
+#[derive(Clone)]
+struct Hangup {
+    server: sys::httpd_handle_t,
+    fd: i32,
+    alive: Arc<AtomicBool>,
+}
+
+// The handle is only passed back to httpd, which queues the close on its task
+unsafe impl Send for Hangup {}
+
+impl Hangup {
+    fn new(ws: &EspHttpWsConnection, alive: Arc<AtomicBool>) -> Option<Self> {
+        match ws {
+            EspHttpWsConnection::New(server, _) | EspHttpWsConnection::Receiving(server, _, _) => {
+                Some(Self { server: *server, fd: ws.session(), alive })
+            }
+            EspHttpWsConnection::Closed(_) => None,
+        }
+    }
+
+    // Skipped once the session is gone, so a new client that got the same
+    // socket number isn't closed instead
+    fn close(&self, why: &str) {
+        if self.alive.swap(false, Ordering::Relaxed) {
+            warn!("WebSocket {} closed: {}", self.fd, why);
+            unsafe { sys::httpd_sess_trigger_close(self.server, self.fd) };
+        }
+    }
+}
+
+pub fn register(server: &mut EspHttpServer<'static>, bridge: Arc<Bridge>, config: &Config) -> Result<()> {
+    let token = config.ws_token.clone();
+    let origins = config.ws_origins.clone();
+    let max_message = config.ws_max_message;
+    let send_timeout = config.ws_send_timeout;
+    let slow_drops = config.ws_slow_drops;
+    let max_clients = config.ws_max_clients;
+    FRAME_DEBUG.store(config.ws_frame_debug, Ordering::Relaxed);
+    let sessions: Mutex<HashMap<i32, Session>> = Mutex::new(HashMap::new());
+    let rpc = start_rpc_worker(bridge.clone())?;
+
+    server.ws_handler("/ws", move |ws| -> Result<()> {
+        let fd = ws.session();
+
+        if ws.is_new() {
+            // Checked first, so a page on another site learns nothing else
+            let origin = origins.as_ref().and_then(|origins| {
+                request_header(ws, c"Origin").filter(|origin| !origin_allowed(ws, origin, origins))
+            });
+            if let Some(origin) = origin {
+                warn!("WebSocket {} rejected: origin {:?} not allowed", fd, origin);
+                close(ws, CLOSE_POLICY_VIOLATION, "origin not allowed")?;
+                return Ok(());
+            }
+            let uri = request_uri(ws);
+            if !authorized(&uri, token.as_deref()) {
+                warn!("WebSocket {} rejected: bad or missing token", fd);
+                close(ws, CLOSE_POLICY_VIOLATION, "unauthorized")?;
+                return Ok(());
+            }
+            let Some(mode) = requested_mode(&uri) else {
+                close(ws, CLOSE_PROTOCOL_ERROR, "unknown protocol")?;
+                return Ok(());
+            };
+            // httpd has already answered the upgrade, so a close frame is
+            // the only way left to say no. Sessions open and close on the
+            // httpd task, so the count can't change under this check.
+            if CLIENTS.load(Ordering::Relaxed) >= max_clients {
+                warn!("WebSocket {} rejected: already {} clients", fd, max_clients);
+                close(ws, CLOSE_TRY_AGAIN, "too many clients")?;
+                return Ok(());
+            }
+            info!("WebSocket {} connected ({:?})", fd, mode);
+            set_send_timeout(fd, send_timeout);
+
+            let alive = Arc::new(AtomicBool::new(true));
+            let Some(hangup) = Hangup::new(ws, alive.clone()) else {
+                return Ok(());
+            };
+            let lock = query_param(&uri, "lock").filter(|key| !key.is_empty()).map(str::to_string);
+            let mut session = Session { mode, alive, tap: None, lock, log: None, log_events: None };
+            start_mode(ws, &mut session, &bridge, hangup, slow_drops)?;
+            sessions.lock().unwrap().insert(fd, session);
+            CLOSERS.lock().unwrap().push(ws.create_detached_sender()?);
+            CLIENTS.fetch_add(1, Ordering::Relaxed);
+            events::record(Event::ClientConnected { fd, protocol: mode.name().into() });
+            return Ok(());
+        }
+
+        if ws.is_closed() {
+            if let Some(session) = sessions.lock().unwrap().remove(&fd) {
+                session.alive.store(false, Ordering::Relaxed);
+                CLOSERS.lock().unwrap().retain(|closer| closer.session() != fd);
+                CLIENTS.fetch_sub(1, Ordering::Relaxed);
+                events::record(Event::ClientDisconnected { fd });
+                if let Some(tap) = session.tap {
+                    bridge.remove_tap(tap);
+                }
+                for stream in [session.log, session.log_events].into_iter().flatten() {
+                    stream.store(false, Ordering::Relaxed);
+                }
+            }
+            info!("WebSocket {} disconnected", fd);
+            return Ok(());
+        }
+
+        let (text, data) = match recv(ws, max_message)? {
+            Some(message) => message,
+            None => return Ok(()),
+        };
+        if let Some((id, control)) = text.then(|| control_message(&data)).flatten() {
+            let mut sessions = sessions.lock().unwrap();
+            let Some(session) = sessions.get_mut(&fd) else {
+                return Ok(());
+            };
+            let mut reply = control
+                .and_then(|control| run_control(ws, session, control, &bridge, slow_drops, max_clients))
+                .unwrap_or_else(|e| json!({ "ctl": "error", "error": e.to_string(), "code": e.code() }));
+            if let Some(id) = id {
+                reply["id"] = id;
+            }
+            send(ws, FrameType::Text(false), reply.to_string().as_bytes())?;
+            return Ok(());
+        }
+        let (mode, alive, lock) = match sessions.lock().unwrap().get(&fd) {
+            Some(session) => (session.mode, session.alive.clone(), session.lock.clone()),
+            None => return Ok(()),
+        };
+
+        match mode {
+            Mode::Raw => {
+                let sent = bridge.lock().check(lock.as_deref()).and_then(|()| bridge.write(&data));
+                if let Err(e) = sent {
+                    warn!("Dropped {} raw bytes: {}", data.len(), e);
+                }
+            }
+            Mode::Rpc => {
+                let Some(hangup) = Hangup::new(ws, alive) else {
+                    return Ok(());
+                };
+                handle_rpc(ws, &rpc, &data, hangup, lock)?
+            }
+            // Read-only stream
+            Mode::Log => {}
+        }
+
+        Ok(())
+    })?;
+    http::add_route("/ws", Method::Get);
+
+    register_echo(server, config)
+}
+
+// For checking a client's WebSocket stack on its own: each message comes
+// back with the same frame type. Echo sessions don't count as clients.
+fn register_echo(server: &mut EspHttpServer<'static>, config: &Config) -> Result<()> {
+    let token = config.ws_token.clone();
+    let max_message = config.ws_max_message;
+
+    server.ws_handler("/ws/echo", move |ws| -> Result<()> {
+        if ws.is_new() {
+            if !authorized(&request_uri(ws), token.as_deref()) {
+                warn!("WebSocket {} rejected: bad or missing token", ws.session());
+                close(ws, CLOSE_POLICY_VIOLATION, "unauthorized")?;
+            } else {
+                debug!("WebSocket {} echo session", ws.session());
+            }
+            return Ok(());
+        }
+        if ws.is_closed() {
+            return Ok(());
+        }
+
+        match recv(ws, max_message)? {
+            Some((true, data)) => send(ws, FrameType::Text(false), &data)?,
+            Some((false, data)) => send(ws, FrameType::Binary(false), &data)?,
+            None => {}
+        }
+        Ok(())
+    })?;
+    http::add_route("/ws/echo", Method::Get);
+
+    Ok(())
+}
+
+pub fn client_count() -> usize {
+    CLIENTS.load(Ordering::Relaxed)
+}
+
+pub fn rpc_queued() -> usize {
+    RPC_QUEUED.load(Ordering::Relaxed)
+}
+
+pub fn frame_debug() -> bool {
+    FRAME_DEBUG.load(Ordering::Relaxed)
+}
+
+pub fn set_frame_debug(on: bool) {
+    FRAME_DEBUG.store(on, Ordering::Relaxed);
+}
+
+// Say goodbye to every /ws client before a planned restart, so browsers see
+// "going away" and reconnect rather than reporting a dropped connection.
+// Detached sends wait for the httpd task, so this must not run on it.
+pub fn close_all() {
+    // Taken out first: httpd removes closed sessions under the same lock
+    let closers: Vec<_> = CLOSERS.lock().unwrap().drain(..).collect();
+    let mut frame = CLOSE_GOING_AWAY.to_be_bytes().to_vec();
+    frame.extend_from_slice(b"restarting");
+
+    for mut sender in closers {
+        if let Err(e) = send_detached(&mut sender, FrameType::Close, &frame) {
+            debug!("WebSocket {} close failed: {}", sender.session(), e);
+        }
+    }
+}
+
+// Only available on the New event
+fn request_uri(ws: &EspHttpWsConnection) -> String {
+    match ws {
+        EspHttpWsConnection::New(_, req) => {
+            unsafe { CStr::from_ptr((**req).uri.as_ptr()) }.to_string_lossy().into_owned()
+        }
+        _ => String::new(),
+    }
+}
+
+fn request_header(ws: &EspHttpWsConnection, name: &CStr) -> Option<String> {
+    let EspHttpWsConnection::New(_, req) = ws else {
+        return None;
+    };
+    let len = unsafe { sys::httpd_req_get_hdr_value_len(*req, name.as_ptr()) };
+    if len == 0 {
+        return None;
+    }
+    let mut buf = vec![0u8; len + 1];
+    let got = unsafe { sys::httpd_req_get_hdr_value_str(*req, name.as_ptr(), buf.as_mut_ptr() as *mut _, buf.len()) };
+    if got != sys::ESP_OK {
+        return None;
+    }
+    Some(CStr::from_bytes_until_nul(&buf).unwrap_or_default().to_string_lossy().into_owned())
+}
+
+// Browsers always send Origin on a WebSocket upgrade and other clients
+// usually don't, so only a page on another site's origin is turned away.
+// The page the bridge serves itself passes as long as Origin names the Host
+// the request came in on.
+fn origin_allowed(ws: &EspHttpWsConnection, origin: &str, allowed: &[String]) -> bool {
+    let origin = origin.trim_end_matches('/').to_ascii_lowercase();
+    if allowed.contains(&origin) {
+        return true;
+    }
+    let own = origin.strip_prefix("http://").or_else(|| origin.strip_prefix("https://"));
+    own.zip(request_header(ws, c"Host")).is_some_and(|(own, host)| own == host.to_ascii_lowercase())
+}
+
+fn authorized(uri: &str, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    token_matches(query_param(uri, "token").unwrap_or(""), token)
+}
+
+// Compares every byte so the time taken doesn't give away a prefix match
+pub fn token_matches(given: &str, token: &str) -> bool {
+    given.len() == token.len() && given.bytes().zip(token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn requested_mode(uri: &str) -> Option<Mode> {
+    let name = query_param(uri, "protocol").filter(|name| !name.is_empty()).unwrap_or("ecco-raw");
+
+    let mode = Mode::from_name(name);
+    if mode.is_none() {
+        warn!("WebSocket asked for unknown protocol {:?}, expected one of {:?}", name, PROTOCOLS);
+    }
+    mode
+}
+
+fn send(ws: &mut EspHttpWsConnection, frame_type: FrameType, data: &[u8]) -> Result<(), sys::EspError> {
+    debug_frame(ws.session(), frame_type, data.len());
+    ws.send(frame_type, data)
+}
+
+fn send_detached(sender: &mut EspHttpWsDetachedSender, frame_type: FrameType, data: &[u8]) -> Result<(), sys::EspError> {
+    debug_frame(sender.session(), frame_type, data.len());
+    sender.send(frame_type, data)
+}
+
+// One line per outbound frame while FRAME_DEBUG is on; a single load when off
+fn debug_frame(fd: i32, frame_type: FrameType, len: usize) {
+    if !FRAME_DEBUG.load(Ordering::Relaxed) {
+        return;
+    }
+    let (opcode, name, fin) = match frame_type {
+        FrameType::Continue(fin) => (0x0, "continuation", fin),
+        FrameType::Text(fragmented) => (0x1, "text", !fragmented),
+        FrameType::Binary(fragmented) => (0x2, "binary", !fragmented),
+        FrameType::Close | FrameType::SocketClose => (0x8, "close", true),
+        FrameType::Ping => (0x9, "ping", true),
+        FrameType::Pong => (0xa, "pong", true),
+    };
+    info!("ws frame {}", json!({ "fd": fd, "opcode": opcode, "type": name, "fin": fin, "len": len }));
+}
+
+// Send a close frame and drop the session without waiting for the reply
+fn close(ws: &mut EspHttpWsConnection, code: u16, reason: &str) -> Result<()> {
+    let mut frame = code.to_be_bytes().to_vec();
+    frame.extend_from_slice(reason.as_bytes());
+    send(ws, FrameType::Close, &frame)?;
+
+    if let EspHttpWsConnection::New(handle, _) | EspHttpWsConnection::Receiving(handle, _, _) = ws {
+        sys::esp!(unsafe { sys::httpd_sess_trigger_close(*handle, ws.session()) })?;
+    }
+    Ok(())
+}
+
+// Whole binary or text message, flagged true for text, or None for control
+// frames. A message over `max` can't be skipped without reading it, so the
+// session is closed.
+fn recv(ws: &mut EspHttpWsConnection, max: usize) -> Result<Option<(bool, Vec<u8>)>> {
+    let (frame_type, len) = ws.recv(&mut [])?;
+    if len > max {
+        warn!("WebSocket {} sent {} bytes, max {}, closing", ws.session(), len, max);
+        close(ws, CLOSE_TOO_BIG, "message too big")?;
+        return Ok(None);
+    }
+
+    let mut buf = vec![0u8; len];
+    ws.recv(&mut buf)?;
+
+    match frame_type {
+        FrameType::Binary(false) => Ok(Some((false, buf))),
+        // Text lengths include a NUL terminator
+        FrameType::Text(false) => {
+            buf.truncate(len.saturating_sub(1));
+            Ok(Some((true, buf)))
+        }
+        FrameType::Binary(true) | FrameType::Text(true) | FrameType::Continue(_) => {
+            warn!("Fragmented WebSocket messages are not supported");
+            Ok(None)
+        }
+        _ => Ok(None),
+    }
+}
+
+// `slow_drops` chunks dropped in a row close the client; 0 only drops them
+fn open_raw(ws: &EspHttpWsConnection, bridge: &Bridge, hangup: Hangup, slow_drops: u8) -> Result<u32> {
+    let queue = spawn_pump(ws.create_detached_sender()?, FrameType::Binary(false), hangup.clone())?;
+    let dropped = AtomicU32::new(0);
+
+    // Runs on the UART reader thread, so it only ever queues
+    Ok(bridge.add_tap(Box::new(move |data| match queue.try_send(data.to_vec()) {
+        Ok(()) => {
+            dropped.store(0, Ordering::Relaxed);
+            true
+        }
+        Err(TrySendError::Full(_)) => {
+            let dropped = dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!("WebSocket client too slow, dropped {} bytes", data.len());
+            if slow_drops > 0 && dropped >= slow_drops as u32 {
+                hangup.close("too slow");
+                return false;
+            }
+            true
+        }
+        Err(TrySendError::Disconnected(_)) => false,
+    })))
+}
+
+// Plain lines for ecco-log, or {"ctl": "event"} messages when `as_events`.
+// The stream runs until the returned flag is cleared or a send fails.
+fn open_log(ws: &EspHttpWsConnection, hangup: Hangup, as_events: bool) -> Result<Arc<AtomicBool>> {
+    let mut sender = ws.create_detached_sender()?;
+    let (history, lines) = logger::subscribe();
+    let active = Arc::new(AtomicBool::new(true));
+    let running = active.clone();
+
+    spawn(PUMP_STACK, move || {
+        for line in history.into_iter().chain(lines) {
+            if !running.load(Ordering::Relaxed) {
+                break;
+            }
+            let message = if as_events {
+                json!({ "ctl": "event", "event": "log", "line": line }).to_string()
+            } else {
+                line
+            };
+            // close() logs once; a warning per failed line would feed back
+            // into this stream, and so would the frame debug
+            if sender.send(FrameType::Text(false), message.as_bytes()).is_err() {
+                hangup.close("send failed");
+                break;
+            }
+        }
+    })?;
+    Ok(active)
+}
+
+// Hooks up what `session.mode` streams to the client
+fn start_mode(
+    ws: &EspHttpWsConnection,
+    session: &mut Session,
+    bridge: &Bridge,
+    hangup: Hangup,
+    slow_drops: u8,
+) -> Result<()> {
+    match session.mode {
+        Mode::Raw => session.tap = Some(open_raw(ws, bridge, hangup, slow_drops)?),
+        Mode::Log => session.log = Some(open_log(ws, hangup, false)?),
+        Mode::Rpc => {}
+    }
+    Ok(())
+}
+
+// Some(..) for a text message that is meant as a control message, whether
+// or not it is a valid one, with the client's "id" for the reply to echo
+fn control_message(data: &[u8]) -> Option<(Option<Value>, Result<Control, ApiError>)> {
+    let mut value: Value = serde_json::from_slice(data).ok()?;
+    value.get("ctl")?.as_str()?;
+    let id = value.as_object_mut()?.remove("id");
+    let control = match &id {
+        Some(Value::String(_) | Value::Number(_)) | None => serde_json::from_value(value).map_err(ApiError::from),
+        Some(_) => Err(ApiError::BadRequest("id must be a string or a number".into())),
+    };
+    Some((id, control))
+}
+
+fn run_control(
+    ws: &mut EspHttpWsConnection,
+    session: &mut Session,
+    control: Control,
+    bridge: &Bridge,
+    slow_drops: u8,
+    max_clients: usize,
+) -> Result<Value, ApiError> {
+    let internal = |e: anyhow::Error| ApiError::Internal(e.to_string());
+
+    match control {
+        Control::Status => {
+            let mut status = http::status(bridge, max_clients);
+            status["ctl"] = "status".into();
+            status["protocol"] = session.mode.name().into();
+            status["events"] = json!(subscribed(session));
+            Ok(status)
+        }
+        Control::Mode { mode: name } => {
+            let mode = Mode::from_name(&name)
+                .ok_or_else(|| ApiError::BadRequest(format!("mode must be one of {:?}", PROTOCOLS)))?;
+            if mode != session.mode {
+                let hangup = Hangup::new(ws, session.alive.clone())
+                    .ok_or_else(|| ApiError::Internal("session closed".into()))?;
+                if let Some(tap) = session.tap.take() {
+                    bridge.remove_tap(tap);
+                }
+                if let Some(stream) = session.log.take() {
+                    stream.store(false, Ordering::Relaxed);
+                }
+                info!("WebSocket {} switched from {:?} to {:?}", ws.session(), session.mode, mode);
+                session.mode = mode;
+                start_mode(ws, session, bridge, hangup, slow_drops).map_err(internal)?;
+            }
+            Ok(json!({ "ctl": "mode", "mode": session.mode.name() }))
+        }
+        Control::Subscribe { events } => {
+            check_events(&events)?;
+            if events.iter().any(|event| event == "log") && session.log_events.is_none() {
+                let hangup = Hangup::new(ws, session.alive.clone())
+                    .ok_or_else(|| ApiError::Internal("session closed".into()))?;
+                session.log_events = Some(open_log(ws, hangup, true).map_err(internal)?);
+            }
+            Ok(json!({ "ctl": "subscribe", "events": subscribed(session) }))
+        }
+        Control::Unsubscribe { events } => {
+            check_events(&events)?;
+            if events.iter().any(|event| event == "log") {
+                if let Some(stream) = session.log_events.take() {
+                    stream.store(false, Ordering::Relaxed);
+                }
+            }
+            Ok(json!({ "ctl": "unsubscribe", "events": subscribed(session) }))
+        }
+    }
+}
+
+fn check_events(events: &[String]) -> Result<(), ApiError> {
+    match events.iter().find(|event| !EVENTS.contains(&event.as_str())) {
+        Some(event) => Err(ApiError::BadRequest(format!("unknown event {:?}, expected one of {:?}", event, EVENTS))),
+        None => Ok(()),
+    }
+}
+
+fn subscribed(session: &Session) -> Vec<&'static str> {
+    session.log_events.iter().map(|_| "log").collect()
+}
+
+// Detached sends block until the server thread picks them up, so they are
+// done from a helper thread rather than from whoever produced the data
+fn spawn_pump(mut sender: EspHttpWsDetachedSender, frame_type: FrameType, hangup: Hangup) -> Result<SyncSender<Vec<u8>>> {
+    let (tx, rx): (SyncSender<Vec<u8>>, Receiver<Vec<u8>>) = mpsc::sync_channel(SEND_QUEUE);
+
+    spawn(PUMP_STACK, move || {
+        for data in rx {
+            // A send that timed out may have left half a frame on the wire,
+            // so the connection can't be used any more
+            if let Err(e) = send_detached(&mut sender, frame_type, &data) {
+                hangup.close(&format!("send failed ({})", e));
+                break;
+            }
+        }
+    })?;
+
+    Ok(tx)
+}
+
+// SO_SNDTIMEO on the session's socket; httpd sets 5s when it accepts it
+fn set_send_timeout(fd: i32, timeout: Duration) {
+    // Borrow the socket; httpd still owns and closes it
+    let socket = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(fd) });
+    if let Err(e) = socket.set_write_timeout(Some(timeout)) {
+        warn!("WebSocket {} send timeout not set: {}", fd, e);
+    }
+}
+
+fn handle_rpc(
+    ws: &mut EspHttpWsConnection,
+    rpc: &SyncSender<RpcJob>,
+    data: &[u8],
+    hangup: Hangup,
+    lock: Option<String>,
+) -> Result<()> {
+    let request = match protocol::parse_frame(data) {
+        Parsed::Frame(frame, _) => frame,
+        _ => {
+            warn!("ecco-rpc message is not a valid frame, dropping {} bytes", data.len());
+            return Ok(());
+        }
+    };
+
+    let seq = request.seq;
+    let cmd = request.cmd;
+    let job = RpcJob { request, sender: ws.create_detached_sender()?, hangup, lock };
+
+    RPC_QUEUED.fetch_add(1, Ordering::Relaxed);
+    if rpc.try_send(job).is_err() {
+        RPC_QUEUED.fetch_sub(1, Ordering::Relaxed);
+        send(ws, FrameType::Binary(false), &reply(seq, cmd, status::ERR_BUSY, Vec::new()))?;
+    }
+    Ok(())
+}
+
+// One worker runs every ecco-rpc exchange in turn, so the server thread is
+// never stuck waiting on the Flipper
+fn start_rpc_worker(bridge: Arc<Bridge>) -> Result<SyncSender<RpcJob>> {
+    let (tx, rx) = mpsc::sync_channel::<RpcJob>(RPC_QUEUE);
+
+    spawn(RPC_STACK, move || {
+        for mut job in rx {
+            RPC_QUEUED.fetch_sub(1, Ordering::Relaxed);
+            let Frame { seq, cmd, payload, .. } = job.request;
+
+            // Checked when the job runs, so a lock taken while it was queued counts
+            let result = bridge
+                .lock()
+                .check(job.lock.as_deref())
+                .and_then(|()| bridge.command(cmd, &payload, protocol::response_timeout(cmd)));
+            events::command("rpc", cmd, &result);
+            let response = match result {
+                Ok(frame) => reply(seq, cmd, frame.status, frame.payload),
+                Err(e) => {
+                    warn!("ecco-rpc 0x{:02x} failed: {}", cmd, e);
+                    reply(seq, cmd, error_status(&e), Vec::new())
+                }
+            };
+
+            if let Err(e) = send_detached(&mut job.sender, FrameType::Binary(false), &response) {
+                job.hangup.close(&format!("response not sent ({})", e));
+            }
+        }
+    })?;
+
+    Ok(tx)
+}
+
+// Response frame carrying the client's own SEQ
+fn reply(seq: u8, cmd: u8, status: u8, payload: Vec<u8>) -> Vec<u8> {
+    Frame { seq, cmd, status, payload }.encode()
+}
+
+fn error_status(e: &ApiError) -> u8 {
+    match e {
+        ApiError::BadRequest(_) => status::ERR_INVALID,
+        ApiError::Timeout => status::ERR_TIMEOUT,
+        ApiError::Busy | ApiError::Locked(_) => status::ERR_BUSY,
+        ApiError::Flipper(code) => *code,
+        _ => status::ERR_UNKNOWN,
+    }
+}
+
+fn spawn<F: FnOnce() + Send + 'static>(stack: usize, f: F) -> Result<()> {
+    thread::Builder::new().stack_size(stack).spawn(f)?;
+    Ok(())
+}
@@ -9,6 +9,7 @@
 // Commands
 #define CMD_PING           0x01
 #define CMD_DEVICE_INFO    0x02
+#define CMD_NOTIFY         0x03
 #define CMD_SUBGHZ_CAPTURE 0x10
 #define CMD_SUBGHZ_TRANSMIT 0x11
 #define CMD_NFC_READ       0x20
@@ -18,7 +19,10 @@
 #define CMD_RFID_READ      0x40
 #define CMD_STORAGE_LIST   0x50
 #define CMD_STORAGE_READ   0x51
+#define CMD_STORAGE_WRITE  0x52
+#define CMD_STORAGE_MKDIR  0x53
 #define CMD_DATA_CONTINUE  0x60
+#define CMD_GUI_SCREENSHOT 0x70
 
 // Status codes
 #define STATUS_OK          0x00
@@ -28,6 +32,8 @@
 #define STATUS_ERR_TIMEOUT 0x04
 #define STATUS_ERR_NOT_FOUND 0x05
 #define STATUS_ERR_NO_DATA 0x06
+#define STATUS_ERR_FULL    0x07
+#define STATUS_ERR_NOT_READY 0x08
 
 typedef struct {
     uint8_t seq;
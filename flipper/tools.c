@@ -5,6 +5,7 @@
 #include <furi_hal_subghz.h>
 #include <furi_hal_infrared.h>
 #include <storage/storage.h>
+#include <notification/notification_messages.h>
 #include <string.h>
 #include <lib/subghz/receiver.h>
 #include <lib/subghz/transmitter.h>
@@ -13,6 +14,13 @@
 
 #define SUBGHZ_RAW_BUF_SIZE 2048
 #define IR_RAW_BUF_SIZE 512
+#define NOTIFY_DEFAULT_MS 500
+#define NOTIFY_MAX_MS 5000
+#define NOTIFY_FLAG_VIBRATE 0x01
+#define NOTIFY_FLAG_SOUND 0x02
+// 128x64 display at one bit per pixel
+#define SCREEN_BYTES (128 * 64 / 8)
+#define SCREENSHOT_TIMEOUT_MS 1000
 
 void ecco_dispatch(EccoApp* app, const EccoFrame* req, EccoFrame* resp) {
     resp->seq = req->seq;
@@ -27,6 +35,9 @@ void ecco_dispatch(EccoApp* app, const EccoFrame* req, EccoFrame* resp) {
         case CMD_DEVICE_INFO:
             tool_device_info(app, req, resp);
             break;
+        case CMD_NOTIFY:
+            tool_notify(app, req, resp);
+            break;
         case CMD_SUBGHZ_CAPTURE:
             tool_subghz_capture(app, req, resp);
             break;
@@ -54,6 +65,15 @@ void ecco_dispatch(EccoApp* app, const EccoFrame* req, EccoFrame* resp) {
         case CMD_STORAGE_READ:
             tool_storage_read(app, req, resp);
             break;
+        case CMD_STORAGE_WRITE:
+            tool_storage_write(app, req, resp);
+            break;
+        case CMD_STORAGE_MKDIR:
+            tool_storage_mkdir(app, req, resp);
+            break;
+        case CMD_GUI_SCREENSHOT:
+            tool_gui_screenshot(app, req, resp);
+            break;
         default:
             resp->status = STATUS_ERR_INVALID;
             break;
@@ -62,8 +82,10 @@ void ecco_dispatch(EccoApp* app, const EccoFrame* req, EccoFrame* resp) {
 
 void tool_ping(EccoApp* app, const EccoFrame* req, EccoFrame* resp) {
     UNUSED(app);
-    UNUSED(req);
-    UNUSED(resp);
+
+    // Echo the payload back so the caller can verify the round trip
+    memcpy(resp->payload, req->payload, req->payload_len);
+    resp->payload_len = req->payload_len;
 }
 
 void tool_device_info(EccoApp* app, const EccoFrame* req, EccoFrame* resp) {
@@ -86,6 +108,50 @@ void tool_device_info(EccoApp* app, const EccoFrame* req, EccoFrame* resp) {
     resp->payload_len = 35;
 }
 
+void tool_notify(EccoApp* app, const EccoFrame* req, EccoFrame* resp) {
+    UNUSED(app);
+
+    if (req->payload_len < 6) {
+        resp->status = STATUS_ERR_INVALID;
+        return;
+    }
+
+    uint16_t duration = req->payload[3] | (req->payload[4] << 8);
+    uint8_t flags = req->payload[5];
+    if (duration == 0) duration = NOTIFY_DEFAULT_MS;
+    if (duration > NOTIFY_MAX_MS) duration = NOTIFY_MAX_MS;
+
+    NotificationMessage red = {.type = NotificationMessageTypeLedRed, .data.led.value = req->payload[0]};
+    NotificationMessage green = {.type = NotificationMessageTypeLedGreen, .data.led.value = req->payload[1]};
+    NotificationMessage blue = {.type = NotificationMessageTypeLedBlue, .data.led.value = req->payload[2]};
+    NotificationMessage sound = {
+        .type = NotificationMessageTypeSoundOn,
+        .data.sound.frequency = 2000.0f,
+        .data.sound.volume = 1.0f,
+    };
+    NotificationMessage delay = {.type = NotificationMessageTypeDelay, .data.delay.length = duration};
+
+    // LED on, optional vibro and beep, hold, then everything off
+    const NotificationMessage* sequence[12];
+    size_t n = 0;
+    sequence[n++] = &red;
+    sequence[n++] = &green;
+    sequence[n++] = &blue;
+    if (flags & NOTIFY_FLAG_VIBRATE) sequence[n++] = &message_vibro_on;
+    if (flags & NOTIFY_FLAG_SOUND) sequence[n++] = &sound;
+    sequence[n++] = &delay;
+    sequence[n++] = &message_red_0;
+    sequence[n++] = &message_green_0;
+    sequence[n++] = &message_blue_0;
+    if (flags & NOTIFY_FLAG_VIBRATE) sequence[n++] = &message_vibro_off;
+    if (flags & NOTIFY_FLAG_SOUND) sequence[n++] = &message_sound_off;
+    sequence[n] = NULL;
+
+    NotificationApp* notifications = furi_record_open(RECORD_NOTIFICATION);
+    notification_message_block(notifications, (const NotificationSequence*)sequence);
+    furi_record_close(RECORD_NOTIFICATION);
+}
+
 // SubGHz capture state
 typedef struct {
     EccoApp* app;
@@ -532,6 +598,28 @@ void tool_rfid_read(EccoApp* app, const EccoFrame* req, EccoFrame* resp) {
     protocol_dict_free(dict);
 }
 
+// Why a storage call failed, as an Ecco status. Paths may be on /int or
+// /ext alike; /ext gives FSE_NOT_READY when no SD card is in.
+static uint8_t storage_status(FS_Error error) {
+    switch (error) {
+        case FSE_NOT_EXIST:
+            return STATUS_ERR_NOT_FOUND;
+        case FSE_NOT_READY:
+            return STATUS_ERR_NOT_READY;
+        case FSE_INVALID_NAME:
+        case FSE_INVALID_PARAMETER:
+            return STATUS_ERR_INVALID;
+        default:
+            return STATUS_ERR_UNKNOWN;
+    }
+}
+
+// /int is small and fills up; a full filesystem only shows as a short write
+static bool storage_full(Storage* storage, const char* path, size_t needed) {
+    uint64_t free_space = 0;
+    return storage_common_fs_info(storage, path, NULL, &free_space) == FSE_OK && free_space < needed;
+}
+
 void tool_storage_list(EccoApp* app, const EccoFrame* req, EccoFrame* resp) {
     UNUSED(app);
 
@@ -546,7 +634,7 @@ void tool_storage_list(EccoApp* app, const EccoFrame* req, EccoFrame* resp) {
     const char* path = (const char*)req->payload;
 
     if (!storage_dir_open(dir, path)) {
-        resp->status = STATUS_ERR_NOT_FOUND;
+        resp->status = storage_status(storage_file_get_error(dir));
         storage_file_free(dir);
         furi_record_close(RECORD_STORAGE);
         return;
@@ -576,37 +664,169 @@ void tool_storage_list(EccoApp* app, const EccoFrame* req, EccoFrame* resp) {
 void tool_storage_read(EccoApp* app, const EccoFrame* req, EccoFrame* resp) {
     UNUSED(app);
 
-    if (req->payload_len < 1) {
+    // PATH must be NUL-terminated; an OFFSET(4) may follow it
+    size_t path_len = strnlen((const char*)req->payload, req->payload_len);
+    if (path_len == 0 || path_len == req->payload_len) {
         resp->status = STATUS_ERR_INVALID;
         return;
     }
 
+    uint32_t offset = 0;
+    if (req->payload_len >= path_len + 1 + 4) {
+        const uint8_t* p = &req->payload[path_len + 1];
+        offset = p[0] | (p[1] << 8) | (p[2] << 16) | ((uint32_t)p[3] << 24);
+    }
+
     Storage* storage = furi_record_open(RECORD_STORAGE);
     File* file = storage_file_alloc(storage);
 
     const char* path = (const char*)req->payload;
 
     if (!storage_file_open(file, path, FSAM_READ, FSOM_OPEN_EXISTING)) {
-        resp->status = STATUS_ERR_NOT_FOUND;
+        resp->status = storage_status(storage_file_get_error(file));
         storage_file_free(file);
         furi_record_close(RECORD_STORAGE);
         return;
     }
 
+    // SIZE is the whole file; DATA is as much from OFFSET as fits in one frame
     uint64_t size = storage_file_size(file);
-    if (size > ECCO_MAX_PAYLOAD - 4) {
-        size = ECCO_MAX_PAYLOAD - 4;
+    resp->payload[0] = size & 0xFF;
+    resp->payload[1] = (size >> 8) & 0xFF;
+    resp->payload[2] = (size >> 16) & 0xFF;
+    resp->payload[3] = (size >> 24) & 0xFF;
+    resp->payload_len = 4;
+
+    if (offset < size && storage_file_seek(file, offset, true)) {
+        uint64_t chunk = size - offset;
+        if (chunk > ECCO_MAX_PAYLOAD - 4) {
+            chunk = ECCO_MAX_PAYLOAD - 4;
+        }
+        uint16_t read = storage_file_read(file, &resp->payload[4], chunk);
+        resp->payload_len = 4 + read;
+    }
+
+    storage_file_close(file);
+    storage_file_free(file);
+    furi_record_close(RECORD_STORAGE);
+}
+
+void tool_storage_write(EccoApp* app, const EccoFrame* req, EccoFrame* resp) {
+    UNUSED(app);
+
+    // PATH\0 OFFSET(4) DATA; DATA may be empty
+    size_t path_len = strnlen((const char*)req->payload, req->payload_len);
+    if (path_len == 0 || req->payload_len < path_len + 1 + 4) {
+        resp->status = STATUS_ERR_INVALID;
+        return;
+    }
+
+    const uint8_t* p = &req->payload[path_len + 1];
+    uint32_t offset = p[0] | (p[1] << 8) | (p[2] << 16) | ((uint32_t)p[3] << 24);
+    const uint8_t* data = p + 4;
+    size_t data_len = req->payload_len - path_len - 1 - 4;
+
+    Storage* storage = furi_record_open(RECORD_STORAGE);
+    File* file = storage_file_alloc(storage);
+
+    const char* path = (const char*)req->payload;
+
+    // Offset 0 starts the file over; anything else continues an upload
+    FS_OpenMode mode = offset == 0 ? FSOM_CREATE_ALWAYS : FSOM_OPEN_ALWAYS;
+    if (!storage_file_open(file, path, FSAM_WRITE, mode)) {
+        resp->status = storage_status(storage_file_get_error(file));
+        storage_file_free(file);
+        furi_record_close(RECORD_STORAGE);
+        return;
+    }
+
+    // Writes must continue exactly where the file ends, never leave a hole
+    if (offset != storage_file_size(file)) {
+        resp->status = STATUS_ERR_INVALID;
+    } else if (!storage_file_seek(file, offset, true) ||
+              storage_file_write(file, data, data_len) != data_len) {
+        resp->status = storage_full(storage, path, data_len) ? STATUS_ERR_FULL : STATUS_ERR_UNKNOWN;
     }
 
+    uint64_t size = storage_file_size(file);
     resp->payload[0] = size & 0xFF;
     resp->payload[1] = (size >> 8) & 0xFF;
     resp->payload[2] = (size >> 16) & 0xFF;
     resp->payload[3] = (size >> 24) & 0xFF;
-
-    uint16_t read = storage_file_read(file, &resp->payload[4], size);
-    resp->payload_len = 4 + read;
+    resp->payload_len = 4;
 
     storage_file_close(file);
     storage_file_free(file);
     furi_record_close(RECORD_STORAGE);
 }
+
+void tool_storage_mkdir(EccoApp* app, const EccoFrame* req, EccoFrame* resp) {
+    UNUSED(app);
+
+    // PATH\0
+    size_t path_len = strnlen((const char*)req->payload, req->payload_len);
+    if (path_len == 0 || path_len == req->payload_len) {
+        resp->status = STATUS_ERR_INVALID;
+        return;
+    }
+
+    Storage* storage = furi_record_open(RECORD_STORAGE);
+    const char* path = (const char*)req->payload;
+
+    // A directory that is already there is fine; a missing parent is not
+    FS_Error error = storage_common_mkdir(storage, path);
+    if (error == FSE_EXIST) {
+        FileInfo info;
+        if (storage_common_stat(storage, path, &info) != FSE_OK || !file_info_is_dir(&info)) {
+            resp->status = STATUS_ERR_INVALID;
+        }
+    } else if (error != FSE_OK) {
+        bool full = error == FSE_INTERNAL && storage_full(storage, path, 1);
+        resp->status = full ? STATUS_ERR_FULL : storage_status(error);
+    }
+
+    furi_record_close(RECORD_STORAGE);
+}
+
+typedef struct {
+    uint8_t* dest;
+    FuriSemaphore* done;
+    bool captured;
+} ScreenshotContext;
+
+// Called by the GUI with each frame it sends to the display; only the
+// first one is kept
+static void screenshot_callback(uint8_t* data, size_t size, CanvasOrientation orientation, void* context) {
+    UNUSED(orientation);
+    ScreenshotContext* ctx = context;
+    if (ctx->captured || size != SCREEN_BYTES) {
+        return;
+    }
+    memcpy(ctx->dest, data, size);
+    ctx->captured = true;
+    furi_semaphore_release(ctx->done);
+}
+
+void tool_gui_screenshot(EccoApp* app, const EccoFrame* req, EccoFrame* resp) {
+    UNUSED(req);
+
+    ScreenshotContext ctx = {
+        .dest = resp->payload,
+        .done = furi_semaphore_alloc(1, 0),
+        .captured = false,
+    };
+
+    gui_add_framebuffer_callback(app->gui, screenshot_callback, &ctx);
+    // Frames are only committed on a redraw, so ask for one
+    view_port_update(app->view_port);
+    bool captured = furi_semaphore_acquire(ctx.done, SCREENSHOT_TIMEOUT_MS) == FuriStatusOk;
+    // Taken under the GUI lock, so no callback is still running after this
+    gui_remove_framebuffer_callback(app->gui, screenshot_callback, &ctx);
+    furi_semaphore_free(ctx.done);
+
+    if (captured) {
+        resp->payload_len = SCREEN_BYTES;
+    } else {
+        resp->status = STATUS_ERR_TIMEOUT;
+    }
+}
@@ -22,7 +22,7 @@ import {
     toHex,
 } from './protocol.js';
 
-import { handleCommand, MOCK_DATA } from './mock-flipper.js';
+import { handleCommand, selectProtocol, authorize, MOCK_DATA } from './mock-flipper.js';
 
 describe('Mock Flipper Command Handlers', () => {
     test('handles PING command', () => {
@@ -34,6 +34,29 @@ describe('Mock Flipper Command Handlers', () => {
         assert.strictEqual(parsed.seq, 1);
     });
 
+    test('PING echoes its payload', () => {
+        const payload = new Uint8Array([0xde, 0xad, 0xbe, 0xef, 0x01, 0x02, 0x03, 0x04]);
+        const response = handleCommand(CMD.PING, 2, payload);
+        const parsed = parseFrame(response);
+
+        assert.strictEqual(parsed.status, STATUS.OK);
+        assert.deepStrictEqual(Array.from(parsed.payload), Array.from(payload));
+    });
+
+    test('handles NOTIFY command', () => {
+        const payload = new Uint8Array([0xff, 0x00, 0x80, 0xf4, 0x01, 0x03]);
+        const parsed = parseFrame(handleCommand(CMD.NOTIFY, 3, payload));
+
+        assert.strictEqual(parsed.cmd, CMD.NOTIFY);
+        assert.strictEqual(parsed.status, STATUS.OK);
+        assert.strictEqual(parsed.payload.length, 0);
+    });
+
+    test('rejects short NOTIFY payload', () => {
+        const parsed = parseFrame(handleCommand(CMD.NOTIFY, 4, new Uint8Array([0xff, 0x00])));
+        assert.strictEqual(parsed.status, STATUS.ERR_INVALID);
+    });
+
     test('handles DEVICE_INFO command', () => {
         const response = handleCommand(CMD.DEVICE_INFO, 5, new Uint8Array());
         const parsed = parseFrame(response);
@@ -192,6 +215,28 @@ describe('Mock Flipper Command Handlers', () => {
         assert.ok(size > 0);
     });
 
+    test('STORAGE_READ reads from an offset', () => {
+        const path = '/ext/subghz/garage.sub';
+        const content = new TextEncoder().encode(MOCK_DATA.fileContents[path]);
+        const payload = new Uint8Array([...encodeString(path), ...encodeUint32LE(10)]);
+
+        const parsed = parseFrame(handleCommand(CMD.STORAGE_READ, 54, payload));
+        const view = new DataView(parsed.payload.buffer, parsed.payload.byteOffset);
+
+        assert.strictEqual(parsed.status, STATUS.OK);
+        assert.strictEqual(view.getUint32(0, true), content.length);
+        assert.deepStrictEqual(Array.from(parsed.payload.slice(4)), Array.from(content.slice(10)));
+    });
+
+    test('STORAGE_READ past the end returns only the size', () => {
+        const path = '/ext/subghz/garage.sub';
+        const payload = new Uint8Array([...encodeString(path), ...encodeUint32LE(100000)]);
+
+        const parsed = parseFrame(handleCommand(CMD.STORAGE_READ, 55, payload));
+        assert.strictEqual(parsed.status, STATUS.OK);
+        assert.strictEqual(parsed.payload.length, 4);
+    });
+
     test('handles STORAGE_READ for nonexistent file', () => {
         const payload = encodeString('/ext/nonexistent.txt');
 
@@ -202,6 +247,96 @@ describe('Mock Flipper Command Handlers', () => {
         assert.strictEqual(parsed.status, STATUS.ERR_NOT_FOUND);
     });
 
+    test('STORAGE_WRITE creates a file and appends at its end', () => {
+        const path = '/ext/nfc/upload.nfc';
+        const first = new Uint8Array([...encodeString(path), ...encodeUint32LE(0), 1, 2, 3]);
+        const second = new Uint8Array([...encodeString(path), ...encodeUint32LE(3), 4, 5]);
+
+        const created = parseFrame(handleCommand(CMD.STORAGE_WRITE, 56, first));
+        assert.strictEqual(created.cmd, CMD.STORAGE_WRITE);
+        assert.strictEqual(created.status, STATUS.OK);
+        assert.strictEqual(new DataView(created.payload.buffer, created.payload.byteOffset).getUint32(0, true), 3);
+
+        const appended = parseFrame(handleCommand(CMD.STORAGE_WRITE, 57, second));
+        assert.strictEqual(appended.status, STATUS.OK);
+        assert.strictEqual(new DataView(appended.payload.buffer, appended.payload.byteOffset).getUint32(0, true), 5);
+        assert.ok(MOCK_DATA.files['/ext/nfc'].includes('upload.nfc'));
+
+        const read = parseFrame(handleCommand(CMD.STORAGE_READ, 58, encodeString(path)));
+        assert.deepStrictEqual(Array.from(read.payload.slice(4)), [1, 2, 3, 4, 5]);
+    });
+
+    test('STORAGE_WRITE rejects an offset that leaves a gap', () => {
+        const path = '/ext/nfc/gap.nfc';
+        handleCommand(CMD.STORAGE_WRITE, 59, new Uint8Array([...encodeString(path), ...encodeUint32LE(0), 1]));
+
+        const payload = new Uint8Array([...encodeString(path), ...encodeUint32LE(10), 2]);
+        const parsed = parseFrame(handleCommand(CMD.STORAGE_WRITE, 60, payload));
+        assert.strictEqual(parsed.status, STATUS.ERR_INVALID);
+        // SIZE says where to resume
+        assert.strictEqual(new DataView(parsed.payload.buffer, parsed.payload.byteOffset).getUint32(0, true), 1);
+    });
+
+    test('STORAGE_WRITE into a missing directory', () => {
+        const payload = new Uint8Array([...encodeString('/ext/nowhere/file.txt'), ...encodeUint32LE(0), 1]);
+        const parsed = parseFrame(handleCommand(CMD.STORAGE_WRITE, 61, payload));
+        assert.strictEqual(parsed.status, STATUS.ERR_NOT_FOUND);
+    });
+
+    test('STORAGE_LIST and STORAGE_WRITE work on internal storage', () => {
+        const listed = parseFrame(handleCommand(CMD.STORAGE_LIST, 67, encodeString('/int')));
+        assert.strictEqual(listed.status, STATUS.OK);
+        assert.strictEqual(listed.payload[0], MOCK_DATA.files['/int'].length);
+
+        const payload = new Uint8Array([...encodeString('/int/small.txt'), ...encodeUint32LE(0), 1, 2]);
+        const written = parseFrame(handleCommand(CMD.STORAGE_WRITE, 68, payload));
+        assert.strictEqual(written.status, STATUS.OK);
+        assert.ok(MOCK_DATA.files['/int'].includes('small.txt'));
+    });
+
+    test('STORAGE_WRITE past the end of internal storage gives ERR_FULL', () => {
+        const data = new Uint8Array(MOCK_DATA.intCapacity + 1);
+        const payload = new Uint8Array([...encodeString('/int/big.bin'), ...encodeUint32LE(0), ...data]);
+        const parsed = parseFrame(handleCommand(CMD.STORAGE_WRITE, 69, payload));
+        assert.strictEqual(parsed.status, STATUS.ERR_FULL);
+        assert.ok(!MOCK_DATA.files['/int'].includes('big.bin'));
+    });
+
+    test('STORAGE_MKDIR creates a directory that can be written into', () => {
+        const created = parseFrame(handleCommand(CMD.STORAGE_MKDIR, 63, encodeString('/ext/backup')));
+        assert.strictEqual(created.cmd, CMD.STORAGE_MKDIR);
+        assert.strictEqual(created.status, STATUS.OK);
+        assert.ok(MOCK_DATA.files['/ext'].includes('backup'));
+
+        // Already there is fine
+        const again = parseFrame(handleCommand(CMD.STORAGE_MKDIR, 64, encodeString('/ext/backup')));
+        assert.strictEqual(again.status, STATUS.OK);
+        assert.strictEqual(MOCK_DATA.files['/ext'].filter(name => name === 'backup').length, 1);
+
+        const payload = new Uint8Array([...encodeString('/ext/backup/a.sub'), ...encodeUint32LE(0), 1]);
+        const written = parseFrame(handleCommand(CMD.STORAGE_WRITE, 65, payload));
+        assert.strictEqual(written.status, STATUS.OK);
+    });
+
+    test('STORAGE_MKDIR needs the parent to exist', () => {
+        const parsed = parseFrame(handleCommand(CMD.STORAGE_MKDIR, 66, encodeString('/ext/missing/deeper')));
+        assert.strictEqual(parsed.status, STATUS.ERR_NOT_FOUND);
+    });
+
+    test('GUI_SCREENSHOT returns a full framebuffer', () => {
+        const parsed = parseFrame(handleCommand(CMD.GUI_SCREENSHOT, 62, new Uint8Array()));
+        assert.strictEqual(parsed.cmd, CMD.GUI_SCREENSHOT);
+        assert.strictEqual(parsed.status, STATUS.OK);
+        assert.strictEqual(parsed.payload.length, 128 * 64 / 8);
+
+        // Pixel (x, y) is bit y % 8 of byte (y / 8) * 128 + x
+        const pixel = (x, y) => (parsed.payload[Math.floor(y / 8) * 128 + x] >> (y % 8)) & 1;
+        assert.strictEqual(pixel(0, 0), 1);
+        assert.strictEqual(pixel(127, 63), 1);
+        assert.strictEqual(pixel(64, 63), 1);
+        assert.strictEqual(pixel(64, 32), 0);
+    });
+
     test('handles unknown command', () => {
         const response = handleCommand(0xFF, 99, new Uint8Array());
         const parsed = parseFrame(response);
@@ -386,3 +521,37 @@ describe('Mock Data Integrity', () => {
         assert.ok(typeof MOCK_DATA.fileContents === 'object');
     });
 });
+
+describe('WebSocket Protocol Selection', () => {
+    test('defaults to ecco-raw', () => {
+        assert.strictEqual(selectProtocol('/ws'), 'ecco-raw');
+    });
+
+    test('accepts every known protocol', () => {
+        assert.strictEqual(selectProtocol('/ws?protocol=ecco-raw'), 'ecco-raw');
+        assert.strictEqual(selectProtocol('/ws?protocol=ecco-rpc'), 'ecco-rpc');
+        assert.strictEqual(selectProtocol('/ws?protocol=ecco-log'), 'ecco-log');
+    });
+
+    test('rejects unknown protocols', () => {
+        assert.strictEqual(selectProtocol('/ws?protocol=ecco-screen'), null);
+        assert.strictEqual(selectProtocol('/ws?protocol=ECCO-RPC'), null);
+    });
+});
+
+describe('WebSocket Token', () => {
+    test('allows everyone without a token', () => {
+        assert.strictEqual(authorize('/ws', null), true);
+        assert.strictEqual(authorize('/ws?token=anything', null), true);
+    });
+
+    test('accepts the matching token', () => {
+        assert.strictEqual(authorize('/ws?protocol=ecco-rpc&token=s3cret', 's3cret'), true);
+    });
+
+    test('rejects a missing or wrong token', () => {
+        assert.strictEqual(authorize('/ws?protocol=ecco-rpc', 's3cret'), false);
+        assert.strictEqual(authorize('/ws?token=s3cre', 's3cret'), false);
+        assert.strictEqual(authorize('/ws?token=S3CRET', 's3cret'), false);
+    });
+});
@@ -21,10 +21,12 @@ import { dirname } from 'path';
 
 import {
     FRAME_START,
+    MAX_PAYLOAD,
     CMD,
     STATUS,
     NFC_TYPE,
     RFID_TYPE,
+    WS_PROTOCOLS,
     buildFrame,
     parseFrame,
     encodeUint16LE,
@@ -37,6 +39,8 @@ const __filename = fileURLToPath(import.meta.url);
 const __dirname = dirname(__filename);
 
 const PORT = parseInt(process.argv[2]) || 8080;
+// Set to require ?token= on /ws, like the ESP32's ws_token NVS key
+const WS_TOKEN = process.env.MOCK_WS_TOKEN || null;
 const WEBAPP_DIR = join(__dirname, '..', 'webapp');
 
 // MIME types for static file serving
@@ -86,8 +90,12 @@ const MOCK_DATA = {
         '/ext/subghz': ['garage.sub', 'car_key.sub', 'doorbell.sub'],
         '/ext/nfc': ['work_badge.nfc', 'hotel_key.nfc'],
         '/ext/infrared': ['tv_remote.ir', 'ac_remote.ir'],
+        '/int': ['notes.txt'],
     },
 
+    // Internal flash is small; writes to /int past this many bytes get ERR_FULL
+    intCapacity: 512,
+
     // Mock file contents
     fileContents: {
         '/ext/subghz/garage.sub': `Filetype: Flipper SubGhz RAW File
@@ -113,11 +121,14 @@ function handleCommand(cmd, seq, payload) {
 
     switch (cmd) {
         case CMD.PING:
-            return buildFrame(CMD.PING, seq, STATUS.OK);
+            return buildFrame(CMD.PING, seq, STATUS.OK, payload);
 
         case CMD.DEVICE_INFO:
             return handleDeviceInfo(seq);
 
+        case CMD.NOTIFY:
+            return handleNotify(seq, payload);
+
         case CMD.SUBGHZ_CAPTURE:
             return handleSubghzCapture(seq, payload);
 
@@ -145,6 +156,15 @@ function handleCommand(cmd, seq, payload) {
         case CMD.STORAGE_READ:
             return handleStorageRead(seq, payload);
 
+        case CMD.STORAGE_WRITE:
+            return handleStorageWrite(seq, payload);
+
+        case CMD.STORAGE_MKDIR:
+            return handleStorageMkdir(seq, payload);
+
+        case CMD.GUI_SCREENSHOT:
+            return handleGuiScreenshot(seq);
+
         default:
             console.log(`[Mock] Unknown command: 0x${cmd.toString(16)}`);
             return buildFrame(cmd, seq, STATUS.ERR_INVALID);
@@ -165,6 +185,18 @@ function handleDeviceInfo(seq) {
     return buildFrame(CMD.DEVICE_INFO, seq, STATUS.OK, payload);
 }
 
+function handleNotify(seq, payload) {
+    // Request: RED(1) + GREEN(1) + BLUE(1) + DURATION(2) + FLAGS(1)
+    if (payload.length < 6) {
+        return buildFrame(CMD.NOTIFY, seq, STATUS.ERR_INVALID);
+    }
+
+    const duration = payload[3] | (payload[4] << 8);
+    const flags = payload[5];
+    console.log(`[Mock] Notify: rgb(${payload[0]},${payload[1]},${payload[2]}) ${duration || 500}ms, vibrate=${!!(flags & 1)}, sound=${!!(flags & 2)}`);
+    return buildFrame(CMD.NOTIFY, seq, STATUS.OK);
+}
+
 function handleSubghzCapture(seq, payload) {
     // Request: FREQUENCY(4) + TIMEOUT(2)
     const view = new DataView(payload.buffer, payload.byteOffset);
@@ -292,27 +324,155 @@ function handleStorageList(seq, payload) {
 }
 
 function handleStorageRead(seq, payload) {
-    // Request: PATH(null-term)
-    const decoder = new TextDecoder();
-    let path = decoder.decode(payload).replace(/\0/g, '');
+    // Request: PATH(null-term) + optional OFFSET(4)
+    const nul = payload.indexOf(0);
+    if (nul <= 0) {
+        return buildFrame(CMD.STORAGE_READ, seq, STATUS.ERR_INVALID);
+    }
+    const path = new TextDecoder().decode(payload.slice(0, nul));
+    const offset = payload.length >= nul + 5
+        ? new DataView(payload.buffer, payload.byteOffset + nul + 1, 4).getUint32(0, true)
+        : 0;
 
-    console.log(`[Mock] Storage read: ${path}`);
+    console.log(`[Mock] Storage read: ${path} from ${offset}`);
 
     const content = MOCK_DATA.fileContents[path];
     if (!content) {
         return buildFrame(CMD.STORAGE_READ, seq, STATUS.ERR_NOT_FOUND);
     }
 
-    // Response: SIZE(4) + DATA(var)
-    const contentBytes = new TextEncoder().encode(content);
-    const respPayload = new Uint8Array(4 + contentBytes.length);
+    // Response: SIZE(4) of the whole file + DATA from OFFSET, one frame at most
+    const contentBytes = typeof content === 'string' ? new TextEncoder().encode(content) : content;
+    const chunk = contentBytes.slice(offset, offset + MAX_PAYLOAD - 4);
+    const respPayload = new Uint8Array(4 + chunk.length);
     const view = new DataView(respPayload.buffer);
     view.setUint32(0, contentBytes.length, true);
-    respPayload.set(contentBytes, 4);
+    respPayload.set(chunk, 4);
 
     return buildFrame(CMD.STORAGE_READ, seq, STATUS.OK, respPayload);
 }
 
+function handleStorageWrite(seq, payload) {
+    // Request: PATH(null-term) + OFFSET(4) + DATA
+    const nul = payload.indexOf(0);
+    if (nul <= 0 || payload.length < nul + 5) {
+        return buildFrame(CMD.STORAGE_WRITE, seq, STATUS.ERR_INVALID);
+    }
+    const path = new TextDecoder().decode(payload.slice(0, nul));
+    const offset = new DataView(payload.buffer, payload.byteOffset + nul + 1, 4).getUint32(0, true);
+    const data = payload.slice(nul + 5);
+
+    console.log(`[Mock] Storage write: ${path} at ${offset}, ${data.length} bytes`);
+
+    const slash = path.lastIndexOf('/');
+    const dir = MOCK_DATA.files[path.slice(0, slash)];
+    if (!dir) {
+        return buildFrame(CMD.STORAGE_WRITE, seq, STATUS.ERR_NOT_FOUND);
+    }
+
+    // OFFSET 0 starts the file over; anything else must append at the end
+    let content = MOCK_DATA.fileContents[path];
+    if (offset === 0 || content === undefined) {
+        content = new Uint8Array(0);
+    } else if (typeof content === 'string') {
+        content = new TextEncoder().encode(content);
+    }
+    let status = STATUS.OK;
+    if (offset !== 0 && offset !== content.length) {
+        status = STATUS.ERR_INVALID;
+    } else if (path.startsWith('/int/') && intUsed(path) + content.length + data.length > MOCK_DATA.intCapacity) {
+        status = STATUS.ERR_FULL;
+    } else {
+        const grown = new Uint8Array(content.length + data.length);
+        grown.set(content);
+        grown.set(data, content.length);
+        content = grown;
+        MOCK_DATA.fileContents[path] = content;
+        const name = path.slice(slash + 1);
+        if (!dir.includes(name)) {
+            dir.push(name);
+        }
+    }
+
+    // Response: SIZE(4) of the file after the write
+    const respPayload = new Uint8Array(4);
+    new DataView(respPayload.buffer).setUint32(0, content.length, true);
+    return buildFrame(CMD.STORAGE_WRITE, seq, status, respPayload);
+}
+
+// Bytes of /int taken by files other than `except`
+function intUsed(except) {
+    return Object.entries(MOCK_DATA.fileContents)
+        .filter(([path]) => path.startsWith('/int/') && path !== except)
+        .reduce((sum, [, content]) => sum + content.length, 0);
+}
+
+function handleStorageMkdir(seq, payload) {
+    // Request: PATH(null-term)
+    const nul = payload.indexOf(0);
+    if (nul <= 0) {
+        return buildFrame(CMD.STORAGE_MKDIR, seq, STATUS.ERR_INVALID);
+    }
+    const path = new TextDecoder().decode(payload.slice(0, nul));
+
+    console.log(`[Mock] Storage mkdir: ${path}`);
+
+    if (MOCK_DATA.files[path]) {
+        return buildFrame(CMD.STORAGE_MKDIR, seq, STATUS.OK);
+    }
+    const slash = path.lastIndexOf('/');
+    const parent = MOCK_DATA.files[path.slice(0, slash)];
+    const name = path.slice(slash + 1);
+    if (!parent) {
+        return buildFrame(CMD.STORAGE_MKDIR, seq, STATUS.ERR_NOT_FOUND);
+    }
+    if (MOCK_DATA.fileContents[path] !== undefined) {
+        return buildFrame(CMD.STORAGE_MKDIR, seq, STATUS.ERR_INVALID);
+    }
+
+    MOCK_DATA.files[path] = [];
+    parent.push(name);
+    return buildFrame(CMD.STORAGE_MKDIR, seq, STATUS.OK);
+}
+
+function handleGuiScreenshot(seq) {
+    // Response: 128x64 framebuffer, 8 pages of 128 single-column bytes (LSB
+    // at the top). The mock screen is a one-pixel border.
+    const framebuffer = new Uint8Array(1024);
+    for (let page = 0; page < 8; page++) {
+        for (let x = 0; x < 128; x++) {
+            let column = 0;
+            if (x === 0 || x === 127) column = 0xFF;
+            if (page === 0) column |= 0x01;
+            if (page === 7) column |= 0x80;
+            framebuffer[page * 128 + x] = column;
+        }
+    }
+
+    console.log('[Mock] GUI screenshot');
+    return buildFrame(CMD.GUI_SCREENSHOT, seq, STATUS.OK, framebuffer);
+}
+
+/**
+ * Pick the WebSocket behavior from /ws?protocol=..., like the ESP32 does.
+ * Returns null for names the bridge doesn't know.
+ */
+function selectProtocol(url) {
+    const query = new URL(url, 'http://localhost').searchParams;
+    const protocol = query.get('protocol') || 'ecco-raw';
+    return WS_PROTOCOLS.includes(protocol) ? protocol : null;
+}
+
+/**
+ * Check /ws?token=... against the configured token, like the ESP32 does.
+ * Every client is allowed when no token is configured.
+ */
+function authorize(url, token = WS_TOKEN) {
+    if (!token) return true;
+    const query = new URL(url, 'http://localhost').searchParams;
+    return query.get('token') === token;
+}
+
 /**
  * Start the mock server
  */
@@ -343,8 +503,26 @@ function startServer() {
 
     const wss = new WebSocketServer({ server, path: '/ws' });
 
-    wss.on('connection', (ws) => {
-        console.log('[Mock] Client connected');
+    wss.on('connection', (ws, req) => {
+        if (!authorize(req.url)) {
+            console.log(`[Mock] Rejected unauthorized connection`);
+            ws.close(1008, 'unauthorized');
+            return;
+        }
+
+        const protocol = selectProtocol(req.url);
+        if (!protocol) {
+            console.log(`[Mock] Rejected connection to ${req.url}`);
+            ws.close(1002, 'unknown protocol');
+            return;
+        }
+
+        console.log(`[Mock] Client connected (${protocol})`);
+
+        if (protocol === 'ecco-log') {
+            ws.send('I (0) mock: log stream attached');
+            return;
+        }
 
         ws.on('message', (data) => {
             try {
@@ -396,7 +574,7 @@ function startServer() {
 }
 
 // Export for testing
-export { handleCommand, MOCK_DATA };
+export { handleCommand, selectProtocol, authorize, MOCK_DATA };
 
 // Run server if executed directly
 if (process.argv[1] === fileURLToPath(import.meta.url)) {
@@ -12,6 +12,7 @@ export const MAX_PAYLOAD = 1024;
 export const CMD = {
     PING: 0x01,
     DEVICE_INFO: 0x02,
+    NOTIFY: 0x03,
     SUBGHZ_CAPTURE: 0x10,
     SUBGHZ_TRANSMIT: 0x11,
     NFC_READ: 0x20,
@@ -21,7 +22,10 @@ export const CMD = {
     RFID_READ: 0x40,
     STORAGE_LIST: 0x50,
     STORAGE_READ: 0x51,
+    STORAGE_WRITE: 0x52,
+    STORAGE_MKDIR: 0x53,
     DATA_CONTINUE: 0x60,
+    GUI_SCREENSHOT: 0x70,
 };
 
 // Status codes
@@ -33,8 +37,13 @@ export const STATUS = {
     ERR_TIMEOUT: 0x04,
     ERR_NOT_FOUND: 0x05,
     ERR_NO_DATA: 0x06,
+    ERR_FULL: 0x07,
+    ERR_NOT_READY: 0x08,
 };
 
+// WebSocket behaviors selectable with /ws?protocol=...
+export const WS_PROTOCOLS = ['ecco-raw', 'ecco-rpc', 'ecco-log'];
+
 // NFC types
 export const NFC_TYPE = {
     MIFARE_CLASSIC: 0x01,
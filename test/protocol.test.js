@@ -365,6 +365,7 @@ describe('Protocol Spec Compliance', () => {
         const expectedCommands = {
             PING: 0x01,
             DEVICE_INFO: 0x02,
+            NOTIFY: 0x03,
             SUBGHZ_CAPTURE: 0x10,
             SUBGHZ_TRANSMIT: 0x11,
             NFC_READ: 0x20,
@@ -374,7 +375,10 @@ describe('Protocol Spec Compliance', () => {
             RFID_READ: 0x40,
             STORAGE_LIST: 0x50,
             STORAGE_READ: 0x51,
+            STORAGE_WRITE: 0x52,
+            STORAGE_MKDIR: 0x53,
             DATA_CONTINUE: 0x60,
+            GUI_SCREENSHOT: 0x70,
         };
 
         for (const [name, value] of Object.entries(expectedCommands)) {
@@ -391,6 +395,8 @@ describe('Protocol Spec Compliance', () => {
             ERR_TIMEOUT: 0x04,
             ERR_NOT_FOUND: 0x05,
             ERR_NO_DATA: 0x06,
+            ERR_FULL: 0x07,
+            ERR_NOT_READY: 0x08,
         };
 
         for (const [name, value] of Object.entries(expectedStatus)) {
@@ -5,6 +5,11 @@ const CLAUDE_MODEL = 'claude-sonnet-4-20250514';
 
 // Protocol constants
 const FRAME_START = 0xEC;
+const WS_PROTOCOL = 'ecco-rpc'; // request/response frames, arbitrated by the ESP32
+// Close codes the bridge uses to turn a connection away; retrying won't help
+const WS_CLOSE_REJECTED = [1002, 1008];
+// The bridge has ws_max_clients connections already; worth another try later
+const WS_CLOSE_FULL = 1013;
 const CMD = {
     PING: 0x01,
     DEVICE_INFO: 0x02,
@@ -17,7 +22,35 @@ const CMD = {
     RFID_READ: 0x40,
     STORAGE_LIST: 0x50,
     STORAGE_READ: 0x51,
+    STORAGE_WRITE: 0x52,
 };
+const STATUS_ERR_TIMEOUT = 0x04;
+
+// Reconnect delay doubles from RECONNECT_MIN_MS after each failed attempt
+const RECONNECT_MIN_MS = 1000;
+const RECONNECT_MAX_MS = 30000;
+// How often the status badge re-measures Flipper round-trip time
+const HEALTH_INTERVAL_MS = 10000;
+
+// Lines kept in the chat and SubGHz logs before the oldest are trimmed;
+// override with localStorage 'ecco-scrollback'
+const SCROLLBACK_LINES = 500;
+
+// Diagnostics echo test: round trips over /ws/echo, which never touches the UART
+const ECHO_ROUNDS = 10;
+const ECHO_TIMEOUT_MS = 5000;
+
+// Live SubGHz panel: each capture listens this long before the next one starts,
+// which also bounds how long Stop takes to land
+const SUBGHZ_LISTEN_SEC = 5;
+
+// Bridge URLs are relative to the page's <base href>, which the bridge sets
+// to base_path when it is mounted under a prefix by a reverse proxy
+function socketUrl(path) {
+    const url = new URL(path, document.baseURI);
+    url.protocol = url.protocol === 'https:' ? 'wss:' : 'ws:';
+    return url.href;
+}
 
 // Tool definitions for Claude
 const TOOLS = [
@@ -109,18 +142,18 @@ const TOOLS = [
     },
     {
         name: 'storage_list',
-        description: 'List files in a directory on the Flipper SD card',
+        description: 'List files in a directory on the Flipper SD card (/ext) or internal storage (/int)',
         input_schema: {
             type: 'object',
             properties: {
-                path: { type: 'string', description: 'Directory path (e.g., /ext/subghz)' }
+                path: { type: 'string', description: 'Directory path (e.g., /ext/subghz or /int)' }
             },
             required: ['path']
         }
     },
     {
         name: 'storage_read',
-        description: 'Read a file from the Flipper SD card',
+        description: 'Read a file from the Flipper SD card (/ext) or internal storage (/int)',
         input_schema: {
             type: 'object',
             properties: {
@@ -138,6 +171,11 @@ class Ecco {
         this.messages = [];
         this.seq = 0;
         this.pendingRequests = new Map();
+        this.reconnectDelay = RECONNECT_MIN_MS;
+        this.reconnectTimer = null;
+        this.healthTimer = null;
+        this.outbox = []; // chat messages typed while the socket was down
+        this.subghz = { running: false, captures: [] };
 
         this.setupUI();
     }
@@ -149,6 +187,7 @@ class Ecco {
         this.messagesEl = document.getElementById('messages');
         this.inputEl = document.getElementById('user-input');
         this.apiKeyEl = document.getElementById('api-key');
+        this.wsTokenEl = document.getElementById('ws-token');
 
         document.getElementById('connect-btn').onclick = () => this.connect();
         document.getElementById('input-form').onsubmit = (e) => {
@@ -156,9 +195,43 @@ class Ecco {
             this.sendMessage();
         };
 
+        this.subghzEl = document.getElementById('subghz');
+        this.subghzLogEl = document.getElementById('subghz-log');
+        this.subghzFreqEl = document.getElementById('subghz-freq');
+        this.subghzStartEl = document.getElementById('subghz-start');
+        this.subghzStopEl = document.getElementById('subghz-stop');
+        this.subghzSaveEl = document.getElementById('subghz-save');
+
+        this.subghzStartEl.onclick = () => this.startSubghz();
+        this.subghzStopEl.onclick = () => this.stopSubghz();
+        this.subghzSaveEl.onclick = () => this.saveSubghz();
+
+        this.recentEl = document.getElementById('recent');
+        this.recentListEl = document.getElementById('recent-list');
+        document.getElementById('recent-load').onclick = () => this.loadRecent();
+
+        this.diagEl = document.getElementById('diag');
+        this.diagLogEl = document.getElementById('diag-log');
+        this.diagEchoEl = document.getElementById('diag-echo');
+        this.diagEchoEl.onclick = () => this.runEchoTest();
+
+        this.uploadEl = document.getElementById('upload');
+        this.uploadFormEl = document.getElementById('upload-form');
+        this.uploadResultEl = document.getElementById('upload-result');
+        this.uploadFormEl.onsubmit = (e) => {
+            e.preventDefault();
+            this.uploadFile();
+        };
+
+        this.versionEl = document.getElementById('version');
+        this.loadVersion();
+        this.loadBranding();
+
         // Load saved API key
         const savedKey = localStorage.getItem('ecco-api-key');
         if (savedKey) this.apiKeyEl.value = savedKey;
+        this.wsTokenEl.value = localStorage.getItem('ecco-ws-token') || '';
+        this.scrollback = parseInt(localStorage.getItem('ecco-scrollback'), 10) || SCROLLBACK_LINES;
     }
 
     connect() {
@@ -169,36 +242,142 @@ class Ecco {
         }
 
         localStorage.setItem('ecco-api-key', this.apiKey);
+        this.wsToken = this.wsTokenEl.value.trim();
+        localStorage.setItem('ecco-ws-token', this.wsToken);
         this.setStatus('connecting');
+        this.openSocket();
+    }
+
+    // With a ws_token set, the bridge wants it on every /api/* call that
+    // reaches the Flipper; the field counts even before Connect is pressed
+    apiFetch(path, options = {}) {
+        const token = this.wsTokenEl.value.trim();
+        const headers = { ...options.headers };
+        if (token) headers.Authorization = `Bearer ${token}`;
+        return fetch(path, { ...options, headers });
+    }
+
+    // A plain form post can't carry the Authorization header, so the form is
+    // sent from here
+    async uploadFile() {
+        this.uploadResultEl.textContent = 'uploading\u2026';
+        try {
+            const resp = await this.apiFetch('api/flipper/file', {
+                method: 'POST',
+                body: new FormData(this.uploadFormEl)
+            });
+            const body = await resp.json();
+            if (!resp.ok) throw new Error(body.error || resp.status);
+            this.uploadResultEl.textContent = `wrote ${body.path} (${body.size} bytes)`;
+        } catch (e) {
+            this.uploadResultEl.textContent = `upload failed: ${e.message}`;
+        }
+    }
 
-        const wsUrl = `ws://${window.location.host}/ws`;
+    openSocket() {
+        let wsUrl = socketUrl(`ws?protocol=${WS_PROTOCOL}`);
+        if (this.wsToken) wsUrl += `&token=${encodeURIComponent(this.wsToken)}`;
         this.ws = new WebSocket(wsUrl);
         this.ws.binaryType = 'arraybuffer';
 
         this.ws.onopen = () => {
+            this.reconnectDelay = RECONNECT_MIN_MS;
             this.setStatus('connected');
             this.setupEl.classList.add('hidden');
             this.chatEl.classList.remove('hidden');
+            this.subghzEl.classList.remove('hidden');
+            this.recentEl.classList.remove('hidden');
+            this.diagEl.classList.remove('hidden');
+            this.uploadEl.classList.remove('hidden');
             this.ping();
+            this.flushOutbox();
+            this.checkHealth();
+            clearInterval(this.healthTimer);
+            this.healthTimer = setInterval(() => this.checkHealth(), HEALTH_INTERVAL_MS);
         };
 
-        this.ws.onclose = () => {
-            this.setStatus('disconnected');
-            this.setupEl.classList.remove('hidden');
-            this.chatEl.classList.add('hidden');
+        this.ws.onclose = (e) => {
+            clearInterval(this.healthTimer);
+            this.failPending('Connection lost');
+            this.stopSubghz();
+
+            if (WS_CLOSE_REJECTED.includes(e.code)) {
+                this.setStatus('disconnected');
+                this.setupEl.classList.remove('hidden');
+                this.chatEl.classList.add('hidden');
+                this.subghzEl.classList.add('hidden');
+                this.recentEl.classList.add('hidden');
+                this.diagEl.classList.add('hidden');
+                this.uploadEl.classList.add('hidden');
+                alert(`Bridge refused the connection: ${e.reason || e.code}`);
+                return;
+            }
+
+            // Never got going: back to the setup panel rather than retrying forever
+            if (this.chatEl.classList.contains('hidden')) {
+                this.setStatus('disconnected', e.code === WS_CLOSE_FULL ? 'Bridge full, close another tab' : undefined);
+                return;
+            }
+            this.scheduleReconnect();
         };
 
         this.ws.onerror = (e) => {
             console.error('WebSocket error:', e);
-            this.setStatus('disconnected');
         };
 
         this.ws.onmessage = (e) => this.handleFrame(e.data);
     }
 
-    setStatus(status) {
+    // Keep the chat visible and retry with exponential backoff
+    scheduleReconnect() {
+        const delay = this.reconnectDelay;
+        this.reconnectDelay = Math.min(delay * 2, RECONNECT_MAX_MS);
+        this.setStatus('reconnecting', `Reconnecting\u2026 ${Math.round(delay / 1000)}s`);
+
+        clearTimeout(this.reconnectTimer);
+        this.reconnectTimer = setTimeout(() => this.openSocket(), delay);
+    }
+
+    // Show Flipper responsiveness on the badge, not just the socket state
+    async checkHealth() {
+        let text = 'Connected';
+        try {
+            const resp = await this.apiFetch('api/flipper/ping');
+            const body = await resp.json();
+            if (resp.ok) {
+                text = `Connected \u00b7 ${Math.round(body.rtt_ms)}ms`;
+                if (!body.echo) text += ' (bad echo)';
+            } else {
+                text = 'Connected \u00b7 Flipper not responding';
+            }
+        } catch (e) {
+            // No ping endpoint (e.g. the mock server); keep the plain badge
+        }
+        if (this.isConnected()) this.setStatus('connected', text);
+    }
+
+    isConnected() {
+        return this.ws && this.ws.readyState === WebSocket.OPEN;
+    }
+
+    // Requests sent on a dead socket will never be answered
+    failPending(reason) {
+        for (const { reject, timeout } of this.pendingRequests.values()) {
+            clearTimeout(timeout);
+            reject(new Error(reason));
+        }
+        this.pendingRequests.clear();
+    }
+
+    flushOutbox() {
+        const queued = this.outbox;
+        this.outbox = [];
+        for (const text of queued) this.chat(text);
+    }
+
+    setStatus(status, text) {
         this.statusEl.className = status;
-        this.statusEl.textContent = status.charAt(0).toUpperCase() + status.slice(1);
+        this.statusEl.textContent = text || status.charAt(0).toUpperCase() + status.slice(1);
     }
 
     // Build binary frame
@@ -244,6 +423,10 @@ class Ecco {
     // Send frame and wait for response
     sendFrame(cmd, payload = new Uint8Array()) {
         return new Promise((resolve, reject) => {
+            if (!this.isConnected()) {
+                reject(new Error('Not connected'));
+                return;
+            }
             const { frame, seq } = this.buildFrame(cmd, payload);
 
             const timeout = setTimeout(() => {
@@ -268,7 +451,9 @@ class Ecco {
                 if (status === 0x00) {
                     pending.resolve({ cmd, payload });
                 } else {
-                    pending.reject(new Error(`Device error: 0x${status.toString(16)}`));
+                    const err = new Error(`Device error: 0x${status.toString(16)}`);
+                    err.status = status;
+                    pending.reject(err);
                 }
             }
         } catch (e) {
@@ -433,6 +618,202 @@ class Ecco {
         return { size, content };
     }
 
+    // Live SubGHz capture: back-to-back captures until stopped, one line each
+    async startSubghz() {
+        const frequency = Math.round(parseFloat(this.subghzFreqEl.value) * 1e6);
+        if (!frequency) return;
+
+        this.subghz.running = true;
+        this.subghzStartEl.disabled = true;
+        this.subghzStopEl.disabled = false;
+        this.addCaptureLine(`listening on ${(frequency / 1e6).toFixed(2)} MHz`, 'idle');
+
+        while (this.subghz.running) {
+            try {
+                const { data } = await this.cmdSubghzCapture(frequency, SUBGHZ_LISTEN_SEC);
+                const bytes = atob(data).length;
+                this.subghz.captures.push({ time: new Date().toISOString(), frequency, data });
+                this.subghzSaveEl.disabled = false;
+                this.addCaptureLine(`${new Date().toLocaleTimeString()}  ${bytes} bytes  ${data}`);
+            } catch (e) {
+                // Nothing heard in this window, keep listening
+                if (e.status === STATUS_ERR_TIMEOUT) continue;
+                this.addCaptureLine(`capture failed: ${e.message}`, 'idle');
+                break;
+            }
+        }
+
+        this.subghz.running = false;
+        this.subghzStartEl.disabled = false;
+        this.subghzStopEl.disabled = true;
+    }
+
+    // Takes effect once the capture in flight returns
+    stopSubghz() {
+        if (this.subghz.running) this.addCaptureLine('stopping', 'idle');
+        this.subghz.running = false;
+    }
+
+    saveSubghz() {
+        const blob = new Blob([JSON.stringify(this.subghz.captures, null, 2)], { type: 'application/json' });
+        const a = document.createElement('a');
+        a.href = URL.createObjectURL(blob);
+        a.download = `subghz-${new Date().toISOString().replace(/[:.]/g, '-')}.json`;
+        a.click();
+        URL.revokeObjectURL(a.href);
+    }
+
+    addCaptureLine(text, type = '') {
+        const div = document.createElement('div');
+        div.className = `capture-line ${type}`;
+        div.textContent = text;
+        this.subghzLogEl.appendChild(div);
+        this.trimScrollback(this.subghzLogEl);
+        this.subghzLogEl.scrollTop = this.subghzLogEl.scrollHeight;
+    }
+
+    // Firmware build in the footer, so a bug report can say what was running
+    async loadVersion() {
+        try {
+            const resp = await fetch('api/version');
+            const { version, git, built } = await resp.json();
+            this.versionEl.textContent = `Ecco ${version} (${git}), built ${built}`;
+        } catch (e) {
+            console.warn('Firmware version unavailable:', e);
+        }
+    }
+
+    // Title, accent colour and logo from the bridge's brand_* settings. The
+    // page already shows the Ecco defaults, so a failure leaves it as is.
+    async loadBranding() {
+        try {
+            const resp = await fetch('api/branding');
+            const { title, accent, logo } = await resp.json();
+            document.title = title;
+            document.getElementById('title').textContent = title;
+            this.inputEl.placeholder = `Ask ${title}...`;
+            document.documentElement.style.setProperty('--accent', accent);
+            if (logo) {
+                const logoEl = document.getElementById('logo');
+                logoEl.src = logo;
+                logoEl.classList.remove('hidden');
+            }
+        } catch (e) {
+            console.warn('Branding unavailable:', e);
+        }
+    }
+
+    // Frames the bridge remembers from /api/command, newest at the bottom.
+    // Redacted or oversized payloads weren't stored and can't be replayed.
+    async loadRecent() {
+        this.recentListEl.textContent = '';
+        let commands;
+        try {
+            const resp = await this.apiFetch('api/command/recent');
+            ({ commands } = await resp.json());
+        } catch (e) {
+            this.addRecentLine(`unavailable: ${e.message}`);
+            return;
+        }
+        if (!commands.length) this.addRecentLine('no commands yet');
+
+        for (const { cmd, payload, skipped } of commands) {
+            const name = Object.keys(CMD).find(k => CMD[k] === cmd) || `0x${cmd.toString(16).padStart(2, '0')}`;
+            const detail = skipped ? `(${skipped})` : payload || '(empty)';
+            this.addRecentLine(`${name}  ${detail}`, skipped ? null : () => this.replayRecent(cmd, payload));
+        }
+    }
+
+    async replayRecent(cmd, payload) {
+        try {
+            const resp = await this.apiFetch('api/command', {
+                method: 'POST',
+                headers: { 'Content-Type': 'application/json' },
+                body: JSON.stringify({ cmd, payload })
+            });
+            const body = await resp.json();
+            if (!resp.ok) throw new Error(body.error || resp.status);
+            this.addMessageUI('tool', `replayed 0x${cmd.toString(16)} \u2192 ${body.payload || '(empty)'}`);
+        } catch (e) {
+            this.addMessageUI('error', `Replay failed: ${e.message}`);
+        }
+    }
+
+    addRecentLine(text, replay = null) {
+        const div = document.createElement('div');
+        div.className = 'recent-line';
+        const label = document.createElement('span');
+        label.textContent = text;
+        div.appendChild(label);
+        if (replay) {
+            const button = document.createElement('button');
+            button.textContent = 'Replay';
+            button.onclick = replay;
+            div.appendChild(button);
+        }
+        this.recentListEl.appendChild(div);
+    }
+
+    // Time WebSocket round trips with no UART involved, then a Flipper PING
+    // for comparison. A slow echo points at the WiFi link or the client; a
+    // fast echo with a slow PING points at the UART side.
+    async runEchoTest() {
+        this.diagEchoEl.disabled = true;
+        this.diagLogEl.textContent = '';
+        try {
+            const rtts = await this.echoRoundTrips(ECHO_ROUNDS);
+            const avg = rtts.reduce((a, b) => a + b, 0) / rtts.length;
+            this.addDiagLine(`WebSocket echo: min ${Math.min(...rtts).toFixed(1)}ms, avg ${avg.toFixed(1)}ms, max ${Math.max(...rtts).toFixed(1)}ms over ${rtts.length}`);
+        } catch (e) {
+            this.addDiagLine(`WebSocket echo failed: ${e.message}`);
+        }
+
+        try {
+            const resp = await this.apiFetch('api/flipper/ping');
+            const body = await resp.json();
+            this.addDiagLine(resp.ok ? `Flipper PING: ${Math.round(body.rtt_ms)}ms` : `Flipper PING failed: ${body.error}`);
+        } catch (e) {
+            this.addDiagLine(`Flipper PING unavailable: ${e.message}`);
+        }
+        this.diagEchoEl.disabled = false;
+    }
+
+    echoRoundTrips(rounds) {
+        let url = socketUrl('ws/echo');
+        if (this.wsToken) url += `?token=${encodeURIComponent(this.wsToken)}`;
+
+        return new Promise((resolve, reject) => {
+            const ws = new WebSocket(url);
+            ws.binaryType = 'arraybuffer';
+            const rtts = [];
+            let sentAt = 0;
+            const timer = setTimeout(() => { ws.close(); reject(new Error('timed out')); }, ECHO_TIMEOUT_MS);
+
+            const send = () => {
+                sentAt = performance.now();
+                ws.send(crypto.getRandomValues(new Uint8Array(32)));
+            };
+            ws.onopen = send;
+            ws.onmessage = () => {
+                rtts.push(performance.now() - sentAt);
+                if (rtts.length < rounds) return send();
+                clearTimeout(timer);
+                ws.close();
+                resolve(rtts);
+            };
+            ws.onclose = (e) => {
+                clearTimeout(timer);
+                if (rtts.length < rounds) reject(new Error(e.reason || `closed (${e.code})`));
+            };
+        });
+    }
+
+    addDiagLine(text) {
+        const div = document.createElement('div');
+        div.textContent = text;
+        this.diagLogEl.appendChild(div);
+    }
+
     // Chat with Claude
     async chat(userMessage) {
         this.messages.push({ role: 'user', content: userMessage });
@@ -518,17 +899,36 @@ class Ecco {
         div.className = `message ${type}`;
         div.textContent = text;
         this.messagesEl.appendChild(div);
+        this.trimScrollback(this.messagesEl);
         this.messagesEl.scrollTop = this.messagesEl.scrollHeight;
     }
 
+    // Only the display is trimmed; the conversation sent to Claude is kept whole
+    trimScrollback(el) {
+        while (el.childElementCount > this.scrollback) {
+            el.firstElementChild.remove();
+        }
+    }
+
     sendMessage() {
         const text = this.inputEl.value.trim();
         if (!text) return;
 
         this.inputEl.value = '';
+        if (!this.isConnected()) {
+            this.outbox.push(text);
+            this.addMessageUI('tool', `Queued until the Flipper reconnects: ${text}`);
+            return;
+        }
         this.chat(text);
     }
 }
 
 // Start
 const ecco = new Ecco();
+
+// Only available over HTTPS (see tls_cert); on plain HTTP every load goes
+// to the bridge as before
+if ('serviceWorker' in navigator) {
+    navigator.serviceWorker.register('sw.js').catch(e => console.warn('Service worker not registered:', e));
+}
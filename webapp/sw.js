@@ -0,0 +1,38 @@
+// Ecco service worker - keeps the webapp shell cached so repeat visits load
+// without waiting on the AP link. Assets are served from the cache and
+// refreshed in the background, so a firmware update shows up one load later.
+// API calls and the WebSocket always go to the bridge.
+
+const CACHE = 'ecco-shell-v1';
+// Under the worker's scope, which is base_path behind a proxy
+const SHELL = ['', 'app.js', 'style.css'].map(path => new URL(path, self.registration.scope).pathname);
+
+self.addEventListener('install', (e) => {
+    e.waitUntil(caches.open(CACHE).then(cache => cache.addAll(SHELL)).then(() => self.skipWaiting()));
+});
+
+// Drop caches from older versions of this worker
+self.addEventListener('activate', (e) => {
+    e.waitUntil(
+        caches.keys()
+            .then(keys => Promise.all(keys.filter(k => k !== CACHE).map(k => caches.delete(k))))
+            .then(() => self.clients.claim())
+    );
+});
+
+self.addEventListener('fetch', (e) => {
+    const url = new URL(e.request.url);
+    if (e.request.method !== 'GET' || url.origin !== self.location.origin || !SHELL.includes(url.pathname)) return;
+
+    e.respondWith(caches.open(CACHE).then(async (cache) => {
+        const cached = await cache.match(e.request);
+        const fresh = fetch(e.request).then((resp) => {
+            if (resp.ok) cache.put(e.request, resp.clone());
+            return resp;
+        });
+        if (!cached) return fresh;
+        // Offline or the bridge is busy; the cached copy is still good
+        fresh.catch(() => {});
+        return cached;
+    }));
+});